@@ -0,0 +1,416 @@
+//! A small assembler for RV32I, to write test programs and demos as text.
+//!
+//! Each line holds at most one instruction, optionally preceded by `label:`.
+//! Everything after `#` is a comment. Operands are separated by commas, and registers
+//! are written as `x0`-`x31` or by their ABI names. Branches and `jal` take a label or
+//! a numeric offset. Besides the instructions `decode` supports, the pseudo
+//! instructions `nop`, `mv`, `li` (12bit immediates only), `j` and `ret` are accepted.
+
+use crate::decode::{supported_instructions, Format, REG_NAMES};
+use crate::encode::{encode_b, encode_i, encode_j, encode_s, i_type, r_type, u_type, EncodeError};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Opcodes which need their own operand syntax.
+const LOAD: u8 = 0b0000011;
+const JALR: u8 = 0b1100111;
+const SYSTEM: u8 = 0b1110011;
+
+/// Reasons why a source cannot be assembled. `line` counts from 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownMnemonic { line: usize, mnemonic: String },
+    InvalidOperand { line: usize, operand: String },
+    WrongOperandCount { line: usize, expected: usize },
+    UndefinedLabel { line: usize, label: String },
+    DuplicateLabel { line: usize, label: String },
+    Encode { line: usize, error: EncodeError },
+}
+
+// An instruction with its mnemonic and operands as written.
+struct Statement<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+/// Assemble `source` into instruction words, the first of which is at offset 0.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    let mut labels = BTreeMap::new();
+    let mut statements = Vec::new();
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = text.split('#').next().unwrap_or("").trim();
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if !is_label(label) {
+                return Err(AsmError::InvalidOperand {
+                    line,
+                    operand: label.to_string(),
+                });
+            }
+            let addr = statements.len() as i32 * 4;
+            if labels.insert(label, addr).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line,
+                    label: label.to_string(),
+                });
+            }
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let operands = operands
+            .split(',')
+            .map(str::trim)
+            .filter(|operand| !operand.is_empty())
+            .collect();
+        statements.push(Statement {
+            line,
+            mnemonic,
+            operands,
+        });
+    }
+
+    statements
+        .iter()
+        .enumerate()
+        .map(|(index, statement)| assemble_one(statement, index as i32 * 4, &labels))
+        .collect()
+}
+
+// Assemble a statement located at `pc`.
+fn assemble_one(
+    statement: &Statement,
+    pc: i32,
+    labels: &BTreeMap<&str, i32>,
+) -> Result<u32, AsmError> {
+    let line = statement.line;
+    let ops = &statement.operands;
+    let (mnemonic, ops): (&str, Vec<&str>) = match (statement.mnemonic, ops.len()) {
+        ("nop", 0) => ("addi", vec!["zero", "zero", "0"]),
+        ("mv", 2) => ("addi", vec![ops[0], ops[1], "0"]),
+        ("li", 2) => ("addi", vec![ops[0], "zero", ops[1]]),
+        ("j", 1) => ("jal", vec!["zero", ops[0]]),
+        ("ret", 0) => ("jalr", vec!["zero", "0(ra)"]),
+        ("jal", 1) => ("jal", vec!["ra", ops[0]]),
+        ("nop", _) | ("ret", _) => return Err(AsmError::WrongOperandCount { line, expected: 0 }),
+        ("mv", _) | ("li", _) => return Err(AsmError::WrongOperandCount { line, expected: 2 }),
+        ("j", _) => return Err(AsmError::WrongOperandCount { line, expected: 1 }),
+        (mnemonic, _) => (mnemonic, ops.clone()),
+    };
+    let info = supported_instructions()
+        .iter()
+        .find(|info| info.mnemonic == mnemonic)
+        .ok_or_else(|| AsmError::UnknownMnemonic {
+            line,
+            mnemonic: mnemonic.to_string(),
+        })?;
+    let opcode = info.opcode as u32;
+    let funct3 = info.funct3.unwrap_or(0) as u32;
+    let funct7 = info.funct7.unwrap_or(0) as u32;
+
+    if let Some(funct12) = info.funct12 {
+        if !ops.is_empty() {
+            return Err(AsmError::WrongOperandCount { line, expected: 0 });
+        }
+        return Ok(i_type(opcode, funct3, 0, 0, funct12 as i32));
+    }
+    let expected = match info.format {
+        Format::R => 3,
+        Format::I if info.opcode == LOAD || info.opcode == JALR => 2,
+        Format::I => 3,
+        Format::S | Format::U | Format::J => 2,
+        Format::B => 3,
+    };
+    // `jalr` also accepts the same form as `addi`.
+    if ops.len() != expected && !(info.opcode == JALR && ops.len() == 3) {
+        return Err(AsmError::WrongOperandCount { line, expected });
+    }
+    let reg = |operand: &str| register(operand).ok_or_else(|| invalid(line, operand));
+    let imm = |operand: &str| immediate(operand).ok_or_else(|| invalid(line, operand));
+    let target = |operand: &str| match labels.get(operand) {
+        Some(addr) => Ok(addr - pc),
+        None if is_label(operand) => Err(AsmError::UndefinedLabel {
+            line,
+            label: operand.to_string(),
+        }),
+        None => imm(operand),
+    };
+    let encoded =
+        |result: Result<u32, EncodeError>| result.map_err(|error| AsmError::Encode { line, error });
+
+    match info.format {
+        Format::R => Ok(r_type(
+            opcode,
+            funct3,
+            funct7,
+            reg(ops[0])?,
+            reg(ops[1])?,
+            reg(ops[2])?,
+        )),
+        Format::I if info.opcode == SYSTEM => {
+            let csr = imm(ops[1])?;
+            if !(0..0x1000).contains(&csr) {
+                return Err(invalid(line, ops[1]));
+            }
+            // Immediate variants take a 5bit unsigned immediate in place of rs1.
+            let rs1 = if funct3 & 0b100 != 0 {
+                unsigned(imm(ops[2])?, 5).ok_or_else(|| invalid(line, ops[2]))?
+            } else {
+                reg(ops[2])?
+            };
+            Ok(i_type(opcode, funct3, reg(ops[0])?, rs1, csr))
+        }
+        Format::I if info.funct7.is_some() => {
+            let shamt = unsigned(imm(ops[2])?, 5).ok_or_else(|| invalid(line, ops[2]))?;
+            let imm = (funct7 << 5 | shamt) as i32;
+            Ok(i_type(opcode, funct3, reg(ops[0])?, reg(ops[1])?, imm))
+        }
+        Format::I if ops.len() == 2 => {
+            let (offset, base) = memory_operand(ops[1]).ok_or_else(|| invalid(line, ops[1]))?;
+            let (offset, base) = (imm(offset)?, reg(base)?);
+            encoded(encode_i(opcode, funct3, reg(ops[0])?, base, offset))
+        }
+        Format::I => encoded(encode_i(
+            opcode,
+            funct3,
+            reg(ops[0])?,
+            reg(ops[1])?,
+            imm(ops[2])?,
+        )),
+        Format::S => {
+            let (offset, base) = memory_operand(ops[1]).ok_or_else(|| invalid(line, ops[1]))?;
+            let (offset, base) = (imm(offset)?, reg(base)?);
+            encoded(encode_s(opcode, funct3, base, reg(ops[0])?, offset))
+        }
+        Format::B => encoded(encode_b(
+            opcode,
+            funct3,
+            reg(ops[0])?,
+            reg(ops[1])?,
+            target(ops[2])?,
+        )),
+        Format::J => encoded(encode_j(opcode, reg(ops[0])?, target(ops[1])?)),
+        Format::U => {
+            let value = unsigned(imm(ops[1])?, 20).ok_or_else(|| invalid(line, ops[1]))?;
+            Ok(u_type(opcode, reg(ops[0])?, value))
+        }
+    }
+}
+
+fn invalid(line: usize, operand: &str) -> AsmError {
+    AsmError::InvalidOperand {
+        line,
+        operand: operand.to_string(),
+    }
+}
+
+// Index of a register written as `xN` or by its ABI name.
+fn register(operand: &str) -> Option<u32> {
+    if operand == "fp" {
+        return Some(8);
+    }
+    if let Some(index) = REG_NAMES.iter().position(|name| *name == operand) {
+        return Some(index as u32);
+    }
+    let index: u32 = operand.strip_prefix('x')?.parse().ok()?;
+    (index < 32).then_some(index)
+}
+
+// Decimal or `0x` prefixed hexadecimal integer, possibly negative.
+fn immediate(operand: &str) -> Option<i32> {
+    let (negative, digits) = match operand.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, operand),
+    };
+    let value = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i64>().ok()?,
+    };
+    let value = if negative { -value } else { value };
+    // Accept any 32bit pattern, such as `0xffffffff`.
+    (i32::MIN as i64..=u32::MAX as i64)
+        .contains(&value)
+        .then_some(value as i32)
+}
+
+// `value` if it fits in a `bits` wide unsigned field.
+fn unsigned(value: i32, bits: u32) -> Option<u32> {
+    (0..1 << bits).contains(&value).then_some(value as u32)
+}
+
+// Split `offset(base)` into its offset and base register.
+fn memory_operand(operand: &str) -> Option<(&str, &str)> {
+    let (offset, base) = operand.strip_suffix(')')?.split_once('(')?;
+    let offset = offset.trim();
+    Some((if offset.is_empty() { "0" } else { offset }, base.trim()))
+}
+
+fn is_label(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encode::*;
+    use crate::memory::{Memory, VectorMemory};
+    use crate::processor::{Processor, StopReason};
+    use alloc::boxed::Box;
+
+    // Assemble `source`, load it at 0 and run it until it stops.
+    fn run(source: &str) -> (Processor, StopReason) {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.load(0, assemble(source).unwrap());
+        let reason = proc.execute();
+        (proc, reason)
+    }
+
+    #[test]
+    fn assemble_instructions() {
+        let source = "
+            addi a0, zero, 5   # comment
+            add a1, a0, a0
+            lw t0, -4(sp)
+            sw t0, 8(x2)
+            slli x5, x5, 3
+            lui x6, 0x12345
+            csrrwi x0, 0x340, 31
+            ebreak
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                addi(10, 0, 5),
+                add(11, 10, 10),
+                lw(5, 2, -4),
+                sw(2, 5, 8),
+                slli(5, 5, 3),
+                lui(6, 0x12345),
+                0x340fd073,
+                ebreak(),
+            ])
+        );
+    }
+
+    #[test]
+    fn labels() {
+        let source = "
+            li t0, 3
+        loop:
+            addi t0, t0, -1
+            bne t0, zero, loop
+            beq t0, zero, end
+            nop
+        end: ret
+        ";
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                addi(5, 0, 3),
+                addi(5, 5, -1),
+                bne(5, 0, -4),
+                beq(5, 0, 8),
+                addi(0, 0, 0),
+                jalr(0, 1, 0),
+            ])
+        );
+        assert_eq!(
+            assemble("j self\nself: j self"),
+            Ok(vec![jal(0, 4), jal(0, 0)])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("nop\nmul a0, a0, a0"),
+            Err(AsmError::UnknownMnemonic {
+                line: 2,
+                mnemonic: "mul".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("add a0, a1"),
+            Err(AsmError::WrongOperandCount {
+                line: 1,
+                expected: 3
+            })
+        );
+        assert_eq!(
+            assemble("addi a0, x32, 1"),
+            Err(AsmError::InvalidOperand {
+                line: 1,
+                operand: "x32".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("beq a0, a1, nowhere"),
+            Err(AsmError::UndefinedLabel {
+                line: 1,
+                label: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("a: nop\na: nop"),
+            Err(AsmError::DuplicateLabel {
+                line: 2,
+                label: "a".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("addi a0, a0, 2048"),
+            Err(AsmError::Encode {
+                line: 1,
+                error: EncodeError::ImmediateOutOfRange(2048)
+            })
+        );
+    }
+
+    #[test]
+    fn run_jumps() {
+        let (proc, reason) = run("
+            j skip
+            addi a0, zero, 1
+        skip:
+            ebreak
+        ");
+        assert_eq!(reason, StopReason::Breakpoint(8));
+        assert_eq!(proc.regs[10], 0);
+
+        // A call and return over an offset which is not a multiple of 8.
+        let (proc, reason) = run("
+            li a0, 3
+            jal double
+            addi a0, a0, 1
+            ebreak
+        double:
+            add a0, a0, a0
+            ret
+        ");
+        assert_eq!(reason, StopReason::Breakpoint(12));
+        assert_eq!(proc.regs[10], 7);
+        assert_eq!(proc.regs[1], 8);
+    }
+
+    #[test]
+    fn run_upper_immediates() {
+        let (proc, reason) = run("
+            lui x6, 0x12345
+            addi x6, x6, 0x678
+            auipc x7, 0x1
+            ebreak
+        ");
+        assert_eq!(reason, StopReason::Breakpoint(12));
+        assert_eq!(proc.regs[6], 0x12345678);
+        assert_eq!(proc.regs[7], 0x1008);
+    }
+}
@@ -0,0 +1,104 @@
+use alloc::boxed::Box;
+use alloc::rc::Rc;
+use core::cell::Cell;
+
+/// Source of delays between instructions set by `Processor::set_interval`
+/// and of the `time` CSR.
+pub trait Clock {
+    /// Wait for `ms` milliseconds.
+    fn sleep(&self, ms: u64);
+
+    /// Nanoseconds elapsed since the clock was created.
+    fn elapsed_ns(&self) -> u64;
+}
+
+/// Clock which actually blocks the current thread and reads the host monotonic clock.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct RealClock {
+    start: std::time::Instant,
+}
+
+#[cfg(feature = "std")]
+impl RealClock {
+    pub fn new() -> Self {
+        Self {
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for RealClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Clock for RealClock {
+    fn sleep(&self, ms: u64) {
+        std::thread::sleep(std::time::Duration::from_millis(ms));
+    }
+
+    fn elapsed_ns(&self) -> u64 {
+        self.start.elapsed().as_nanos() as u64
+    }
+}
+
+/// Clock which only records requested delays without blocking.
+/// Time advances only by the recorded delays, so tests can drive it explicitly.
+/// Clones share the record, so a clone kept by a test can inspect the requests.
+#[derive(Debug, Default, Clone)]
+pub struct MockClock {
+    slept_ms: Rc<Cell<u64>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Total delay requested so far in milliseconds.
+    pub fn slept_ms(&self) -> u64 {
+        self.slept_ms.get()
+    }
+}
+
+impl Clock for MockClock {
+    fn sleep(&self, ms: u64) {
+        self.slept_ms.set(self.slept_ms.get() + ms);
+    }
+
+    fn elapsed_ns(&self) -> u64 {
+        self.slept_ms.get() * 1_000_000
+    }
+}
+
+/// Clock used unless another one is installed.
+#[cfg(feature = "std")]
+pub(crate) fn default_clock() -> Box<dyn Clock> {
+    Box::new(RealClock::new())
+}
+
+/// Clock used unless another one is installed.
+/// There is no way to sleep without std, so delays are only recorded.
+#[cfg(not(feature = "std"))]
+pub(crate) fn default_clock() -> Box<dyn Clock> {
+    Box::new(MockClock::new())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let shared = clock.clone();
+        clock.sleep(10);
+        clock.sleep(5);
+        assert_eq!(shared.slept_ms(), 15);
+        assert_eq!(shared.elapsed_ns(), 15_000_000);
+    }
+}
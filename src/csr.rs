@@ -0,0 +1,219 @@
+use crate::exception::Exception;
+use alloc::vec;
+use alloc::vec::Vec;
+use bit_field::BitField;
+use core::ops::Range;
+
+const CSR_SIZE: usize = 4096;
+// Bits 11:10 of an address indicate whether the CSR is read-only (0b11) or not.
+const ACCESSIBILITY_RANGE: Range<usize> = 10..12;
+
+/// Entropy source of the Zkr extension.
+pub const SEED: u16 = 0x015;
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MVENDORID: u16 = 0xf11;
+pub const MARCHID: u16 = 0xf12;
+pub const MIMPID: u16 = 0xf13;
+/// Hart ID register.
+pub const MHARTID: u16 = 0xf14;
+/// Machine cycle counter, lower 32bit.
+pub const MCYCLE: u16 = 0xb00;
+/// Machine instructions-retired counter, lower 32bit.
+pub const MINSTRET: u16 = 0xb02;
+/// Machine cycle counter, upper 32bit.
+pub const MCYCLEH: u16 = 0xb80;
+/// Machine instructions-retired counter, upper 32bit.
+pub const MINSTRETH: u16 = 0xb82;
+/// Read-only shadow of `mcycle`.
+pub const CYCLE: u16 = 0xc00;
+/// Read-only shadow of `minstret`.
+pub const INSTRET: u16 = 0xc02;
+/// Read-only shadow of `mcycleh`.
+pub const CYCLEH: u16 = 0xc80;
+/// Read-only shadow of `minstreth`.
+pub const INSTRETH: u16 = 0xc82;
+/// Real-time counter, lower 32bit.
+pub const TIME: u16 = 0xc01;
+/// Real-time counter, upper 32bit.
+pub const TIMEH: u16 = 0xc81;
+
+/// Value of `misa`: RV32 with the base integer ISA only.
+pub const MISA_VALUE: u32 = 1 << 30 | 1 << 8;
+/// Value of `mvendorid`. 0 means a non-commercial implementation.
+pub const VENDOR_ID: u32 = 0;
+/// Value of `marchid`. 0 means not implemented.
+pub const ARCH_ID: u32 = 0;
+/// Value of `mimpid`. 0 means not implemented.
+pub const IMP_ID: u32 = 0;
+
+/// Machine interrupt enable bit in `mstatus`.
+pub const MSTATUS_MIE: u32 = 1 << 3;
+/// Previous value of `MIE` before entering a trap.
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+/// Privilege mode before entering a trap.
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+
+// CSRs accessible in strict mode.
+const IMPLEMENTED: [u16; 23] = [
+    MSTATUS, MISA, MIE, MTVEC, MSCRATCH, MEPC, MCAUSE, MTVAL, MIP, MVENDORID, MARCHID, MIMPID,
+    MHARTID, MCYCLE, MINSTRET, MCYCLEH, MINSTRETH, CYCLE, INSTRET, CYCLEH, INSTRETH, TIME, TIMEH,
+];
+
+/// Control and status registers.
+#[derive(Debug)]
+pub struct Csr {
+    csrs: Vec<u32>,
+    // If true, accessing CSRs not implemented by this emulator raises an exception.
+    strict: bool,
+}
+
+impl Default for Csr {
+    fn default() -> Self {
+        let mut csrs = vec![0; CSR_SIZE];
+        csrs[MISA as usize] = MISA_VALUE;
+        csrs[MVENDORID as usize] = VENDOR_ID;
+        csrs[MARCHID as usize] = ARCH_ID;
+        csrs[MIMPID as usize] = IMP_ID;
+        Self {
+            csrs,
+            strict: false,
+        }
+    }
+}
+
+impl Csr {
+    /// Create CSRs holding `values` indexed by their addresses, such as
+    /// `ProcessorState::csrs` of a snapshot. Read-only CSRs are set too.
+    /// CSRs beyond the end of `values` keep their default values.
+    pub fn from_values(values: &[u32]) -> Self {
+        let mut csr = Self::default();
+        let len = values.len().min(CSR_SIZE);
+        csr.csrs[..len].copy_from_slice(&values[..len]);
+        csr
+    }
+
+    /// Whether the CSR at `addr` is implemented by this emulator.
+    pub fn is_implemented(addr: u16) -> bool {
+        IMPLEMENTED.contains(&addr)
+    }
+
+    /// In strict mode, accessing an unimplemented CSR raises an illegal instruction
+    /// exception as real hardware does. Otherwise every CSR is accessible.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Check if the CSR at `addr` can be accessed at all.
+    pub fn check_access(&self, addr: u16) -> Result<(), Exception> {
+        if self.strict && !Self::is_implemented(addr) {
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    /// Whether the CSR at `addr` is read-only.
+    pub fn is_readonly(addr: u16) -> bool {
+        addr.get_bits(ACCESSIBILITY_RANGE) == 0b11
+    }
+
+    /// Values of all CSRs indexed by their addresses.
+    pub fn values(&self) -> &[u32] {
+        &self.csrs
+    }
+
+    /// Read the CSR at `addr`.
+    pub fn read(&self, addr: u16) -> u32 {
+        self.csrs[addr as usize]
+    }
+
+    /// Write `val` to the CSR at `addr`.
+    /// Writing to a read-only CSR raises an illegal instruction exception.
+    /// Writes to `misa` are ignored, as the set of extensions is fixed.
+    pub fn write(&mut self, addr: u16, val: u32) -> Result<(), Exception> {
+        if Self::is_readonly(addr) {
+            return Err(Exception::IllegalInstruction);
+        }
+        if addr == MISA {
+            return Ok(());
+        }
+        self.csrs[addr as usize] = val;
+        Ok(())
+    }
+
+    /// Write `val` to the CSR at `addr` even if it is read-only.
+    /// This is for the emulator itself to set CSRs, not for guest programs.
+    pub(crate) fn force_write(&mut self, addr: u16, val: u32) {
+        self.csrs[addr as usize] = val;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write_csr() -> Result<(), Exception> {
+        let mut csr = Csr::default();
+        // mscratch
+        assert_eq!(csr.read(0x340), 0);
+        csr.write(0x340, 0xdeadbeef)?;
+        assert_eq!(csr.read(0x340), 0xdeadbeef);
+        Ok(())
+    }
+
+    #[test]
+    fn strict_csr() {
+        let mut csr = Csr::default();
+        assert_eq!(csr.check_access(0x7ff), Ok(()));
+
+        csr.set_strict(true);
+        assert_eq!(csr.check_access(0x7ff), Err(Exception::IllegalInstruction));
+        assert_eq!(csr.check_access(MSTATUS), Ok(()));
+    }
+
+    #[test]
+    fn id_csrs() {
+        let mut csr = Csr::default();
+        assert_eq!(csr.read(MISA), 0x40000100);
+        assert_eq!(csr.read(MVENDORID), VENDOR_ID);
+        assert_eq!(csr.read(MARCHID), ARCH_ID);
+        assert_eq!(csr.read(MIMPID), IMP_ID);
+
+        assert_eq!(csr.write(MISA, 0), Ok(()));
+        assert_eq!(csr.read(MISA), MISA_VALUE);
+        for addr in [MVENDORID, MARCHID, MIMPID, MHARTID] {
+            assert_eq!(csr.write(addr, 1), Err(Exception::IllegalInstruction));
+        }
+    }
+
+    #[test]
+    fn write_readonly_csr() {
+        let mut csr = Csr::default();
+        assert_eq!(csr.write(MHARTID, 1), Err(Exception::IllegalInstruction));
+        assert_eq!(csr.read(MHARTID), 0);
+
+        csr.force_write(MHARTID, 1);
+        assert_eq!(csr.read(MHARTID), 1);
+    }
+
+    #[test]
+    fn from_values() {
+        let mut values = vec![0; MHARTID as usize + 1];
+        values[MSCRATCH as usize] = 0xdeadbeef;
+        values[MHARTID as usize] = 7;
+        let csr = Csr::from_values(&values);
+        assert_eq!(csr.read(MSCRATCH), 0xdeadbeef);
+        assert_eq!(csr.read(MHARTID), 7);
+        // Given as 0.
+        assert_eq!(csr.read(MISA), 0);
+        assert_eq!(csr.values().len(), CSR_SIZE);
+    }
+}
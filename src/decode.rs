@@ -1,6 +1,7 @@
 use crate::exception::Exception;
 use bit_field::BitField;
-use std::ops::Range;
+use core::fmt;
+use core::ops::Range;
 
 const OPCODE_RANGE: Range<usize> = 0..7;
 const RD_RANGE: Range<usize> = 7..12;
@@ -11,9 +12,17 @@ const FUNCT7_RANGE: Range<usize> = 25..32;
 const IMM_RANGE: Range<usize> = 20..32;
 const UPPER_IMM_RANGE: Range<usize> = 12..32;
 
+// Instructions which are identified by the whole word.
+const ECALL: u32 = 0x00000073;
+const EBREAK: u32 = 0x00100073;
+const MRET: u32 = 0x30200073;
+const WFI: u32 = 0x10500073;
+// A `fence` hinting a spin-wait loop (Zihintpause).
+const PAUSE: u32 = 0x0100000f;
+
 /// Enumerates instructions.
 /// Each entry have a struct holding parameters such as register index.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     // R-Type
     Add(RType),
@@ -69,50 +78,169 @@ pub enum Instruction {
     // U-Type
     Lui(UType),
     Auipc(UType),
+
+    // Instructions without operands
+    Ecall,
+    Ebreak,
+    Mret,
+    Pause,
+    Wfi,
 }
 
 /// Parameters common to R-Type instructions.
 /// This is the same for structs below.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RType {
     pub rd: usize,
     pub rs1: usize,
     pub rs2: usize,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct IType {
     pub rd: usize,
     pub rs1: usize,
     pub imm: u16,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SType {
     pub rs1: usize,
     pub rs2: usize,
     pub imm: u16,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BType {
     pub rs1: usize,
     pub rs2: usize,
     pub imm: u16,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UType {
     pub rd: usize,
+    /// The upper 20 bits of the value, not shifted into place.
     pub imm: u32,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct JType {
     pub rd: usize,
+    /// The offset in bytes, sign-extended.
     pub imm: u32,
 }
 
+/// An instruction interpreted by a decoder installed with
+/// `Processor::set_custom_decoder`.
+/// `id` is chosen by the decoder so that the executor can tell instructions apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomInstruction {
+    pub id: u32,
+    pub raw: u32,
+}
+
+/// Instruction formats of the base ISA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+/// Encoding of an instruction supported by `decode`.
+/// Fields which don't identify the instruction are `None`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionInfo {
+    pub mnemonic: &'static str,
+    pub format: Format,
+    pub opcode: u8,
+    pub funct3: Option<u8>,
+    /// Bits 31:25, which also distinguish shift instructions with an immediate.
+    pub funct7: Option<u8>,
+    /// Bits 31:20 of instructions without operands such as `ebreak`.
+    pub funct12: Option<u16>,
+}
+
+const fn info(
+    mnemonic: &'static str,
+    format: Format,
+    opcode: u8,
+    funct3: Option<u8>,
+    funct7: Option<u8>,
+    funct12: Option<u16>,
+) -> InstructionInfo {
+    InstructionInfo {
+        mnemonic,
+        format,
+        opcode,
+        funct3,
+        funct7,
+        funct12,
+    }
+}
+
+// One entry per variant of `Instruction`, in the same order.
+#[rustfmt::skip]
+const SUPPORTED_INSTRUCTIONS: [InstructionInfo; 48] = [
+    info("add", Format::R, 0b0110011, Some(0b000), Some(0b0000000), None),
+    info("sub", Format::R, 0b0110011, Some(0b000), Some(0b0100000), None),
+    info("sll", Format::R, 0b0110011, Some(0b001), Some(0b0000000), None),
+    info("slt", Format::R, 0b0110011, Some(0b010), Some(0b0000000), None),
+    info("sltu", Format::R, 0b0110011, Some(0b011), Some(0b0000000), None),
+    info("xor", Format::R, 0b0110011, Some(0b100), Some(0b0000000), None),
+    info("srl", Format::R, 0b0110011, Some(0b101), Some(0b0000000), None),
+    info("sra", Format::R, 0b0110011, Some(0b101), Some(0b0100000), None),
+    info("or", Format::R, 0b0110011, Some(0b110), Some(0b0000000), None),
+    info("and", Format::R, 0b0110011, Some(0b111), Some(0b0000000), None),
+    info("jalr", Format::I, 0b1100111, Some(0b000), None, None),
+    info("addi", Format::I, 0b0010011, Some(0b000), None, None),
+    info("slli", Format::I, 0b0010011, Some(0b001), Some(0b0000000), None),
+    info("slti", Format::I, 0b0010011, Some(0b010), None, None),
+    info("sltiu", Format::I, 0b0010011, Some(0b011), None, None),
+    info("xori", Format::I, 0b0010011, Some(0b100), None, None),
+    info("srli", Format::I, 0b0010011, Some(0b101), Some(0b0000000), None),
+    info("srai", Format::I, 0b0010011, Some(0b101), Some(0b0100000), None),
+    info("ori", Format::I, 0b0010011, Some(0b110), None, None),
+    info("andi", Format::I, 0b0010011, Some(0b111), None, None),
+    info("lb", Format::I, 0b0000011, Some(0b000), None, None),
+    info("lh", Format::I, 0b0000011, Some(0b001), None, None),
+    info("lw", Format::I, 0b0000011, Some(0b010), None, None),
+    info("lbu", Format::I, 0b0000011, Some(0b100), None, None),
+    info("lhu", Format::I, 0b0000011, Some(0b101), None, None),
+    info("csrrw", Format::I, 0b1110011, Some(0b001), None, None),
+    info("csrrs", Format::I, 0b1110011, Some(0b010), None, None),
+    info("csrrc", Format::I, 0b1110011, Some(0b011), None, None),
+    info("csrrwi", Format::I, 0b1110011, Some(0b101), None, None),
+    info("csrrsi", Format::I, 0b1110011, Some(0b110), None, None),
+    info("csrrci", Format::I, 0b1110011, Some(0b111), None, None),
+    info("sb", Format::S, 0b0100011, Some(0b000), None, None),
+    info("sh", Format::S, 0b0100011, Some(0b001), None, None),
+    info("sw", Format::S, 0b0100011, Some(0b010), None, None),
+    info("beq", Format::B, 0b1100011, Some(0b000), None, None),
+    info("bne", Format::B, 0b1100011, Some(0b001), None, None),
+    info("blt", Format::B, 0b1100011, Some(0b100), None, None),
+    info("bge", Format::B, 0b1100011, Some(0b101), None, None),
+    info("bltu", Format::B, 0b1100011, Some(0b110), None, None),
+    info("bgeu", Format::B, 0b1100011, Some(0b111), None, None),
+    info("jal", Format::J, 0b1101111, None, None, None),
+    info("lui", Format::U, 0b0110111, None, None, None),
+    info("auipc", Format::U, 0b0010111, None, None, None),
+    info("ecall", Format::I, 0b1110011, Some(0b000), None, Some(0x000)),
+    info("ebreak", Format::I, 0b1110011, Some(0b000), None, Some(0x001)),
+    info("mret", Format::I, 0b1110011, Some(0b000), None, Some(0x302)),
+    info("pause", Format::I, 0b0001111, Some(0b000), None, Some(0x010)),
+    info("wfi", Format::I, 0b1110011, Some(0b000), None, Some(0x105)),
+];
+
+/// Instructions `decode` supports and how they are encoded.
+pub fn supported_instructions() -> &'static [InstructionInfo] {
+    &SUPPORTED_INSTRUCTIONS
+}
+
 impl RType {
     fn new(instruction: u32) -> Self {
         Self {
@@ -135,7 +263,7 @@ impl IType {
 
 impl SType {
     fn new(instruction: u32) -> Self {
-        let imm = instruction.get_bits(7..12) + (instruction.get_bits(25..32) << 5);
+        let imm = instruction.get_bits(7..12) | (instruction.get_bits(25..32) << 5);
         Self {
             rs1: instruction.get_bits(RS1_RANGE) as usize,
             rs2: instruction.get_bits(RS2_RANGE) as usize,
@@ -147,9 +275,9 @@ impl SType {
 impl BType {
     fn new(instruction: u32) -> Self {
         let imm = (instruction.get_bits(8..12)
-            + (instruction.get_bits(25..31) << 4)
-            + (instruction.get_bits(7..8) << 10)
-            + (instruction.get_bits(31..32) << 11))
+            | (instruction.get_bits(25..31) << 4)
+            | (instruction.get_bits(7..8) << 10)
+            | (instruction.get_bits(31..32) << 11))
             << 1;
         Self {
             rs1: instruction.get_bits(RS1_RANGE) as usize,
@@ -161,7 +289,7 @@ impl BType {
 
 impl UType {
     fn new(instruction: u32) -> Self {
-        let imm = instruction.get_bits(UPPER_IMM_RANGE) << 12;
+        let imm = instruction.get_bits(UPPER_IMM_RANGE);
         Self {
             rd: instruction.get_bits(RD_RANGE) as usize,
             imm,
@@ -171,10 +299,11 @@ impl UType {
 
 impl JType {
     fn new(instruction: u32) -> Self {
-        let imm = instruction.get_bits(21..31)
-            + (instruction.get_bits(20..21) << 10)
-            + (instruction.get_bits(12..20) << 11)
-            + (instruction.get_bits(31..32) << 19);
+        let imm = (instruction.get_bits(21..31) << 1)
+            | (instruction.get_bits(20..21) << 11)
+            | (instruction.get_bits(12..20) << 12)
+            | (instruction.get_bits(31..32) << 20);
+        let imm = sext(imm, 21) as u32;
         Self {
             rd: instruction.get_bits(RD_RANGE) as usize,
             imm,
@@ -182,15 +311,37 @@ impl JType {
     }
 }
 
+/// Optional extensions accepted by `decode_with_extensions`. All are enabled by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// CSR instructions. Disabling them models a pure RV32I core.
+    pub zicsr: bool,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self { zicsr: true }
+    }
+}
+
 /// Decode an instruction.
+/// This never panics; words which are not valid instructions result in an `Err`.
 pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
+    decode_with_extensions(instruction, Extensions::default())
+}
+
+/// Decode an instruction, regarding those of disabled extensions as illegal.
+pub fn decode_with_extensions(
+    instruction: u32,
+    extensions: Extensions,
+) -> Result<Instruction, Exception> {
     let decoded = match instruction.get_bits(OPCODE_RANGE) {
         // R-Type
         0b0110011 => match instruction.get_bits(FUNCT3_RANGE) {
             0b000 => match instruction.get_bits(FUNCT7_RANGE) {
                 0b0000000 => Instruction::Add(RType::new(instruction)),
                 0b0100000 => Instruction::Sub(RType::new(instruction)),
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction),
             },
             0b001 => Instruction::Sll(RType::new(instruction)),
             0b010 => Instruction::Slt(RType::new(instruction)),
@@ -199,7 +350,7 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
             0b101 => match instruction.get_bits(FUNCT7_RANGE) {
                 0b0000000 => Instruction::Srl(RType::new(instruction)),
                 0b0100000 => Instruction::Sra(RType::new(instruction)),
-                _ => panic!("Invalid instruction"),
+                _ => return Err(Exception::IllegalInstruction),
             },
             0b110 => Instruction::Or(RType::new(instruction)),
             0b111 => Instruction::And(RType::new(instruction)),
@@ -209,7 +360,7 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
         // I Type
         0b1100111 => {
             let decoded = IType::new(instruction);
-            if decoded.imm % 4 != 0 {
+            if !decoded.imm.is_multiple_of(4) {
                 return Err(Exception::InstructionAddressMisaligned);
             }
             Instruction::Jalr(decoded)
@@ -238,6 +389,14 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
             _ => return Err(Exception::IllegalInstruction),
         },
         0b1110011 => match instruction.get_bits(FUNCT3_RANGE) {
+            0b000 => match instruction {
+                ECALL => Instruction::Ecall,
+                EBREAK => Instruction::Ebreak,
+                MRET => Instruction::Mret,
+                WFI => Instruction::Wfi,
+                _ => return Err(Exception::IllegalInstruction),
+            },
+            _ if !extensions.zicsr => return Err(Exception::IllegalInstruction),
             0b001 => Instruction::Csrrw(IType::new(instruction)),
             0b010 => Instruction::Csrrs(IType::new(instruction)),
             0b011 => Instruction::Csrrc(IType::new(instruction)),
@@ -246,6 +405,11 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
             0b111 => Instruction::Csrrci(IType::new(instruction)),
             _ => return Err(Exception::IllegalInstruction),
         },
+        // Other `fence` variants are not supported.
+        0b0001111 => match instruction {
+            PAUSE => Instruction::Pause,
+            _ => return Err(Exception::IllegalInstruction),
+        },
 
         // S-Type
         0b0100011 => match instruction.get_bits(FUNCT3_RANGE) {
@@ -270,7 +434,7 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
         0b1101111 => {
             let decoded = JType::new(instruction);
             // Target address should be aligned 4byte boundary.
-            if decoded.imm % 4 != 0 {
+            if !decoded.imm.is_multiple_of(4) {
                 return Err(Exception::InstructionAddressMisaligned);
             }
             Instruction::Jal(decoded)
@@ -284,9 +448,292 @@ pub fn decode(instruction: u32) -> Result<Instruction, Exception> {
     Ok(decoded)
 }
 
+/// Decode an instruction at the head of little-endian `bytes`, also returning how many
+/// bytes it occupies.
+/// Compressed (16bit) instructions are not supported yet, so they are reported as
+/// `IllegalInstruction`.
+pub fn decode_with_len(bytes: &[u8]) -> Result<(Instruction, u8), Exception> {
+    let low = *bytes.first().ok_or(Exception::InstructionAccessFault)?;
+    // The lowest 2 bits are 0b11 except for 16bit instructions, and the lowest 5 bits are
+    // 0b11111 for instructions longer than 32bit.
+    if low & 0b11 != 0b11 || low & 0b11100 == 0b11100 {
+        return Err(Exception::IllegalInstruction);
+    }
+    if bytes.len() < 4 {
+        return Err(Exception::InstructionAccessFault);
+    }
+    let instruction = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    Ok((decode(instruction)?, 4))
+}
+
+// ABI names of registers.
+pub(crate) const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+// Interpret lower `bits` bits of `value` as a signed integer.
+fn sext(value: u32, bits: u32) -> i32 {
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+impl Instruction {
+    /// Format of the instruction. Instructions without operands are I-type.
+    pub fn format(&self) -> Format {
+        match self {
+            Instruction::Add(_)
+            | Instruction::Sub(_)
+            | Instruction::Sll(_)
+            | Instruction::Slt(_)
+            | Instruction::Sltu(_)
+            | Instruction::Xor(_)
+            | Instruction::Srl(_)
+            | Instruction::Sra(_)
+            | Instruction::Or(_)
+            | Instruction::And(_) => Format::R,
+            Instruction::Sb(_) | Instruction::Sh(_) | Instruction::Sw(_) => Format::S,
+            Instruction::Beq(_)
+            | Instruction::Bne(_)
+            | Instruction::Blt(_)
+            | Instruction::Bge(_)
+            | Instruction::Bltu(_)
+            | Instruction::Bgeu(_) => Format::B,
+            Instruction::Lui(_) | Instruction::Auipc(_) => Format::U,
+            Instruction::Jal(_) => Format::J,
+            _ => Format::I,
+        }
+    }
+
+    /// Mnemonic of the instruction, such as `addi`.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Instruction::Add(_) => "add",
+            Instruction::Sub(_) => "sub",
+            Instruction::Sll(_) => "sll",
+            Instruction::Slt(_) => "slt",
+            Instruction::Sltu(_) => "sltu",
+            Instruction::Xor(_) => "xor",
+            Instruction::Srl(_) => "srl",
+            Instruction::Sra(_) => "sra",
+            Instruction::Or(_) => "or",
+            Instruction::And(_) => "and",
+            Instruction::Jalr(_) => "jalr",
+            Instruction::Addi(_) => "addi",
+            Instruction::Slli(_) => "slli",
+            Instruction::Slti(_) => "slti",
+            Instruction::Sltiu(_) => "sltiu",
+            Instruction::Xori(_) => "xori",
+            Instruction::Srli(_) => "srli",
+            Instruction::Srai(_) => "srai",
+            Instruction::Ori(_) => "ori",
+            Instruction::Andi(_) => "andi",
+            Instruction::Lb(_) => "lb",
+            Instruction::Lh(_) => "lh",
+            Instruction::Lw(_) => "lw",
+            Instruction::Lbu(_) => "lbu",
+            Instruction::Lhu(_) => "lhu",
+            Instruction::Csrrw(_) => "csrrw",
+            Instruction::Csrrs(_) => "csrrs",
+            Instruction::Csrrc(_) => "csrrc",
+            Instruction::Csrrwi(_) => "csrrwi",
+            Instruction::Csrrsi(_) => "csrrsi",
+            Instruction::Csrrci(_) => "csrrci",
+            Instruction::Sb(_) => "sb",
+            Instruction::Sh(_) => "sh",
+            Instruction::Sw(_) => "sw",
+            Instruction::Beq(_) => "beq",
+            Instruction::Bne(_) => "bne",
+            Instruction::Blt(_) => "blt",
+            Instruction::Bge(_) => "bge",
+            Instruction::Bltu(_) => "bltu",
+            Instruction::Bgeu(_) => "bgeu",
+            Instruction::Jal(_) => "jal",
+            Instruction::Lui(_) => "lui",
+            Instruction::Auipc(_) => "auipc",
+            Instruction::Ecall => "ecall",
+            Instruction::Ebreak => "ebreak",
+            Instruction::Mret => "mret",
+            Instruction::Pause => "pause",
+            Instruction::Wfi => "wfi",
+        }
+    }
+}
+
+/// Disassemble an instruction in the syntax of objdump.
+/// Canonical forms of pseudo-instructions such as `nop`, `mv` and `ret` are shown as such.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let r = |idx: usize| REG_NAMES[idx];
+        let imm12 = |imm: u16| sext(imm as u32, 12);
+        let r_type = |f: &mut fmt::Formatter, name: &str, args: &RType| {
+            write!(f, "{} {},{},{}", name, r(args.rd), r(args.rs1), r(args.rs2))
+        };
+        let i_type = |f: &mut fmt::Formatter, name: &str, args: &IType| {
+            write!(
+                f,
+                "{} {},{},{}",
+                name,
+                r(args.rd),
+                r(args.rs1),
+                imm12(args.imm)
+            )
+        };
+        let shift = |f: &mut fmt::Formatter, name: &str, args: &IType| {
+            write!(
+                f,
+                "{} {},{},{}",
+                name,
+                r(args.rd),
+                r(args.rs1),
+                args.imm & 0x1f
+            )
+        };
+        let load = |f: &mut fmt::Formatter, name: &str, args: &IType| {
+            write!(
+                f,
+                "{} {},{}({})",
+                name,
+                r(args.rd),
+                imm12(args.imm),
+                r(args.rs1)
+            )
+        };
+        let store = |f: &mut fmt::Formatter, name: &str, args: &SType| {
+            write!(
+                f,
+                "{} {},{}({})",
+                name,
+                r(args.rs2),
+                imm12(args.imm),
+                r(args.rs1)
+            )
+        };
+        let csr = |f: &mut fmt::Formatter, name: &str, args: &IType| {
+            write!(f, "{} {},{:#x},{}", name, r(args.rd), args.imm, r(args.rs1))
+        };
+        let csr_imm = |f: &mut fmt::Formatter, name: &str, args: &IType| {
+            write!(f, "{} {},{:#x},{}", name, r(args.rd), args.imm, args.rs1)
+        };
+        // `beqz` and `bnez` are shown if `has_zero_form`.
+        let branch = |f: &mut fmt::Formatter, name: &str, args: &BType, has_zero_form: bool| {
+            let offset = sext(args.imm as u32, 13);
+            if has_zero_form && args.rs2 == 0 {
+                write!(f, "{}z {},{}", name, r(args.rs1), offset)
+            } else {
+                write!(f, "{} {},{},{}", name, r(args.rs1), r(args.rs2), offset)
+            }
+        };
+
+        match self {
+            // R-Type
+            Instruction::Sub(args) if args.rs1 == 0 => {
+                write!(f, "neg {},{}", r(args.rd), r(args.rs2))
+            }
+            Instruction::Sltu(args) if args.rs1 == 0 => {
+                write!(f, "snez {},{}", r(args.rd), r(args.rs2))
+            }
+            Instruction::Add(args) => r_type(f, "add", args),
+            Instruction::Sub(args) => r_type(f, "sub", args),
+            Instruction::Sll(args) => r_type(f, "sll", args),
+            Instruction::Slt(args) => r_type(f, "slt", args),
+            Instruction::Sltu(args) => r_type(f, "sltu", args),
+            Instruction::Xor(args) => r_type(f, "xor", args),
+            Instruction::Srl(args) => r_type(f, "srl", args),
+            Instruction::Sra(args) => r_type(f, "sra", args),
+            Instruction::Or(args) => r_type(f, "or", args),
+            Instruction::And(args) => r_type(f, "and", args),
+
+            // I-Type
+            Instruction::Jalr(args) if args.imm == 0 => match (args.rd, args.rs1) {
+                (0, 1) => write!(f, "ret"),
+                (0, rs1) => write!(f, "jr {}", r(rs1)),
+                (1, rs1) => write!(f, "jalr {}", r(rs1)),
+                (rd, rs1) => write!(f, "jalr {},0({})", r(rd), r(rs1)),
+            },
+            Instruction::Jalr(args) => load(f, "jalr", args),
+            Instruction::Addi(args) if args.rd == 0 && args.rs1 == 0 && args.imm == 0 => {
+                write!(f, "nop")
+            }
+            Instruction::Addi(args) if args.rs1 == 0 => {
+                write!(f, "li {},{}", r(args.rd), imm12(args.imm))
+            }
+            Instruction::Addi(args) if args.imm == 0 => {
+                write!(f, "mv {},{}", r(args.rd), r(args.rs1))
+            }
+            Instruction::Sltiu(args) if args.imm == 1 => {
+                write!(f, "seqz {},{}", r(args.rd), r(args.rs1))
+            }
+            Instruction::Xori(args) if imm12(args.imm) == -1 => {
+                write!(f, "not {},{}", r(args.rd), r(args.rs1))
+            }
+            Instruction::Addi(args) => i_type(f, "addi", args),
+            Instruction::Slli(args) => shift(f, "slli", args),
+            Instruction::Slti(args) => i_type(f, "slti", args),
+            Instruction::Sltiu(args) => i_type(f, "sltiu", args),
+            Instruction::Xori(args) => i_type(f, "xori", args),
+            Instruction::Srli(args) => shift(f, "srli", args),
+            Instruction::Srai(args) => shift(f, "srai", args),
+            Instruction::Ori(args) => i_type(f, "ori", args),
+            Instruction::Andi(args) => i_type(f, "andi", args),
+            Instruction::Lb(args) => load(f, "lb", args),
+            Instruction::Lh(args) => load(f, "lh", args),
+            Instruction::Lw(args) => load(f, "lw", args),
+            Instruction::Lbu(args) => load(f, "lbu", args),
+            Instruction::Lhu(args) => load(f, "lhu", args),
+            Instruction::Csrrw(args) if args.rd == 0 => {
+                write!(f, "csrw {:#x},{}", args.imm, r(args.rs1))
+            }
+            Instruction::Csrrs(args) if args.rs1 == 0 => {
+                write!(f, "csrr {},{:#x}", r(args.rd), args.imm)
+            }
+            Instruction::Csrrw(args) => csr(f, "csrrw", args),
+            Instruction::Csrrs(args) => csr(f, "csrrs", args),
+            Instruction::Csrrc(args) => csr(f, "csrrc", args),
+            Instruction::Csrrwi(args) => csr_imm(f, "csrrwi", args),
+            Instruction::Csrrsi(args) => csr_imm(f, "csrrsi", args),
+            Instruction::Csrrci(args) => csr_imm(f, "csrrci", args),
+
+            // S-Type
+            Instruction::Sb(args) => store(f, "sb", args),
+            Instruction::Sh(args) => store(f, "sh", args),
+            Instruction::Sw(args) => store(f, "sw", args),
+
+            // B-Type
+            Instruction::Beq(args) => branch(f, "beq", args, true),
+            Instruction::Bne(args) => branch(f, "bne", args, true),
+            Instruction::Blt(args) => branch(f, "blt", args, false),
+            Instruction::Bge(args) => branch(f, "bge", args, false),
+            Instruction::Bltu(args) => branch(f, "bltu", args, false),
+            Instruction::Bgeu(args) => branch(f, "bgeu", args, false),
+
+            // J-Type
+            Instruction::Jal(args) => match args.rd {
+                0 => write!(f, "j {}", args.imm as i32),
+                1 => write!(f, "jal {}", args.imm as i32),
+                rd => write!(f, "jal {},{}", r(rd), args.imm as i32),
+            },
+
+            // U-Type
+            Instruction::Lui(args) => write!(f, "lui {},{:#x}", r(args.rd), args.imm),
+            Instruction::Auipc(args) => write!(f, "auipc {},{:#x}", r(args.rd), args.imm),
+
+            Instruction::Ecall => write!(f, "ecall"),
+            Instruction::Ebreak => write!(f, "ebreak"),
+            Instruction::Mret => write!(f, "mret"),
+            Instruction::Pause => write!(f, "pause"),
+            Instruction::Wfi => write!(f, "wfi"),
+        }
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
 mod tests {
     use super::*;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
 
     #[test]
     fn decode_rv32i_r() -> Result<(), Exception> {
@@ -716,9 +1163,9 @@ mod tests {
 
     #[test]
     fn decode_rv32i_j() -> Result<(), Exception> {
-        // jal x1, 264704
+        // jal x1, 529408
         assert_eq!(
-            Instruction::Jal(JType { rd: 1, imm: 264704 }),
+            Instruction::Jal(JType { rd: 1, imm: 529408 }),
             decode(0b01000000000010000001_00001_1101111)?
         );
         Ok(())
@@ -738,21 +1185,228 @@ mod tests {
     fn decode_rv32_u() -> Result<(), Exception> {
         // lui x1, 623706
         assert_eq!(
-            Instruction::Lui(UType {
-                rd: 1,
-                imm: 2554699776,
-            }),
+            Instruction::Lui(UType { rd: 1, imm: 623706 }),
             decode(0b10011000010001011010_00001_0110111)?
         );
 
         // auipc x1, 103275
         assert_eq!(
-            Instruction::Auipc(UType {
-                rd: 1,
-                imm: 423014400,
-            }),
+            Instruction::Auipc(UType { rd: 1, imm: 103275 }),
             decode(0b00011001001101101011_00001_0010111)?
         );
         Ok(())
     }
+
+    #[test]
+    fn decode_length() -> Result<(), Exception> {
+        // addi a5, a5, 1
+        assert_eq!(
+            (
+                Instruction::Addi(IType {
+                    rd: 15,
+                    rs1: 15,
+                    imm: 1,
+                }),
+                4
+            ),
+            decode_with_len(&[0x93, 0x87, 0x17, 0x00, 0xff])?
+        );
+
+        // c.addi a5, 1
+        assert_eq!(
+            Err(Exception::IllegalInstruction),
+            decode_with_len(&[0x85, 0x07])
+        );
+
+        // 48bit instruction
+        assert_eq!(
+            Err(Exception::IllegalInstruction),
+            decode_with_len(&[0x1f, 0x00, 0x00, 0x00, 0x00, 0x00])
+        );
+
+        // Truncated 32bit instruction
+        assert_eq!(
+            Err(Exception::InstructionAccessFault),
+            decode_with_len(&[0x93, 0x87])
+        );
+        assert_eq!(Err(Exception::InstructionAccessFault), decode_with_len(&[]));
+        Ok(())
+    }
+
+    #[test]
+    fn display() -> Result<(), Exception> {
+        let disasm = |word: u32| decode(word).map(|inst| inst.to_string());
+        assert_eq!(disasm(0x00000013)?, "nop");
+        assert_eq!(disasm(0x00008067)?, "ret");
+        assert_eq!(disasm(0x000780e7)?, "jalr a5");
+        assert_eq!(disasm(0x00078067)?, "jr a5");
+        assert_eq!(disasm(0x00c12183)?, "lw gp,12(sp)");
+        assert_eq!(disasm(0x00112623)?, "sw ra,12(sp)");
+        assert_eq!(disasm(0x00178793)?, "addi a5,a5,1");
+        assert_eq!(disasm(0xff010113)?, "addi sp,sp,-16");
+        assert_eq!(disasm(0x02a00113)?, "li sp,42");
+        assert_eq!(disasm(0x00078513)?, "mv a0,a5");
+        assert_eq!(disasm(0x010787b3)?, "add a5,a5,a6");
+        assert_eq!(disasm(0xfe209ce3)?, "bne ra,sp,-8");
+        assert_eq!(disasm(0xfe008ee3)?, "beqz ra,-4");
+        assert_eq!(disasm(0x0080006f)?, "j 8");
+        assert_eq!(disasm(0xffdff0ef)?, "jal -4");
+        assert_eq!(disasm(0x0100056f)?, "jal a0,16");
+        assert_eq!(disasm(0x12345537)?, "lui a0,0x12345");
+        assert_eq!(disasm(0xc01020f3)?, "csrr ra,0xc01");
+        assert_eq!(disasm(0x3400a173)?, "csrrs sp,0x340,ra");
+        assert_eq!(disasm(0x00000073)?, "ecall");
+        assert_eq!(disasm(0x00100073)?, "ebreak");
+        Ok(())
+    }
+
+    #[test]
+    fn decode_rv32i_system() -> Result<(), Exception> {
+        // ecall
+        assert_eq!(
+            Instruction::Ecall,
+            decode(0b000000000000_00000_000_00000_1110011)?
+        );
+
+        // ebreak
+        assert_eq!(
+            Instruction::Ebreak,
+            decode(0b000000000001_00000_000_00000_1110011)?
+        );
+
+        // ebreak with nonzero rd is not valid.
+        assert_eq!(
+            Err(Exception::IllegalInstruction),
+            decode(0b000000000001_00000_000_00001_1110011)
+        );
+
+        // mret
+        assert_eq!(
+            Instruction::Mret,
+            decode(0b001100000010_00000_000_00000_1110011)?
+        );
+
+        // wfi
+        assert_eq!(
+            Instruction::Wfi,
+            decode(0b000100000101_00000_000_00000_1110011)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn decode_pause() {
+        assert_eq!(decode(0x0100000f), Ok(Instruction::Pause));
+        // fence rw,rw
+        assert_eq!(decode(0x0330000f), Err(Exception::IllegalInstruction));
+    }
+
+    #[test]
+    fn decode_invalid_rv32i_r() {
+        // add and srl with an unknown funct7
+        assert_eq!(
+            Err(Exception::IllegalInstruction),
+            decode(0b0000001_00011_00010_000_00001_0110011)
+        );
+        assert_eq!(
+            Err(Exception::IllegalInstruction),
+            decode(0b0010000_00011_00010_101_00001_0110011)
+        );
+    }
+
+    #[test]
+    fn supported_instructions() {
+        let addi = super::supported_instructions()
+            .iter()
+            .find(|info| info.mnemonic == "addi")
+            .unwrap();
+        assert_eq!(addi.format, Format::I);
+        assert_eq!(addi.opcode, 0b0010011);
+
+        // Every entry decodes to a distinct variant, covering all 48 variants.
+        let mut decoded: Vec<Instruction> = Vec::new();
+        for info in super::supported_instructions() {
+            let mut word = info.opcode as u32;
+            word.set_bits(FUNCT3_RANGE, info.funct3.unwrap_or(0) as u32);
+            word.set_bits(FUNCT7_RANGE, info.funct7.unwrap_or(0) as u32);
+            if let Some(funct12) = info.funct12 {
+                word.set_bits(IMM_RANGE, funct12 as u32);
+            }
+            let inst = decode(word).unwrap();
+            assert_eq!(inst.mnemonic(), info.mnemonic);
+            assert!(decoded
+                .iter()
+                .all(|d| core::mem::discriminant(d) != core::mem::discriminant(&inst)));
+            decoded.push(inst);
+        }
+        assert_eq!(decoded.len(), 48);
+    }
+
+    #[test]
+    fn decode_never_panics() {
+        // Pseudo-random words (xorshift32), then words spread over the whole range.
+        let mut x: u32 = 0x12345678;
+        for _ in 0..1 << 20 {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            if let Ok(inst) = decode(x) {
+                let _ = inst.to_string();
+            }
+        }
+        for word in (0..=u32::MAX).step_by(4099) {
+            if let Ok(inst) = decode(word) {
+                let _ = inst.to_string();
+            }
+        }
+    }
+
+    #[test]
+    fn decode_without_zicsr() {
+        // csrrw x1,mscratch,x2
+        let csrrw = 0x340110f3;
+        let pure = Extensions { zicsr: false };
+        assert_eq!(
+            decode_with_extensions(csrrw, pure),
+            Err(Exception::IllegalInstruction)
+        );
+        assert_eq!(
+            decode_with_extensions(csrrw, Extensions::default()),
+            Ok(Instruction::Csrrw(IType {
+                rd: 1,
+                rs1: 2,
+                imm: 0x340
+            }))
+        );
+        // Other system instructions are not part of Zicsr.
+        assert_eq!(
+            decode_with_extensions(0x00100073, pure),
+            Ok(Instruction::Ebreak)
+        );
+    }
+
+    #[test]
+    fn register_fields_in_range() {
+        // All bits set gives the largest value each field can hold.
+        let r = RType::new(u32::MAX);
+        assert_eq!((r.rd, r.rs1, r.rs2), (31, 31, 31));
+        let i = IType::new(u32::MAX);
+        assert_eq!((i.rd, i.rs1, i.imm), (31, 31, 0xfff));
+        let s = SType::new(u32::MAX);
+        assert_eq!((s.rs1, s.rs2, s.imm), (31, 31, 0xfff));
+        let b = BType::new(u32::MAX);
+        assert_eq!((b.rs1, b.rs2), (31, 31));
+        assert_eq!(UType::new(u32::MAX).rd, 31);
+        assert_eq!(JType::new(u32::MAX).rd, 31);
+
+        // csrrwi x31,0xfff,31: zimm and the CSR address stay within their fields.
+        assert_eq!(
+            decode(0xffffdff3),
+            Ok(Instruction::Csrrwi(IType {
+                rd: 31,
+                rs1: 31,
+                imm: 0xfff
+            }))
+        );
+    }
 }
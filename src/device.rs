@@ -0,0 +1,179 @@
+use crate::processor::StopReason;
+use alloc::rc::Rc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Ref, RefCell};
+
+/// A device mapped into the address space of a processor.
+/// Loads and stores to the mapped range are forwarded to the device instead of memory.
+pub trait MmioDevice {
+    /// Size of the mapped range in bytes.
+    fn size(&self) -> usize;
+
+    /// Read `size` bytes at `offset` from the base address of the device.
+    fn read(&mut self, offset: usize, size: usize) -> u32;
+
+    /// Write lower `size` bytes of `value` at `offset` from the base address of the device.
+    fn write(&mut self, offset: usize, size: usize, value: u32);
+
+    /// Take a request to stop execution, if the device has issued one.
+    fn take_stop_request(&mut self) -> Option<StopReason> {
+        None
+    }
+}
+
+/// Magic value written to `ExitDevice` to exit successfully.
+pub const EXIT_SUCCESS: u32 = 0x5555;
+
+/// Magic value written to `ExitDevice` to exit with failure.
+/// Upper 16bit of the written value holds the exit code.
+pub const EXIT_FAILURE: u32 = 0x3333;
+
+/// A device to terminate execution from the guest, like QEMU's `sifive_test`.
+/// Writing `EXIT_SUCCESS` stops with exit code 0, and writing `EXIT_FAILURE | (code << 16)`
+/// stops with `code`, or 1 if `code` is 0 so that a failure never looks like success.
+/// Other values, and anything but a word written at offset 0, are ignored.
+#[derive(Debug, Default)]
+pub struct ExitDevice {
+    exit_code: Option<u32>,
+}
+
+impl ExitDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MmioDevice for ExitDevice {
+    fn size(&self) -> usize {
+        4
+    }
+
+    fn read(&mut self, _offset: usize, _size: usize) -> u32 {
+        0
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u32) {
+        if offset != 0 || size != 4 {
+            return;
+        }
+        match value & 0xffff {
+            EXIT_SUCCESS => self.exit_code = Some(0),
+            EXIT_FAILURE => self.exit_code = Some((value >> 16).max(1)),
+            _ => (),
+        }
+    }
+
+    fn take_stop_request(&mut self) -> Option<StopReason> {
+        self.exit_code.take().map(StopReason::Exit)
+    }
+}
+
+/// A frame buffer of `width` x `height` pixels, each of which is a 32bit word laid out in
+/// row-major order. Windowing is up to the host, which renders `pixels`.
+/// Clones share the pixels, so a clone kept by the host can see what the guest drew.
+#[derive(Debug, Clone)]
+pub struct Framebuffer {
+    width: usize,
+    height: usize,
+    pixels: Rc<RefCell<Vec<u32>>>,
+}
+
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: Rc::new(RefCell::new(vec![0; width * height])),
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixels in row-major order.
+    pub fn pixels(&self) -> Ref<'_, [u32]> {
+        Ref::map(self.pixels.borrow(), |pixels| pixels.as_slice())
+    }
+}
+
+impl MmioDevice for Framebuffer {
+    fn size(&self) -> usize {
+        self.width * self.height * 4
+    }
+
+    // Accesses go byte by byte, as a misaligned one may span two pixels.
+    fn read(&mut self, offset: usize, size: usize) -> u32 {
+        let pixels = self.pixels.borrow();
+        (0..size).fold(0, |value, i| {
+            let shift = (offset + i) % 4 * 8;
+            let byte = (pixels[(offset + i) / 4] >> shift) & 0xff;
+            value | byte << (i * 8)
+        })
+    }
+
+    fn write(&mut self, offset: usize, size: usize, value: u32) {
+        let mut pixels = self.pixels.borrow_mut();
+        for i in 0..size {
+            let pixel = &mut pixels[(offset + i) / 4];
+            let shift = (offset + i) % 4 * 8;
+            let byte = (value >> (i * 8)) & 0xff;
+            *pixel = (*pixel & !(0xff << shift)) | byte << shift;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_device() {
+        let mut device = ExitDevice::new();
+        assert_eq!(device.take_stop_request(), None);
+
+        device.write(0, 4, 0x1234);
+        assert_eq!(device.take_stop_request(), None);
+
+        device.write(0, 4, EXIT_SUCCESS);
+        assert_eq!(device.take_stop_request(), Some(StopReason::Exit(0)));
+        assert_eq!(device.take_stop_request(), None);
+
+        device.write(0, 4, EXIT_FAILURE | (3 << 16));
+        assert_eq!(device.take_stop_request(), Some(StopReason::Exit(3)));
+
+        device.write(0, 4, EXIT_FAILURE);
+        assert_eq!(device.take_stop_request(), Some(StopReason::Exit(1)));
+
+        // Only a word at offset 0 is a command.
+        device.write(0, 1, EXIT_SUCCESS);
+        device.write(0, 2, EXIT_SUCCESS);
+        device.write(2, 2, EXIT_SUCCESS);
+        assert_eq!(device.take_stop_request(), None);
+    }
+
+    #[test]
+    fn framebuffer() {
+        let mut fb = Framebuffer::new(2, 2);
+        assert_eq!(fb.size(), 16);
+
+        fb.write(4, 4, 0x00ff00ff);
+        fb.write(8, 1, 0x12);
+        fb.write(10, 2, 0x3456);
+        assert_eq!(&*fb.pixels(), &[0, 0x00ff00ff, 0x34560012, 0]);
+        assert_eq!(fb.read(4, 4), 0x00ff00ff);
+        assert_eq!(fb.read(10, 2), 0x3456);
+        assert_eq!(fb.read(11, 1), 0x34);
+
+        // A misaligned access spans two pixels.
+        fb.write(2, 4, 0x44332211);
+        assert_eq!(&*fb.pixels(), &[0x22110000, 0x00ff4433, 0x34560012, 0]);
+        assert_eq!(fb.read(2, 4), 0x44332211);
+        assert_eq!(fb.read(7, 2), 0x1200);
+    }
+}
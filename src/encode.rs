@@ -0,0 +1,420 @@
+//! Helpers to assemble RV32I instruction words, mainly for building test programs.
+
+use crate::decode::{supported_instructions, Instruction};
+
+/// Reasons why an instruction cannot be encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The immediate does not fit in the field of the instruction.
+    ImmediateOutOfRange(i32),
+    /// The offset of a branch or jump is odd.
+    MisalignedOffset(i32),
+}
+
+// Check `imm` fits in a `bits` wide signed field.
+fn check_range(imm: i32, bits: u32) -> Result<(), EncodeError> {
+    let limit = 1 << (bits - 1);
+    if (-limit..limit).contains(&imm) {
+        Ok(())
+    } else {
+        Err(EncodeError::ImmediateOutOfRange(imm))
+    }
+}
+
+// Check `offset` is a valid offset of a branch or jump with a `bits` wide field.
+fn check_offset(offset: i32, bits: u32) -> Result<(), EncodeError> {
+    if offset % 2 != 0 {
+        return Err(EncodeError::MisalignedOffset(offset));
+    }
+    check_range(offset, bits)
+}
+
+/// Like `i_type`, but fails if `imm` does not fit in 12 bits.
+pub fn encode_i(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> Result<u32, EncodeError> {
+    check_range(imm, 12)?;
+    Ok(i_type(opcode, funct3, rd, rs1, imm))
+}
+
+/// Like `s_type`, but fails if `imm` does not fit in 12 bits.
+pub fn encode_s(
+    opcode: u32,
+    funct3: u32,
+    rs1: u32,
+    rs2: u32,
+    imm: i32,
+) -> Result<u32, EncodeError> {
+    check_range(imm, 12)?;
+    Ok(s_type(opcode, funct3, rs1, rs2, imm))
+}
+
+/// Like `b_type`, but fails if `offset` is odd or does not fit in 13 bits.
+pub fn encode_b(
+    opcode: u32,
+    funct3: u32,
+    rs1: u32,
+    rs2: u32,
+    offset: i32,
+) -> Result<u32, EncodeError> {
+    check_offset(offset, 13)?;
+    Ok(b_type(opcode, funct3, rs1, rs2, offset))
+}
+
+/// Like `j_type`, but fails if `offset` is odd or does not fit in 21 bits.
+pub fn encode_j(opcode: u32, rd: u32, offset: i32) -> Result<u32, EncodeError> {
+    check_offset(offset, 21)?;
+    Ok(j_type(opcode, rd, offset))
+}
+
+/// Assemble an R-type instruction.
+pub const fn r_type(opcode: u32, funct3: u32, funct7: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// Assemble an I-type instruction. Only the lower 12 bits of `imm` are used.
+pub const fn i_type(opcode: u32, funct3: u32, rd: u32, rs1: u32, imm: i32) -> u32 {
+    (imm as u32 & 0xfff) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+/// Assemble an S-type instruction. Only the lower 12 bits of `imm` are used.
+pub const fn s_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7f) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1f) << 7 | opcode
+}
+
+/// Assemble a B-type instruction. `offset` is relative to the branch and must be even.
+pub const fn b_type(opcode: u32, funct3: u32, rs1: u32, rs2: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3f) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xf) << 8
+        | (imm >> 11 & 1) << 7
+        | opcode
+}
+
+/// Assemble a U-type instruction. `imm` is the value of the upper 20 bits.
+pub const fn u_type(opcode: u32, rd: u32, imm: u32) -> u32 {
+    (imm & 0xfffff) << 12 | rd << 7 | opcode
+}
+
+/// Assemble a J-type instruction. `offset` is relative to the jump and must be even.
+pub const fn j_type(opcode: u32, rd: u32, offset: i32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3ff) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xff) << 12
+        | rd << 7
+        | opcode
+}
+
+/// Encode a decoded instruction back into its word.
+pub fn instruction(inst: &Instruction) -> u32 {
+    let info = supported_instructions()
+        .iter()
+        .find(|info| info.mnemonic == inst.mnemonic())
+        .expect("every instruction is in the table");
+    let opcode = info.opcode as u32;
+    let funct3 = info.funct3.unwrap_or(0) as u32;
+    let funct7 = info.funct7.unwrap_or(0) as u32;
+    match inst {
+        Instruction::Add(args)
+        | Instruction::Sub(args)
+        | Instruction::Sll(args)
+        | Instruction::Slt(args)
+        | Instruction::Sltu(args)
+        | Instruction::Xor(args)
+        | Instruction::Srl(args)
+        | Instruction::Sra(args)
+        | Instruction::Or(args)
+        | Instruction::And(args) => r_type(
+            opcode,
+            funct3,
+            funct7,
+            args.rd as u32,
+            args.rs1 as u32,
+            args.rs2 as u32,
+        ),
+        // The immediate of shifts includes funct7.
+        Instruction::Jalr(args)
+        | Instruction::Addi(args)
+        | Instruction::Slli(args)
+        | Instruction::Slti(args)
+        | Instruction::Sltiu(args)
+        | Instruction::Xori(args)
+        | Instruction::Srli(args)
+        | Instruction::Srai(args)
+        | Instruction::Ori(args)
+        | Instruction::Andi(args)
+        | Instruction::Lb(args)
+        | Instruction::Lh(args)
+        | Instruction::Lw(args)
+        | Instruction::Lbu(args)
+        | Instruction::Lhu(args)
+        | Instruction::Csrrw(args)
+        | Instruction::Csrrs(args)
+        | Instruction::Csrrc(args)
+        | Instruction::Csrrwi(args)
+        | Instruction::Csrrsi(args)
+        | Instruction::Csrrci(args) => i_type(
+            opcode,
+            funct3,
+            args.rd as u32,
+            args.rs1 as u32,
+            args.imm as i32,
+        ),
+        Instruction::Sb(args) | Instruction::Sh(args) | Instruction::Sw(args) => s_type(
+            opcode,
+            funct3,
+            args.rs1 as u32,
+            args.rs2 as u32,
+            args.imm as i32,
+        ),
+        Instruction::Beq(args)
+        | Instruction::Bne(args)
+        | Instruction::Blt(args)
+        | Instruction::Bge(args)
+        | Instruction::Bltu(args)
+        | Instruction::Bgeu(args) => b_type(
+            opcode,
+            funct3,
+            args.rs1 as u32,
+            args.rs2 as u32,
+            args.imm as i32,
+        ),
+        Instruction::Jal(args) => j_type(opcode, args.rd as u32, args.imm as i32),
+        Instruction::Lui(args) | Instruction::Auipc(args) => {
+            u_type(opcode, args.rd as u32, args.imm)
+        }
+        Instruction::Ecall
+        | Instruction::Ebreak
+        | Instruction::Mret
+        | Instruction::Pause
+        | Instruction::Wfi => i_type(opcode, funct3, 0, 0, info.funct12.unwrap_or(0) as i32),
+    }
+}
+
+pub const fn add(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(0b0110011, 0b000, 0b0000000, rd, rs1, rs2)
+}
+
+pub const fn sub(rd: u32, rs1: u32, rs2: u32) -> u32 {
+    r_type(0b0110011, 0b000, 0b0100000, rd, rs1, rs2)
+}
+
+pub const fn addi(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0b0010011, 0b000, rd, rs1, imm)
+}
+
+pub const fn slli(rd: u32, rs1: u32, shamt: u32) -> u32 {
+    i_type(0b0010011, 0b001, rd, rs1, shamt as i32)
+}
+
+pub const fn lb(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0b0000011, 0b000, rd, rs1, imm)
+}
+
+pub const fn lw(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0b0000011, 0b010, rd, rs1, imm)
+}
+
+pub const fn sb(rs1: u32, rs2: u32, imm: i32) -> u32 {
+    s_type(0b0100011, 0b000, rs1, rs2, imm)
+}
+
+pub const fn sw(rs1: u32, rs2: u32, imm: i32) -> u32 {
+    s_type(0b0100011, 0b010, rs1, rs2, imm)
+}
+
+pub const fn beq(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0b1100011, 0b000, rs1, rs2, offset)
+}
+
+pub const fn bne(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0b1100011, 0b001, rs1, rs2, offset)
+}
+
+pub const fn blt(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0b1100011, 0b100, rs1, rs2, offset)
+}
+
+pub const fn bge(rs1: u32, rs2: u32, offset: i32) -> u32 {
+    b_type(0b1100011, 0b101, rs1, rs2, offset)
+}
+
+pub const fn lui(rd: u32, imm: u32) -> u32 {
+    u_type(0b0110111, rd, imm)
+}
+
+pub const fn jal(rd: u32, offset: i32) -> u32 {
+    j_type(0b1101111, rd, offset)
+}
+
+pub const fn jalr(rd: u32, rs1: u32, imm: i32) -> u32 {
+    i_type(0b1100111, 0b000, rd, rs1, imm)
+}
+
+pub const fn ebreak() -> u32 {
+    0x00100073
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::{decode, BType, IType, JType, UType};
+
+    // Decode `word`, encode it back and check the result decodes to the same instruction.
+    // Returns false if `word` is not a valid instruction.
+    fn assert_fixpoint(word: u32) -> bool {
+        let inst = match decode(word) {
+            Ok(inst) => inst,
+            Err(_) => return false,
+        };
+        let encoded = instruction(&inst);
+        assert_eq!(decode(encoded).as_ref(), Ok(&inst), "{:#010x}", word);
+        true
+    }
+
+    #[test]
+    fn fixpoint() {
+        let mut x: u32 = 0x2545f491;
+        for info in supported_instructions() {
+            // Bits fixed by the encoding, and the fields filled with generated values.
+            let mut fixed = 0x7f;
+            let mut word = info.opcode as u32;
+            if let Some(funct3) = info.funct3 {
+                fixed |= 0b111 << 12;
+                word |= (funct3 as u32) << 12;
+            }
+            if let Some(funct7) = info.funct7 {
+                fixed |= 0x7f << 25;
+                word |= (funct7 as u32) << 25;
+            }
+            if let Some(funct12) = info.funct12 {
+                // Instructions identified by the whole word.
+                fixed = !0;
+                word |= (funct12 as u32) << 20;
+            }
+            let mut valid = 0;
+            for _ in 0..256 {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                if assert_fixpoint(word | (x & !fixed)) {
+                    valid += 1;
+                }
+            }
+            assert!(valid > 0, "{}", info.mnemonic);
+        }
+    }
+
+    #[test]
+    fn fixpoint_objdump_words() {
+        // Words and operands as printed by objdump.
+        let cases = [
+            (
+                0xffdff0ef,
+                Instruction::Jal(JType {
+                    rd: 1,
+                    imm: -4i32 as u32,
+                }),
+            ),
+            (0x0080006f, Instruction::Jal(JType { rd: 0, imm: 8 })),
+            (
+                0x12345537,
+                Instruction::Lui(UType {
+                    rd: 10,
+                    imm: 0x12345,
+                }),
+            ),
+            (0x00001297, Instruction::Auipc(UType { rd: 5, imm: 0x1 })),
+        ];
+        for (word, inst) in cases.iter() {
+            assert_eq!(decode(*word).as_ref(), Ok(inst), "{:#010x}", word);
+            assert_eq!(instruction(inst), *word);
+            assert!(assert_fixpoint(*word));
+        }
+    }
+
+    #[test]
+    fn negative_immediates() {
+        // addi x1,x2,-1
+        let word = encode_i(0b0010011, 0b000, 1, 2, -1).unwrap();
+        assert_eq!(
+            decode(word),
+            Ok(Instruction::Addi(IType {
+                rd: 1,
+                rs1: 2,
+                imm: 0xfff
+            }))
+        );
+
+        // beq x1,x2,-8
+        let word = encode_b(0b1100011, 0b000, 1, 2, -8).unwrap();
+        let imm = match decode(word) {
+            Ok(Instruction::Beq(BType {
+                rs1: 1,
+                rs2: 2,
+                imm,
+            })) => imm,
+            inst => panic!("{:?}", inst),
+        };
+        assert_eq!(((imm as i32) << 19) >> 19, -8);
+
+        // jal x1,-0x1000
+        let word = encode_j(0b1101111, 1, -0x1000).unwrap();
+        assert_eq!(
+            decode(word),
+            Ok(Instruction::Jal(JType {
+                rd: 1,
+                imm: -0x1000i32 as u32
+            }))
+        );
+
+        assert_eq!(
+            encode_i(0b0010011, 0b000, 1, 2, -2049),
+            Err(EncodeError::ImmediateOutOfRange(-2049))
+        );
+        assert_eq!(
+            encode_s(0b0100011, 0b010, 1, 2, 2048),
+            Err(EncodeError::ImmediateOutOfRange(2048))
+        );
+        assert_eq!(
+            encode_b(0b1100011, 0b000, 1, 2, -4098),
+            Err(EncodeError::ImmediateOutOfRange(-4098))
+        );
+        assert_eq!(
+            encode_j(0b1101111, 1, 3),
+            Err(EncodeError::MisalignedOffset(3))
+        );
+        // The sign bit and the widest offsets survive the scattered fields.
+        for offset in [-0x100000, 0xffffc] {
+            let word = encode_j(0b1101111, 1, offset).unwrap();
+            assert_eq!(
+                decode(word),
+                Ok(Instruction::Jal(JType {
+                    rd: 1,
+                    imm: offset as u32
+                }))
+            );
+        }
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(addi(15, 15, 1), 0x00178793);
+        assert_eq!(slli(16, 16, 2), 0x00281813);
+        assert_eq!(add(15, 15, 16), 0x010787b3);
+        assert_eq!(sub(1, 2, 3), 0x403100b3);
+        assert_eq!(addi(1, 0, -1), 0xfff00093);
+        assert_eq!(lw(5, 2, -4), 0xffc12283);
+        assert_eq!(sw(2, 5, -4), 0xfe512e23);
+        assert_eq!(bne(1, 2, -8), 0xfe209ce3);
+        assert_eq!(beq(1, 2, 2048), 0x00208063 | 1 << 7);
+        assert_eq!(lui(1, 0x12345), 0x123450b7);
+        assert_eq!(jal(1, -4), 0xffdff0ef);
+        assert_eq!(jalr(0, 1, 0), 0x00008067);
+    }
+}
@@ -1,6 +1,99 @@
+use crate::decode::Instruction;
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum Exception {
     InstructionAddressMisaligned,
     InstructionAccessFault,
     IllegalInstruction,
+    Breakpoint,
+    LoadAddressMisaligned,
+    LoadAccessFault,
+    StoreAddressMisaligned,
+    StoreAccessFault,
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+}
+
+impl Exception {
+    /// Exception code written to `mcause`.
+    pub const fn code(&self) -> u32 {
+        match self {
+            Exception::InstructionAddressMisaligned => 0,
+            Exception::InstructionAccessFault => 1,
+            Exception::IllegalInstruction => 2,
+            Exception::Breakpoint => 3,
+            Exception::LoadAddressMisaligned => 4,
+            Exception::LoadAccessFault => 5,
+            Exception::StoreAddressMisaligned => 6,
+            Exception::StoreAccessFault => 7,
+            Exception::EnvironmentCallFromUMode => 8,
+            Exception::EnvironmentCallFromSMode => 9,
+            Exception::EnvironmentCallFromMMode => 11,
+        }
+    }
+
+    /// Process exit status for a run stopped by this exception: 100 plus `code`.
+    pub const fn to_exit_code(&self) -> i32 {
+        100 + self.code() as i32
+    }
+}
+
+/// Interrupts, whose discriminants are the exception codes in `mcause`
+/// and the bit positions in `mip`/`mie`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    SupervisorSoftware = 1,
+    MachineSoftware = 3,
+    SupervisorTimer = 5,
+    MachineTimer = 7,
+    SupervisorExternal = 9,
+    MachineExternal = 11,
+}
+
+impl Interrupt {
+    /// All interrupts from the highest priority to the lowest.
+    pub const PRIORITY: [Interrupt; 6] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+        Interrupt::SupervisorExternal,
+        Interrupt::SupervisorSoftware,
+        Interrupt::SupervisorTimer,
+    ];
+
+    /// Mask of the bit in `mip`/`mie` corresponding to the interrupt.
+    pub const fn mask(self) -> u32 {
+        1 << (self as u32)
+    }
+}
+
+/// State captured at the moment an exception is raised.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TrapFrame {
+    pub cause: Exception,
+    /// Address of the instruction which raised the exception.
+    pub pc: u32,
+    /// Exception specific information, as `mtval` would hold.
+    /// This is the instruction word for `IllegalInstruction`, the fetch address for
+    /// `InstructionAccessFault` and `Breakpoint`, the accessed address for misaligned
+    /// and faulting loads and stores, and zero otherwise.
+    pub mtval: u32,
+    /// The instruction at `pc`, if it could be fetched and decoded.
+    pub instruction: Option<Instruction>,
+    /// Operands of the access if the instruction is a load or store.
+    pub access: Option<MemoryAccess>,
+}
+
+/// Operands of a load or store resolved at the moment it raised an exception.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    /// Index of the base register.
+    pub base: usize,
+    /// Value of the base register.
+    pub base_value: u32,
+    /// Sign-extended offset added to the base.
+    pub offset: i32,
+    /// Address computed from the base and the offset.
+    pub addr: u32,
 }
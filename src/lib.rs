@@ -1,12 +1,31 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "asm")]
+pub mod asm;
+pub mod clock;
+pub mod csr;
 pub mod decode;
+pub mod device;
+pub mod encode;
 pub mod exception;
 pub mod memory;
 pub mod processor;
+pub mod register;
+pub mod state;
+pub mod stats;
+pub mod system;
 
 #[cfg(test)]
 mod tests {
-    use crate::memory::{Memory, VectorMemory};
-    use crate::processor::Processor;
+    use crate::encode::*;
+    use crate::exception::Exception;
+    use crate::memory::{Addr, Memory, VectorMemory};
+    use crate::processor::{Processor, StopReason};
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
 
     #[test]
     fn register_caluculation() {
@@ -30,4 +49,112 @@ mod tests {
         assert_eq!(15, processor.regs[15]);
         assert_eq!(12, processor.regs[16]);
     }
+
+    #[test]
+    fn run_program() {
+        let (processor, reason) = Processor::run_program(
+            vec![0x00178793, 0x00278793, 0x00380813, 0x00281813, 0x010787b3],
+            4,
+        );
+        // Execution runs off the end of the memory.
+        assert_eq!(
+            reason,
+            StopReason::Exception(Exception::InstructionAccessFault)
+        );
+        assert_eq!(15, processor.regs[15]);
+        assert_eq!(12, processor.regs[16]);
+    }
+
+    #[test]
+    fn factorial() {
+        let program = vec![
+            addi(10, 0, 5),
+            addi(11, 0, 1),
+            // Multiply x11 by x10 with repeated addition.
+            beq(10, 0, 36),
+            addi(12, 0, 0),
+            addi(13, 10, 0),
+            add(12, 12, 11),
+            addi(13, 13, -1),
+            bne(13, 0, -8),
+            addi(11, 12, 0),
+            addi(10, 10, -1),
+            beq(0, 0, -32),
+            ebreak(),
+        ];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(program.len() * 4));
+        let mut processor = Processor::new(memory);
+        processor.load(0, program);
+        assert_eq!(
+            processor.execute_with_limit(1000),
+            StopReason::Breakpoint(44)
+        );
+        assert_eq!(processor.regs[10], 0);
+        assert_eq!(processor.regs[11], 120);
+    }
+
+    #[test]
+    fn memcpy() {
+        let program = vec![
+            addi(10, 0, 0x200),
+            addi(11, 0, 0x100),
+            addi(12, 0, 16),
+            beq(12, 0, 28),
+            lb(13, 11, 0),
+            sb(10, 13, 0),
+            addi(10, 10, 1),
+            addi(11, 11, 1),
+            addi(12, 12, -1),
+            beq(0, 0, -24),
+            ebreak(),
+        ];
+        let data: Vec<u8> = (0..16).map(|i| 0xf0 - i * 7).collect();
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x300));
+        let mut processor = Processor::new(memory);
+        processor.load(0, program);
+        processor.load_binary(0x100, &data);
+        assert_eq!(
+            processor.execute_with_limit(1000),
+            StopReason::Breakpoint(40)
+        );
+        let copied: Vec<u8> = (0x200..0x210)
+            .map(|addr| processor.mem.read_byte(Addr(addr)))
+            .collect();
+        assert_eq!(copied, data);
+        assert_eq!(processor.mem.read_byte(Addr(0x210)), 0);
+        assert_eq!(processor.regs[10], 0x210);
+    }
+
+    #[test]
+    fn call_sum() {
+        let program = vec![
+            // Sum the 4 words at 0x1010.
+            lui(10, 0x1),
+            addi(10, 10, 0x10),
+            addi(11, 0, 4),
+            jal(1, 12),
+            addi(10, 10, 1),
+            ebreak(),
+            // sum(a0 = address, a1 = count) -> a0
+            addi(12, 0, 0),
+            beq(11, 0, 24),
+            lw(13, 10, 0),
+            add(12, 12, 13),
+            addi(10, 10, 4),
+            addi(11, 11, -1),
+            beq(0, 0, -20),
+            addi(10, 12, 0),
+            jalr(0, 1, 0),
+        ];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x1100));
+        let mut processor = Processor::new(memory);
+        processor.load(0, program);
+        processor.load(0x1010, vec![1, 2, 3, 4]);
+        assert_eq!(
+            processor.execute_with_limit(1000),
+            StopReason::Breakpoint(20)
+        );
+        assert_eq!(processor.regs[10], 11);
+        assert_eq!(processor.regs[1], 16);
+    }
 }
@@ -1,65 +1,324 @@
+use crate::exception::Exception;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::convert::TryInto;
+
+// Granularity of zero-page compression in snapshots.
+const SNAPSHOT_PAGE_SIZE: usize = 4096;
+
+/// Address in the 32bit address space of the processor.
+/// Offsets wrap around instead of turning into a huge index.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Addr(pub u32);
+
+impl Addr {
+    /// Add a signed `offset`, wrapping around the address space.
+    pub const fn offset(self, offset: i32) -> Self {
+        Self(self.0.wrapping_add(offset as u32))
+    }
+
+    /// Index of the byte at this address in a memory.
+    pub const fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+impl From<u32> for Addr {
+    fn from(addr: u32) -> Self {
+        Self(addr)
+    }
+}
+
+impl From<Addr> for u32 {
+    fn from(addr: Addr) -> Self {
+        addr.0
+    }
+}
+
+impl From<Addr> for usize {
+    fn from(addr: Addr) -> Self {
+        addr.index()
+    }
+}
+
 pub trait Memory {
     /// Read an instruction located at *addr*
-    fn read_inst(&self, addr: usize) -> u32;
+    fn read_inst(&self, addr: Addr) -> u32;
+
+    /// Read an instruction located at *addr*, or raise an instruction access fault if
+    /// it does not fit in the memory.
+    fn try_read_inst(&self, addr: Addr) -> Result<u32, Exception> {
+        match addr.index().checked_add(4) {
+            Some(end) if end <= self.len() => Ok(self.read_inst(addr)),
+            _ => Err(Exception::InstructionAccessFault),
+        }
+    }
 
     /// Read byte located at *addr*
-    fn read_byte(&self, addr: usize) -> u8;
+    fn read_byte(&self, addr: Addr) -> u8;
 
     /// Read half word located at *addr*
-    fn read_halfword(&self, addr: usize) -> u16;
+    fn read_halfword(&self, addr: Addr) -> u16;
 
     /// Read word located at *addr*
-    fn read_word(&self, addr: usize) -> u32;
+    fn read_word(&self, addr: Addr) -> u32;
 
     /// Write an instruction located at *addr*
-    fn write_inst(&mut self, addr: usize, data: u32);
+    fn write_inst(&mut self, addr: Addr, data: u32);
 
     /// Write halfword at *addr*
-    fn write_byte(&mut self, addr: usize, data: u8);
+    fn write_byte(&mut self, addr: Addr, data: u8);
 
     /// Write halfword at *addr*
-    fn write_halfword(&mut self, addr: usize, data: u16);
+    fn write_halfword(&mut self, addr: Addr, data: u16);
 
     /// Write word at *addr*
-    fn write_word(&mut self, addr: usize, data: u32);
+    fn write_word(&mut self, addr: Addr, data: u32);
 
     /// Get memory size in byte.
     fn len(&self) -> usize;
+
+    /// Whether the memory accepts writes. Stores to a memory which does not accept
+    /// writes raise `StoreAccessFault`.
+    fn is_writable(&self) -> bool {
+        true
+    }
+
+    /// Whether the memory has no byte at all.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Compact copy of the whole memory which `VectorMemory::from_bytes` restores.
+    fn snapshot_bytes(&self) -> Vec<u8> {
+        let bytes: Vec<u8> = (0..self.len())
+            .map(|a| self.read_byte(Addr(a as u32)))
+            .collect();
+        compress_snapshot(&bytes)
+    }
+
+    /// Set `len` bytes from `addr` to `value`, e.g. to clear BSS.
+    fn fill(&mut self, addr: Addr, len: usize, value: u8) {
+        for i in 0..len {
+            self.write_byte(Addr(addr.0.wrapping_add(i as u32)), value);
+        }
+    }
+}
+
+/// Kinds of accesses allowed to a memory region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Permissions {
+    pub const RWX: Self = Self::new(true, true, true);
+    pub const RW: Self = Self::new(true, true, false);
+    pub const RX: Self = Self::new(true, false, true);
+    pub const R: Self = Self::new(true, false, false);
+
+    pub const fn new(read: bool, write: bool, execute: bool) -> Self {
+        Self {
+            read,
+            write,
+            execute,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct EmptyMemory;
 
 impl Memory for EmptyMemory {
-    fn read_inst(&self, _addr: usize) -> u32 {
+    fn read_inst(&self, _addr: Addr) -> u32 {
         0
     }
 
-    fn read_byte(&self, _addr: usize) -> u8 {
+    fn read_byte(&self, _addr: Addr) -> u8 {
         0
     }
 
-    fn read_halfword(&self, _addr: usize) -> u16 {
+    fn read_halfword(&self, _addr: Addr) -> u16 {
         0
     }
 
-    fn read_word(&self, _addr: usize) -> u32 {
+    fn read_word(&self, _addr: Addr) -> u32 {
         0
     }
 
-    fn write_inst(&mut self, _addr: usize, _data: u32) {}
+    fn write_inst(&mut self, _addr: Addr, _data: u32) {}
 
-    fn write_byte(&mut self, _addr: usize, _data: u8) {}
+    fn write_byte(&mut self, _addr: Addr, _data: u8) {}
 
-    fn write_halfword(&mut self, _addr: usize, _data: u16) {}
+    fn write_halfword(&mut self, _addr: Addr, _data: u16) {}
 
-    fn write_word(&mut self, _addr: usize, _data: u32) {}
+    fn write_word(&mut self, _addr: Addr, _data: u32) {}
 
     fn len(&self) -> usize {
         0
     }
 }
 
+/// Format `len` bytes from `addr` like `xxd`: 16 bytes per line with an ASCII column.
+/// Bytes beyond the end of the memory are not shown.
+pub fn hex_dump<M: Memory + ?Sized>(memory: &M, addr: Addr, len: usize) -> String {
+    let addr = addr.index();
+    let end = addr.saturating_add(len).min(memory.len());
+    let mut dump = String::new();
+    for line in (addr..end).step_by(16) {
+        let bytes: Vec<u8> = (line..end.min(line + 16))
+            .map(|a| memory.read_byte(Addr(a as u32)))
+            .collect();
+        let mut hex = String::new();
+        for (i, byte) in bytes.iter().enumerate() {
+            if i != 0 && i % 2 == 0 {
+                hex.push(' ');
+            }
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!("{:08x}: {:<40} {}\n", line, hex, ascii));
+    }
+    dump
+}
+
+// Snapshot format: the length as u64 LE, followed by a tag for each page, which is 0 for
+// a page filled with zero, or 1 followed by the bytes of the page.
+fn compress_snapshot(bytes: &[u8]) -> Vec<u8> {
+    let mut snapshot = (bytes.len() as u64).to_le_bytes().to_vec();
+    for page in bytes.chunks(SNAPSHOT_PAGE_SIZE) {
+        if page.iter().all(|&b| b == 0) {
+            snapshot.push(0);
+        } else {
+            snapshot.push(1);
+            snapshot.extend_from_slice(page);
+        }
+    }
+    snapshot
+}
+
+// Length of the memory a snapshot restores, or `None` if the header is missing or claims
+// more bytes than the pages following it can hold. A zero page takes 1 byte, so this
+// bounds the allocation by the size of the input.
+fn snapshot_len(snapshot: &[u8]) -> Option<usize> {
+    let len = u64::from_le_bytes(snapshot.get(..8)?.try_into().ok()?);
+    let max_len = (snapshot.len() as u64 - 8).checked_mul(SNAPSHOT_PAGE_SIZE as u64)?;
+    if len > max_len {
+        return None;
+    }
+    len.try_into().ok()
+}
+
+// Restore the bytes of a snapshot into `bytes`, which must be `snapshot_len` bytes long
+// and filled with zero.
+fn decompress_snapshot_into(snapshot: &[u8], bytes: &mut [u8]) -> Option<()> {
+    let mut rest = &snapshot[8..];
+    for page in bytes.chunks_mut(SNAPSHOT_PAGE_SIZE) {
+        match rest.first()? {
+            0 => rest = &rest[1..],
+            1 => {
+                page.copy_from_slice(rest.get(1..1 + page.len())?);
+                rest = &rest[1 + page.len()..];
+            }
+            _ => return None,
+        }
+    }
+    if !rest.is_empty() {
+        return None;
+    }
+    Some(())
+}
+
+fn decompress_snapshot(snapshot: &[u8]) -> Option<Vec<u8>> {
+    let len = snapshot_len(snapshot)?;
+    let mut bytes = Vec::new();
+    bytes.try_reserve_exact(len).ok()?;
+    bytes.resize(len, 0);
+    decompress_snapshot_into(snapshot, &mut bytes)?;
+    Some(bytes)
+}
+
+/// Memory shared by multiple processors.
+/// Cloning this makes another handle to the same memory.
+#[derive(Clone)]
+pub struct SharedMemory {
+    memory: Rc<RefCell<Box<dyn Memory>>>,
+}
+
+impl SharedMemory {
+    pub fn new(memory: Box<dyn Memory>) -> Self {
+        Self {
+            memory: Rc::new(RefCell::new(memory)),
+        }
+    }
+}
+
+impl Memory for SharedMemory {
+    fn read_inst(&self, addr: Addr) -> u32 {
+        self.memory.borrow().read_inst(addr)
+    }
+
+    fn read_byte(&self, addr: Addr) -> u8 {
+        self.memory.borrow().read_byte(addr)
+    }
+
+    fn read_halfword(&self, addr: Addr) -> u16 {
+        self.memory.borrow().read_halfword(addr)
+    }
+
+    fn read_word(&self, addr: Addr) -> u32 {
+        self.memory.borrow().read_word(addr)
+    }
+
+    fn write_inst(&mut self, addr: Addr, data: u32) {
+        self.memory.borrow_mut().write_inst(addr, data);
+    }
+
+    fn write_byte(&mut self, addr: Addr, data: u8) {
+        self.memory.borrow_mut().write_byte(addr, data);
+    }
+
+    fn write_halfword(&mut self, addr: Addr, data: u16) {
+        self.memory.borrow_mut().write_halfword(addr, data);
+    }
+
+    fn write_word(&mut self, addr: Addr, data: u32) {
+        self.memory.borrow_mut().write_word(addr, data);
+    }
+
+    fn len(&self) -> usize {
+        self.memory.borrow().len()
+    }
+
+    fn is_writable(&self) -> bool {
+        self.memory.borrow().is_writable()
+    }
+
+    fn snapshot_bytes(&self) -> Vec<u8> {
+        self.memory.borrow().snapshot_bytes()
+    }
+
+    fn fill(&mut self, addr: Addr, len: usize, value: u8) {
+        self.memory.borrow_mut().fill(addr, len, value);
+    }
+}
+
 #[derive(Debug)]
 pub struct VectorMemory {
     memory: Vec<u8>,
@@ -67,12 +326,22 @@ pub struct VectorMemory {
 
 impl VectorMemory {
     pub fn new(size: usize) -> Self {
-        let mut memory = Vec::with_capacity(size);
-        memory.resize(size, 0);
+        let memory = vec![0; size];
 
         Self { memory }
     }
 
+    /// Dump the memory into a compact form where pages filled with zero take 1 byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        compress_snapshot(&self.memory)
+    }
+
+    /// Restore a memory dumped by `to_bytes` or `Memory::snapshot_bytes`.
+    /// Returns `None` if `bytes` is malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        decompress_snapshot(bytes).map(Self::from)
+    }
+
     /// read little-endian byte located at *addr*
     fn read_lb(&self, addr: usize) -> u8 {
         self.memory[addr]
@@ -83,14 +352,6 @@ impl VectorMemory {
         (self.memory[addr] as u16) | (self.memory[addr + 1] as u16) << 8
     }
 
-    /// read big-endian word located at *addr*
-    fn read_bw(&self, addr: usize) -> u32 {
-        (self.memory[addr] as u32) << 24
-            | (self.memory[addr + 1] as u32) << 16
-            | (self.memory[addr + 2] as u32) << 8
-            | (self.memory[addr + 3] as u32)
-    }
-
     /// read little-endian word located at *addr*
     fn read_lw(&self, addr: usize) -> u32 {
         (self.memory[addr] as u32)
@@ -110,14 +371,6 @@ impl VectorMemory {
         self.memory[addr + 1] = (val >> 8) as u8;
     }
 
-    /// write big-endian word at *addr*
-    fn write_bw(&mut self, addr: usize, val: u32) {
-        self.memory[addr] = (val >> 24) as u8;
-        self.memory[addr + 1] = (val >> 16) as u8;
-        self.memory[addr + 2] = (val >> 8) as u8;
-        self.memory[addr + 3] = val as u8;
-    }
-
     /// write little-endian word at *addr*
     fn write_lw(&mut self, addr: usize, val: u32) {
         self.memory[addr] = val as u8;
@@ -126,51 +379,58 @@ impl VectorMemory {
         self.memory[addr + 3] = (val >> 24) as u8;
     }
 
-    /// read an instruction located at addr
-    /// This impl stores instructions as big-endian value
-    /// but, we don't know whether it's popular...
-    pub fn write_inst(&mut self, addr: usize, inst: u32) {
-        self.write_bw(addr, inst);
+    /// Write an instruction located at *addr*.
+    /// Instructions are stored as little-endian values, as RISC-V specifies.
+    pub fn write_inst(&mut self, addr: Addr, inst: u32) {
+        self.write_lw(addr.index(), inst);
     }
 }
 
 impl Memory for VectorMemory {
-    fn read_inst(&self, addr: usize) -> u32 {
-        self.read_bw(addr)
+    fn read_inst(&self, addr: Addr) -> u32 {
+        self.read_lw(addr.index())
     }
 
-    fn read_byte(&self, addr: usize) -> u8 {
-        self.read_lb(addr)
+    fn read_byte(&self, addr: Addr) -> u8 {
+        self.read_lb(addr.index())
     }
 
-    fn read_halfword(&self, addr: usize) -> u16 {
-        self.read_lh(addr)
+    fn read_halfword(&self, addr: Addr) -> u16 {
+        self.read_lh(addr.index())
     }
 
-    fn read_word(&self, addr: usize) -> u32 {
-        self.read_lw(addr)
+    fn read_word(&self, addr: Addr) -> u32 {
+        self.read_lw(addr.index())
     }
 
     /// write word at *addr*
-    fn write_inst(&mut self, addr: usize, data: u32) {
-        self.write_bw(addr, data);
+    fn write_inst(&mut self, addr: Addr, data: u32) {
+        self.write_lw(addr.index(), data);
     }
 
-    fn write_byte(&mut self, addr: usize, data: u8) {
-        self.write_lb(addr, data);
+    fn write_byte(&mut self, addr: Addr, data: u8) {
+        self.write_lb(addr.index(), data);
     }
 
-    fn write_halfword(&mut self, addr: usize, data: u16) {
-        self.write_lh(addr, data);
+    fn write_halfword(&mut self, addr: Addr, data: u16) {
+        self.write_lh(addr.index(), data);
     }
 
-    fn write_word(&mut self, addr: usize, data: u32) {
-        self.write_lw(addr, data);
+    fn write_word(&mut self, addr: Addr, data: u32) {
+        self.write_lw(addr.index(), data);
     }
 
     fn len(&self) -> usize {
         self.memory.len()
     }
+
+    fn snapshot_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn fill(&mut self, addr: Addr, len: usize, value: u8) {
+        self.memory[addr.index()..addr.index() + len].fill(value);
+    }
 }
 
 impl From<Vec<u8>> for VectorMemory {
@@ -179,62 +439,367 @@ impl From<Vec<u8>> for VectorMemory {
     }
 }
 
+/// Memory backed by an anonymous memory map. The OS commits pages on first touch, so a
+/// huge memory of which only a small part is used stays cheap.
+#[cfg(feature = "mmap")]
+pub struct MmapMemory {
+    memory: memmap2::MmapMut,
+}
+
+#[cfg(feature = "mmap")]
+impl MmapMemory {
+    /// Map `size` bytes of zeroed memory. Panics if the OS refuses the mapping.
+    pub fn new(size: usize) -> Self {
+        let memory = memmap2::MmapMut::map_anon(size).expect("failed to map anonymous memory");
+        Self { memory }
+    }
+
+    /// Dump the memory into a compact form where pages filled with zero take 1 byte.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        compress_snapshot(&self.memory)
+    }
+
+    /// Restore a memory dumped by `to_bytes` or `Memory::snapshot_bytes`.
+    /// Returns `None` if `bytes` is malformed.
+    /// Pages are decompressed straight into the mapping, which starts zeroed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let len = snapshot_len(bytes)?;
+        let mut memory = memmap2::MmapMut::map_anon(len).ok()?;
+        decompress_snapshot_into(bytes, &mut memory)?;
+        Some(Self { memory })
+    }
+
+    /// Write an instruction located at *addr*.
+    /// Instructions are stored as little-endian values, as RISC-V specifies.
+    pub fn write_inst(&mut self, addr: Addr, inst: u32) {
+        self.write_word(addr, inst);
+    }
+
+    fn bytes<const N: usize>(&self, addr: Addr) -> [u8; N] {
+        self.memory[addr.index()..addr.index() + N]
+            .try_into()
+            .unwrap()
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl Memory for MmapMemory {
+    fn read_inst(&self, addr: Addr) -> u32 {
+        self.read_word(addr)
+    }
+
+    fn read_byte(&self, addr: Addr) -> u8 {
+        self.memory[addr.index()]
+    }
+
+    fn read_halfword(&self, addr: Addr) -> u16 {
+        u16::from_le_bytes(self.bytes(addr))
+    }
+
+    fn read_word(&self, addr: Addr) -> u32 {
+        u32::from_le_bytes(self.bytes(addr))
+    }
+
+    fn write_inst(&mut self, addr: Addr, data: u32) {
+        self.write_word(addr, data);
+    }
+
+    fn write_byte(&mut self, addr: Addr, data: u8) {
+        self.memory[addr.index()] = data;
+    }
+
+    fn write_halfword(&mut self, addr: Addr, data: u16) {
+        self.memory[addr.index()..addr.index() + 2].copy_from_slice(&data.to_le_bytes());
+    }
+
+    fn write_word(&mut self, addr: Addr, data: u32) {
+        self.memory[addr.index()..addr.index() + 4].copy_from_slice(&data.to_le_bytes());
+    }
+
+    fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    fn snapshot_bytes(&self) -> Vec<u8> {
+        self.to_bytes()
+    }
+
+    fn fill(&mut self, addr: Addr, len: usize, value: u8) {
+        self.memory[addr.index()..addr.index() + len].fill(value);
+    }
+}
+
+/// Read-only memory over borrowed bytes, to run code without copying it.
+/// A processor needs a `'static` memory, so `'a` is usually `'static` there.
+#[derive(Debug)]
+pub struct SliceMemory<'a>(pub &'a [u8]);
+
+impl Memory for SliceMemory<'_> {
+    fn read_inst(&self, addr: Addr) -> u32 {
+        self.read_word(addr)
+    }
+
+    fn read_byte(&self, addr: Addr) -> u8 {
+        self.0[addr.index()]
+    }
+
+    fn read_halfword(&self, addr: Addr) -> u16 {
+        let a = addr.index();
+        u16::from_le_bytes([self.0[a], self.0[a + 1]])
+    }
+
+    fn read_word(&self, addr: Addr) -> u32 {
+        let a = addr.index();
+        u32::from_le_bytes([self.0[a], self.0[a + 1], self.0[a + 2], self.0[a + 3]])
+    }
+
+    fn write_inst(&mut self, _addr: Addr, _data: u32) {}
+
+    fn write_byte(&mut self, _addr: Addr, _data: u8) {}
+
+    fn write_halfword(&mut self, _addr: Addr, _data: u16) {}
+
+    fn write_word(&mut self, _addr: Addr, _data: u32) {}
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn is_writable(&self) -> bool {
+        false
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn addr() {
+        assert_eq!(Addr(0x100).offset(-4), Addr(0xfc));
+        assert_eq!(Addr(4).offset(-8), Addr(0xfffffffc));
+        assert_eq!(Addr(0xfffffffc).offset(8), Addr(4));
+        assert_eq!(Addr::from(0x80u32).index(), 0x80);
+        assert_eq!(u32::from(Addr(7)), 7);
+        assert_eq!(usize::from(Addr(0xffffffff)), 0xffffffff);
+    }
+
     #[test]
     fn empty_memory() {
         let mut mem = EmptyMemory;
 
-        assert_eq!(mem.read_word(0), 0);
-        assert_eq!(mem.read_word(4), 0);
-        assert_eq!(mem.read_word(8), 0);
-        assert_eq!(mem.read_word(12), 0);
+        assert_eq!(mem.read_word(Addr(0)), 0);
+        assert_eq!(mem.read_word(Addr(4)), 0);
+        assert_eq!(mem.read_word(Addr(8)), 0);
+        assert_eq!(mem.read_word(Addr(12)), 0);
+
+        mem.write_word(Addr(0), 0x12345678);
+        mem.write_word(Addr(4), 0x90abcdef);
+        mem.write_word(Addr(8), 0xdeadbeef);
+        mem.write_word(Addr(12), 0xabadbabe);
+
+        assert_eq!(mem.read_word(Addr(0)), 0);
+        assert_eq!(mem.read_word(Addr(4)), 0);
+        assert_eq!(mem.read_word(Addr(8)), 0);
+        assert_eq!(mem.read_word(Addr(12)), 0);
+    }
+
+    #[test]
+    fn try_read_inst() {
+        let mut mem = VectorMemory::new(8);
+        mem.write_inst(Addr(4), 0x00000013);
+        assert_eq!(mem.try_read_inst(Addr(4)), Ok(0x00000013));
+        assert_eq!(
+            mem.try_read_inst(Addr(6)),
+            Err(Exception::InstructionAccessFault)
+        );
+        assert_eq!(
+            mem.try_read_inst(Addr(8)),
+            Err(Exception::InstructionAccessFault)
+        );
+        assert_eq!(
+            mem.try_read_inst(Addr(0xfffffffe)),
+            Err(Exception::InstructionAccessFault)
+        );
+    }
+
+    #[test]
+    fn is_empty() {
+        assert!(EmptyMemory.is_empty());
+        assert!(VectorMemory::new(0).is_empty());
+        assert!(!VectorMemory::new(4).is_empty());
+    }
+
+    #[test]
+    fn dump() {
+        let mut bytes = b"Hello, world!\n".to_vec();
+        bytes.resize(32, 0);
+        bytes[16..20].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        let mem = VectorMemory::from(bytes);
+        assert_eq!(
+            hex_dump(&mem, Addr(0), 32),
+            "00000000: 4865 6c6c 6f2c 2077 6f72 6c64 210a 0000  Hello, world!...\n\
+             00000010: dead beef 0000 0000 0000 0000 0000 0000  ................\n"
+        );
+
+        // Partial line and range beyond the end.
+        assert_eq!(
+            hex_dump(&mem, Addr(28), 8),
+            "0000001c: 0000 0000                                ....\n"
+        );
+    }
+
+    #[test]
+    fn fill() {
+        let mut mem = VectorMemory::new(128);
+        mem.fill(Addr(32), 64, 0xaa);
+        assert_eq!(mem.read_byte(Addr(31)), 0);
+        assert!((32..96).all(|addr| mem.read_byte(Addr(addr)) == 0xaa));
+        assert_eq!(mem.read_word(Addr(92)), 0xaaaaaaaa);
+        assert_eq!(mem.read_byte(Addr(96)), 0);
+
+        let mut shared = SharedMemory::new(Box::new(mem));
+        shared.fill(Addr(0), 4, 0x55);
+        assert_eq!(shared.read_word(Addr(0)), 0x55555555);
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut mem = VectorMemory::new(5 * SNAPSHOT_PAGE_SIZE + 100);
+        mem.write_word(Addr(0x10), 0xdeadbeef);
+        mem.fill(Addr(0x3000), 16, 0xaa);
+        mem.write_byte(Addr(0x5060), 0x55);
+
+        let bytes = mem.to_bytes();
+        // Header, 3 zero pages, 2 full pages with data and the last partial page.
+        assert_eq!(
+            bytes.len(),
+            8 + 3 + 2 * (1 + SNAPSHOT_PAGE_SIZE) + (1 + 100)
+        );
+        let restored = VectorMemory::from_bytes(&bytes).unwrap();
+        assert_eq!(restored.memory, mem.memory);
+
+        let shared = SharedMemory::new(Box::new(mem));
+        assert_eq!(shared.snapshot_bytes(), bytes);
+        assert!(VectorMemory::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert_eq!(
+            VectorMemory::from_bytes(&EmptyMemory.snapshot_bytes()).map(|m| m.len()),
+            Some(0)
+        );
+
+        // Headers claiming more than the pages could hold, down to a truncated header.
+        assert!(VectorMemory::from_bytes(&[0xff; 8]).is_none());
+        assert!(VectorMemory::from_bytes(&[0xff; 9]).is_none());
+        let mut huge = (1u64 << 40).to_le_bytes().to_vec();
+        huge.push(0);
+        assert!(VectorMemory::from_bytes(&huge).is_none());
+        let mut two_pages = ((SNAPSHOT_PAGE_SIZE + 1) as u64).to_le_bytes().to_vec();
+        two_pages.push(0);
+        assert!(VectorMemory::from_bytes(&two_pages).is_none());
+        two_pages.push(0);
+        assert_eq!(
+            VectorMemory::from_bytes(&two_pages).map(|m| m.len()),
+            Some(SNAPSHOT_PAGE_SIZE + 1)
+        );
+        assert!(VectorMemory::from_bytes(&[0; 7]).is_none());
+        assert!(VectorMemory::from_bytes(&[2, 0, 0, 0, 0, 0, 0, 0, 2]).is_none());
+    }
 
-        mem.write_word(0, 0x12345678);
-        mem.write_word(4, 0x90abcdef);
-        mem.write_word(8, 0xdeadbeef);
-        mem.write_word(12, 0xabadbabe);
+    #[test]
+    fn slice_memory() {
+        let bytes = [0x78, 0x56, 0x34, 0x12];
+        let mut mem = SliceMemory(&bytes);
+        assert_eq!(mem.read_word(Addr(0)), 0x12345678);
+        assert_eq!(mem.read_halfword(Addr(2)), 0x1234);
+        mem.write_byte(Addr(0), 0);
+        assert_eq!(mem.read_byte(Addr(0)), 0x78);
+        assert!(!mem.is_writable());
+    }
+
+    #[test]
+    fn shared_memory() {
+        let mut mem1 = SharedMemory::new(Box::new(VectorMemory::new(8)));
+        let mut mem2 = mem1.clone();
+
+        mem1.write_word(Addr(0), 0x12345678);
+        assert_eq!(mem2.read_word(Addr(0)), 0x12345678);
+        mem2.write_byte(Addr(4), 0xff);
+        assert_eq!(mem1.read_byte(Addr(4)), 0xff);
+        assert_eq!(mem1.len(), 8);
+    }
 
-        assert_eq!(mem.read_word(0), 0);
-        assert_eq!(mem.read_word(4), 0);
-        assert_eq!(mem.read_word(8), 0);
-        assert_eq!(mem.read_word(12), 0);
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn mmap_memory() {
+        // 512MiB, most of which is never touched.
+        let mut mem = MmapMemory::new(512 << 20);
+        let high = Addr((512 << 20) - 8);
+        assert_eq!(mem.read_word(high), 0);
+        mem.write_word(high, 0x12345678);
+        mem.write_halfword(high.offset(4), 0xbeef);
+        assert_eq!(mem.read_word(high), 0x12345678);
+        assert_eq!(mem.read_byte(high), 0x78);
+        assert_eq!(mem.read_halfword(high.offset(4)), 0xbeef);
+
+        let mut small = MmapMemory::new(0x2000);
+        small.write_word(Addr(0x1ffc), 0xdeadbeef);
+        let restored = MmapMemory::from_bytes(&small.to_bytes()).unwrap();
+        assert_eq!(restored.read_word(Addr(0x1ffc)), 0xdeadbeef);
+        assert_eq!(restored.read_word(Addr(0)), 0);
+        assert!(MmapMemory::from_bytes(&[0xff; 8]).is_none());
+    }
+
+    // Sub-word stores must leave the other bytes of the word intact.
+    fn check_sub_word_writes(mem: &mut dyn Memory) {
+        mem.write_word(Addr(4), 0x12345678);
+        mem.write_byte(Addr(6), 0xab);
+        assert_eq!(mem.read_word(Addr(4)), 0x12ab5678);
+        mem.write_halfword(Addr(5), 0xcdef);
+        assert_eq!(mem.read_word(Addr(4)), 0x12cdef78);
+        assert_eq!(mem.read_word(Addr(0)), 0);
+        assert_eq!(mem.read_word(Addr(8)), 0);
+    }
+
+    #[test]
+    fn sub_word_writes() {
+        check_sub_word_writes(&mut VectorMemory::new(12));
+        check_sub_word_writes(&mut SharedMemory::new(Box::new(VectorMemory::new(12))));
+        #[cfg(feature = "mmap")]
+        check_sub_word_writes(&mut MmapMemory::new(12));
     }
 
     #[test]
     fn vector_memory() {
         let mut mem = VectorMemory::new(16);
 
-        assert_eq!(mem.read_word(0), 0);
-        assert_eq!(mem.read_word(4), 0);
-        assert_eq!(mem.read_word(8), 0);
-        assert_eq!(mem.read_word(12), 0);
-
-        mem.write_byte(4, 0x78);
-        mem.write_byte(5, 0x56);
-        mem.write_byte(6, 0x34);
-        mem.write_byte(7, 0x12);
-        assert_eq!(mem.read_byte(4), 0x78);
-        assert_eq!(mem.read_byte(5), 0x56);
-        assert_eq!(mem.read_byte(6), 0x34);
-        assert_eq!(mem.read_byte(7), 0x12);
-        assert_eq!(mem.read_word(4), 0x12345678);
-
-        mem.write_halfword(8, 0x5678);
-        mem.write_halfword(10, 0x1234);
-        assert_eq!(mem.read_halfword(8), 0x5678);
-        assert_eq!(mem.read_halfword(10), 0x1234);
-        assert_eq!(mem.read_word(8), 0x12345678);
-
-        mem.write_word(0, 0x12345678);
-        mem.write_word(4, 0x90abcdef);
-        mem.write_word(8, 0xdeadbeef);
-        mem.write_word(12, 0xabadbabe);
-        assert_eq!(mem.read_word(0), 0x12345678);
-        assert_eq!(mem.read_word(4), 0x90abcdef);
-        assert_eq!(mem.read_word(8), 0xdeadbeef);
-        assert_eq!(mem.read_word(12), 0xabadbabe);
+        assert_eq!(mem.read_word(Addr(0)), 0);
+        assert_eq!(mem.read_word(Addr(4)), 0);
+        assert_eq!(mem.read_word(Addr(8)), 0);
+        assert_eq!(mem.read_word(Addr(12)), 0);
+
+        mem.write_byte(Addr(4), 0x78);
+        mem.write_byte(Addr(5), 0x56);
+        mem.write_byte(Addr(6), 0x34);
+        mem.write_byte(Addr(7), 0x12);
+        assert_eq!(mem.read_byte(Addr(4)), 0x78);
+        assert_eq!(mem.read_byte(Addr(5)), 0x56);
+        assert_eq!(mem.read_byte(Addr(6)), 0x34);
+        assert_eq!(mem.read_byte(Addr(7)), 0x12);
+        assert_eq!(mem.read_word(Addr(4)), 0x12345678);
+
+        mem.write_halfword(Addr(8), 0x5678);
+        mem.write_halfword(Addr(10), 0x1234);
+        assert_eq!(mem.read_halfword(Addr(8)), 0x5678);
+        assert_eq!(mem.read_halfword(Addr(10)), 0x1234);
+        assert_eq!(mem.read_word(Addr(8)), 0x12345678);
+
+        mem.write_word(Addr(0), 0x12345678);
+        mem.write_word(Addr(4), 0x90abcdef);
+        mem.write_word(Addr(8), 0xdeadbeef);
+        mem.write_word(Addr(12), 0xabadbabe);
+        assert_eq!(mem.read_word(Addr(0)), 0x12345678);
+        assert_eq!(mem.read_word(Addr(4)), 0x90abcdef);
+        assert_eq!(mem.read_word(Addr(8)), 0xdeadbeef);
+        assert_eq!(mem.read_word(Addr(12)), 0xabadbabe);
     }
 }
@@ -1,1220 +1,5049 @@
-use crate::decode::{decode, BType, IType, Instruction, JType, RType, SType, UType};
-use crate::exception::Exception;
-use crate::memory::Memory;
+use crate::clock::{default_clock, Clock};
+use crate::csr::{
+    Csr, CYCLE, CYCLEH, INSTRET, INSTRETH, MCAUSE, MCYCLE, MCYCLEH, MEPC, MHARTID, MIE, MINSTRET,
+    MINSTRETH, MIP, MSTATUS, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MTVAL, MTVEC, SEED, TIME,
+    TIMEH,
+};
+use crate::decode::{
+    decode_with_extensions, BType, CustomInstruction, Extensions, IType, Instruction, JType, RType,
+    SType, UType,
+};
+use crate::device::MmioDevice;
+use crate::exception::{Exception, Interrupt, MemoryAccess, TrapFrame};
+use crate::memory::{hex_dump, Addr, Memory, Permissions, VectorMemory};
+use crate::register::RegisterFile;
+use crate::state::ProcessorState;
+use crate::stats::ExecStats;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::convert::TryFrom;
+use core::ops::Range;
+#[cfg(feature = "hash")]
+use sha2::{Digest, Sha256};
+#[cfg(feature = "std")]
+use std::io::Write;
+
+/// Interprets an instruction word which the built-in decoder regards as illegal.
+pub type CustomDecoder = Box<dyn Fn(u32) -> Option<CustomInstruction>>;
+
+/// Executes an instruction returned by `CustomDecoder`.
+pub type CustomExecutor<M = dyn Memory> =
+    Box<dyn FnMut(&mut Processor<M>, &CustomInstruction) -> Result<(), Exception>>;
+
+/// Gets the raw word of an instruction nothing could decode, and returns whether it
+/// handled the instruction. Declining lets `IllegalInstruction` be raised.
+pub type IllegalInstructionHook<M = dyn Memory> = Box<dyn FnMut(&mut Processor<M>, u32) -> bool>;
+
+/// Serves `ecall` on the host under `EcallPolicy::Semihost`, or semihosting `ebreak`s if
+/// installed with `Processor::set_semihosting_handler`, usually reading the operation
+/// number and arguments from registers. Returns the reason to stop if any.
+pub type SyscallHandler<M = dyn Memory> = Box<dyn FnMut(&mut Processor<M>) -> Option<StopReason>>;
+
+// Maximum number of instructions executed by `Processor::run_program`.
+const RUN_PROGRAM_LIMIT: usize = 1_000_000;
+
+// Index of the return address register.
+const RA: usize = 1;
+
+// Index of the stack pointer register.
+const SP: usize = 2;
+
+// `slli x0,x0,0x1f` and `srai x0,x0,7` around an `ebreak` mark a semihosting call.
+const SEMIHOST_ENTRY: u32 = 0x01f01013;
+const SEMIHOST_EXIT: u32 = 0x40705013;
+
+// An instruction word decoded by `Processor::decode`.
+enum Decoded {
+    Builtin(Instruction),
+    Custom(CustomInstruction),
+}
+
+/// Unsigned integer as wide as a register (XLEN bits).
+pub type Xlen = u32;
+
+/// Signed counterpart of `Xlen`.
+pub type SXlen = i32;
+
+/// Reason why `Processor::execute` stopped.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// An exception occured.
+    Exception(Exception),
+    /// A device requested to exit with the exit code.
+    Exit(u32),
+    /// `ebreak` at the address was executed.
+    Breakpoint(u32),
+    /// An all-zero word was fetched with `set_stop_on_zero` enabled.
+    Halted,
+    /// The number of instructions reached the given limit.
+    LimitReached,
+    /// `ecall` was executed under `EcallPolicy::Stop`.
+    Ecall,
+    /// sp or a store through it left the bounds set with `set_stack_bounds`.
+    StackOverflow,
+    /// `wfi` was executed with no interrupt pending, unless `set_wfi_as_nop` is enabled.
+    /// Execution resumes from the next instruction.
+    WaitingForInterrupt,
+}
+
+impl StopReason {
+    /// Process exit status for a run stopped for this reason. A clean halt is 0 and an
+    /// exit device passes its code through if it is below 100. Other guest codes become
+    /// 1, so that they neither collide with the codes below nor get truncated to 0.
+    /// Exceptions, including `ebreak` and `ecall`, map to `Exception::to_exit_code`.
+    /// Running out of instructions is 124 as `timeout` reports, a stack overflow 120 and
+    /// waiting for an interrupt 121.
+    pub const fn to_exit_code(&self) -> i32 {
+        match self {
+            StopReason::Halted => 0,
+            StopReason::Exit(code) if *code < 100 => *code as i32,
+            StopReason::Exit(_) => 1,
+            StopReason::Exception(exception) => exception.to_exit_code(),
+            StopReason::Breakpoint(_) => Exception::Breakpoint.to_exit_code(),
+            StopReason::Ecall => Exception::EnvironmentCallFromMMode.to_exit_code(),
+            StopReason::StackOverflow => 120,
+            StopReason::WaitingForInterrupt => 121,
+            StopReason::LimitReached => 124,
+        }
+    }
+}
+
+/// How `Processor::step` deals with `ecall`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum EcallPolicy {
+    /// Deliver the exception to the guest's trap handler at `mtvec`.
+    Trap,
+    /// Call the handler installed with `Processor::set_syscall_handler` and continue
+    /// from the next instruction.
+    Semihost,
+    /// Stop with `StopReason::Ecall`, leaving the pc at the `ecall`.
+    #[default]
+    Stop,
+}
+
+/// How loads and stores to addresses not aligned to their size are handled.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentPolicy {
+    /// Raise `LoadAddressMisaligned` or `StoreAddressMisaligned`.
+    Trap,
+    /// Perform the access as if it were split into bytes.
+    #[default]
+    Emulate,
+}
 
-pub struct Processor {
-    pub regs: [u32; 32],
+/// Architectural effects of a retired instruction.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RetireRecord {
     pub pc: u32,
-    pub mem: Box<dyn Memory>,
+    pub inst_word: u32,
+    /// Pairs of register index and written value, in the order of writes.
+    pub reg_writes: Vec<(usize, u32)>,
+    /// Triples of address, size in bytes and written value.
+    pub mem_writes: Vec<(usize, usize, u32)>,
+    /// Pairs of CSR address and written value.
+    pub csr_writes: Vec<(u16, u32)>,
+    /// pc of the next instruction.
+    pub new_pc: u32,
+}
+
+/// Gets the pc and the instruction whose computed result is discarded because it
+/// targets x0.
+pub type UselessWriteHook = Box<dyn FnMut(u32, &Instruction)>;
+
+/// Effects of a single instruction executed by `Processor::step_delta`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct StepDelta {
+    /// Executed instruction word.
+    pub inst: u32,
+    /// Register index and written value.
+    pub reg_write: Option<(usize, u32)>,
+    /// Address, size in bytes and written value.
+    pub mem_write: Option<(u32, u8, u32)>,
+    /// CSR address and written value.
+    pub csr_write: Option<(u16, u32)>,
+    pub pc_before: u32,
+    pub pc_after: u32,
+}
+
+/// Receives a record for each retired instruction.
+pub type RetireSink = Box<dyn FnMut(&RetireRecord)>;
+
+/// Provides the value of a load from the address it is mapped to.
+pub type ReadCallback = Box<dyn FnMut() -> u32>;
+
+/// Receives the value of a store to the address it is mapped to.
+pub type WriteCallback = Box<dyn FnMut(u32)>;
+
+/// RV32I hart. `M` is the type of the memory. The default `dyn Memory` accepts any
+/// memory, while a concrete type such as `VectorMemory` avoids a virtual call per access.
+pub struct Processor<M: Memory + ?Sized = dyn Memory> {
+    pub regs: RegisterFile,
+    pc: Xlen,
+    pub mem: Box<M>,
+    csr: Csr,
+    // If true, fetching an all-zero word stops execution instead of raising an exception.
+    stop_on_zero: bool,
+    // If true, an instruction word nothing can decode panics instead of raising an exception.
+    panic_on_unimplemented: bool,
+    // If true, an instruction word nothing can decode is skipped instead of raising an
+    // exception.
+    skip_illegal: bool,
+    // If true, `wfi` never stops execution.
+    wfi_as_nop: bool,
+    // Set when `wfi` is executed to stop execution.
+    waiting: bool,
+    // Delay in milliseconds after each instruction.
+    interval: u64,
+    clock: Box<dyn Clock>,
+    // Devices and their base addresses.
+    devices: Vec<(usize, Box<dyn MmioDevice>)>,
+    // Address ranges with restricted permissions. Later entries take precedence.
+    regions: Vec<(Range<usize>, Permissions)>,
+    // Address ranges and the extra cycles each access to them takes. Later entries take
+    // precedence.
+    latencies: Vec<(Range<usize>, u64)>,
+    // Callbacks and the addresses they are mapped to.
+    read_callbacks: Vec<(usize, ReadCallback)>,
+    write_callbacks: Vec<(usize, WriteCallback)>,
     // Used to determine if the pc should be incremented.
     has_jumped: bool,
+    // Set when the instruction executed by `step` retires.
+    retired: bool,
+    retire_sink: Option<RetireSink>,
+    // Destination of the human-readable log of retired instructions, if enabled.
+    #[cfg(feature = "std")]
+    trace_log: Option<Box<dyn Write>>,
+    // Effects of the instruction being executed, recorded only if `retire_sink` is set
+    // or `record_retire` is true.
+    retiring: Option<RetireRecord>,
+    // If true, the record is kept after retirement for `step_delta`.
+    record_retire: bool,
+    custom_decoder: Option<CustomDecoder>,
+    custom_executor: Option<CustomExecutor<M>>,
+    illegal_instruction_hook: Option<IllegalInstructionHook<M>>,
+    ecall_policy: EcallPolicy,
+    // Optional extensions the decoder accepts.
+    extensions: Extensions,
+    // If true, `ebreak` in the semihosting sequence calls `semihosting_handler`.
+    semihosting: bool,
+    semihosting_handler: Option<SyscallHandler<M>>,
+    alignment_policy: AlignmentPolicy,
+    syscall_handler: Option<SyscallHandler<M>>,
+    useless_write_hook: Option<UselessWriteHook>,
+    // Fetched words and their decoded instructions keyed by pc, if caching is enabled.
+    fetch_cache: Option<BTreeMap<Xlen, (u32, Instruction)>>,
+    stats: ExecStats,
+    // State of the PRNG behind `seed` CSR, if enabled.
+    entropy: Option<Cell<u64>>,
+    // Symbol names keyed by their addresses.
+    symbols: BTreeMap<Xlen, String>,
+    // Pairs of pc and instruction word of recently fetched instructions, if enabled.
+    backtrace: Option<VecDeque<(Xlen, u32)>>,
+    backtrace_len: usize,
+    // Values of `mcycle` and `minstret`. Each instruction takes one cycle.
+    cycle: u64,
+    instret: u64,
+    // Lowest and highest valid values of sp, if checked.
+    stack_bounds: Option<(Xlen, Xlen)>,
+    // Set when a store through sp fell outside `stack_bounds`.
+    stack_violated: bool,
+    // Address ranges written by `load` and `load_binary`, in the order of loading.
+    #[cfg(feature = "hash")]
+    loaded: Vec<Range<usize>>,
 }
 
-impl Processor {
+impl<M: Memory + ?Sized> Processor<M> {
     /// Instruction execution starts from the `pc`.
-    pub fn new(memory: Box<dyn Memory>) -> Self {
+    pub fn new(memory: Box<M>) -> Self {
         Self {
-            regs: [0; 32],
+            regs: RegisterFile::new(),
             pc: 0,
             mem: memory,
+            csr: Csr::default(),
+            stop_on_zero: false,
+            panic_on_unimplemented: false,
+            skip_illegal: false,
+            wfi_as_nop: false,
+            waiting: false,
+            interval: 0,
+            clock: default_clock(),
+            devices: Vec::new(),
+            regions: Vec::new(),
+            latencies: Vec::new(),
+            read_callbacks: Vec::new(),
+            write_callbacks: Vec::new(),
             has_jumped: false,
+            retired: false,
+            retire_sink: None,
+            #[cfg(feature = "std")]
+            trace_log: None,
+            retiring: None,
+            record_retire: false,
+            custom_decoder: None,
+            custom_executor: None,
+            illegal_instruction_hook: None,
+            ecall_policy: EcallPolicy::default(),
+            extensions: Extensions::default(),
+            semihosting: false,
+            semihosting_handler: None,
+            alignment_policy: AlignmentPolicy::default(),
+            syscall_handler: None,
+            useless_write_hook: None,
+            fetch_cache: None,
+            stats: ExecStats::default(),
+            entropy: None,
+            symbols: BTreeMap::new(),
+            backtrace: None,
+            backtrace_len: 0,
+            cycle: 0,
+            instret: 0,
+            stack_bounds: None,
+            stack_violated: false,
+            #[cfg(feature = "hash")]
+            loaded: Vec::new(),
         }
     }
 
-    /// Set program counter to start instruction execution.
-    pub fn set_pc(&mut self, pc: u32) {
-        if pc % 4 != 0 {
-            // If this rule is broken, instruction execution will never be done properly.
-            // And this is not during instruction execution, so returning `Exception` is
-            // inappropriate.
-            panic!("Instruction address must be aligned to a 4byte boundary");
-        }
-        self.pc = pc;
+    /// Create a processor resuming from the given pc, registers and CSRs, e.g. those of
+    /// a snapshot. Unlike `write_csr`, any CSR including read-only ones can be preset
+    /// by building `csr` with `Csr::from_values`, and `mcycle` and `minstret` resume
+    /// from their values there.
+    /// Only machine mode is implemented, so there is no privilege mode to give.
+    /// Fails with `InstructionAddressMisaligned` if `pc` is not aligned to a 4byte
+    /// boundary.
+    ///
+    /// ```
+    /// use wadachi_cpu::csr::{Csr, MHARTID, MINSTRET};
+    /// use wadachi_cpu::memory::{Memory, VectorMemory};
+    /// use wadachi_cpu::processor::Processor;
+    /// use wadachi_cpu::register::RegisterFile;
+    ///
+    /// let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+    /// let mut state = Processor::new(memory).state();
+    /// state.csrs[MHARTID as usize] = 7;
+    /// state.csrs[MINSTRET as usize] = 100;
+    ///
+    /// let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+    /// let csr = Csr::from_values(&state.csrs);
+    /// let processor = Processor::new_with_state(memory, 4, RegisterFile::new(), csr).unwrap();
+    /// assert_eq!(processor.hart_id(), 7);
+    /// assert_eq!(processor.instructions_retired(), 100);
+    /// ```
+    pub fn new_with_state(
+        memory: Box<M>,
+        pc: Xlen,
+        regs: RegisterFile,
+        csr: Csr,
+    ) -> Result<Self, Exception> {
+        let mut processor = Self::new(memory);
+        processor.try_set_pc(pc)?;
+        processor.regs = regs;
+        let counter = |low: u16, high: u16| (csr.read(high) as u64) << 32 | csr.read(low) as u64;
+        processor.cycle = counter(MCYCLE, MCYCLEH);
+        processor.instret = counter(MINSTRET, MINSTRETH);
+        processor.csr = csr;
+        Ok(processor)
     }
 
-    /// Load a program, which is an array of `u32` integer, in the `address`.
-    pub fn load(&mut self, address: u32, program: Vec<u32>) {
-        if address % 4 != 0 {
-            panic!("Instruction address must be aligned to a 4byte boundary");
-        }
-        for (index, instruction) in program.iter().enumerate() {
-            self.mem
-                .write_inst(address as usize + index * 4, *instruction);
-        }
+    /// Back `seed` CSR with a PRNG seeded by `seed`, so that guests reading hardware
+    /// entropy behave deterministically. Writes to `seed` are ignored.
+    pub fn with_entropy_seed(mut self, seed: u64) -> Self {
+        // xorshift gets stuck at zero.
+        let state = if seed == 0 { 0x9e3779b97f4a7c15 } else { seed };
+        self.entropy = Some(Cell::new(state));
+        self
     }
 
-    /// Execute the program stored in the memory.
-    pub fn execute(&mut self) {
-        loop {
-            if let Err(_) = self.tick() {
-                // We have nothing to do with exception, stop the loop for now.
-                break;
-            }
-        }
+    /// Install a sink receiving a `RetireRecord` after each successful `tick`.
+    pub fn set_retire_sink(&mut self, sink: RetireSink) {
+        self.retire_sink = Some(sink);
     }
 
-    /// Read the register value at index `idx`.
-    fn read_reg(&self, idx: usize) -> u32 {
-        if idx == 0 {
-            0
-        } else {
-            self.regs[idx]
-        }
+    /// Append a line to `writer` for each retired instruction, showing the pc, the raw
+    /// word, the disassembly and the last register, memory or CSR write.
+    #[cfg(feature = "std")]
+    pub fn enable_trace_log(&mut self, writer: Box<dyn Write>) {
+        self.trace_log = Some(writer);
     }
 
-    /// Write value to the register at index `idx`.
-    fn write_reg(&mut self, idx: usize, val: u32) {
-        if idx != 0 {
-            self.regs[idx] = val;
-        }
+    /// Install a decoder which gets a chance to interpret instruction words
+    /// the built-in decoder rejects as `IllegalInstruction`.
+    pub fn set_custom_decoder(&mut self, f: CustomDecoder) {
+        self.custom_decoder = Some(f);
     }
 
-    /// Read an instruction from current program counter and execute it.
-    pub fn tick(&mut self) -> Result<(), Exception> {
-        if self.pc + 4 > self.mem.len() as u32 {
-            return Err(Exception::InstructionAccessFault);
-        }
+    /// Install a handler executing instructions returned by the custom decoder.
+    /// The pc is incremented afterwards unless the handler calls `set_pc`.
+    pub fn set_custom_executor(&mut self, f: CustomExecutor<M>) {
+        self.custom_executor = Some(f);
+    }
 
-        let raw_inst = self.mem.read_inst(self.pc as usize);
-        match decode(raw_inst)? {
-            // R-Type
-            Instruction::Add(args) => self.inst_add(&args),
-            Instruction::Sub(args) => self.inst_sub(&args),
-            Instruction::Sll(args) => self.inst_sll(&args),
-            Instruction::Slt(args) => self.inst_slt(&args),
-            Instruction::Sltu(args) => self.inst_sltu(&args),
-            Instruction::Xor(args) => self.inst_xor(&args),
-            Instruction::Srl(args) => self.inst_srl(&args),
-            Instruction::Sra(args) => self.inst_sra(&args),
-            Instruction::Or(args) => self.inst_or(&args),
-            Instruction::And(args) => self.inst_and(&args),
+    /// Install a hook warning about computational instructions targeting x0, which are
+    /// often dead code or bugs. The canonical `nop` (`addi x0,x0,0`) is not reported.
+    pub fn set_useless_write_hook(&mut self, f: UselessWriteHook) {
+        self.useless_write_hook = Some(f);
+    }
 
-            // I-Type
-            Instruction::Jalr(args) => self.inst_jalr(&args)?,
-            Instruction::Addi(args) => self.inst_addi(&args),
-            Instruction::Slli(args) => self.inst_slli(&args),
-            Instruction::Slti(args) => self.inst_slti(&args),
-            Instruction::Sltiu(args) => self.inst_sltiu(&args),
-            Instruction::Xori(args) => self.inst_xori(&args),
-            Instruction::Srli(args) => self.inst_srli(&args),
-            Instruction::Srai(args) => self.inst_srai(&args),
-            Instruction::Ori(args) => self.inst_ori(&args),
-            Instruction::Andi(args) => self.inst_andi(&args),
-            Instruction::Lb(args) => self.inst_lb(&args),
-            Instruction::Lh(args) => self.inst_lh(&args),
-            Instruction::Lw(args) => self.inst_lw(&args),
-            Instruction::Lbu(args) => self.inst_lbu(&args),
-            Instruction::Lhu(args) => self.inst_lhu(&args),
+    /// Install a hook which gets a chance to emulate instruction words neither the
+    /// built-in nor the custom decoder understands, such as those of missing extensions.
+    /// The pc is incremented after a handled instruction unless the hook calls `set_pc`.
+    pub fn set_illegal_instruction_hook(&mut self, f: IllegalInstructionHook<M>) {
+        self.illegal_instruction_hook = Some(f);
+    }
 
-            // S-Type
-            Instruction::Sb(args) => self.inst_sb(&args),
-            Instruction::Sh(args) => self.inst_sh(&args),
-            Instruction::Sw(args) => self.inst_sw(&args),
+    /// Choose how `step` deals with `ecall`. The default is `EcallPolicy::Stop`.
+    pub fn set_ecall_policy(&mut self, policy: EcallPolicy) {
+        self.ecall_policy = policy;
+    }
 
-            // B-Type
-            Instruction::Beq(args) => self.inst_beq(&args)?,
-            Instruction::Bne(args) => self.inst_bne(&args)?,
-            Instruction::Blt(args) => self.inst_blt(&args)?,
-            Instruction::Bge(args) => self.inst_bge(&args)?,
-            Instruction::Bltu(args) => self.inst_bltu(&args)?,
-            Instruction::Bgeu(args) => self.inst_bgeu(&args)?,
+    /// Choose how misaligned loads and stores are handled, to model either kind of
+    /// hardware. The default is `AlignmentPolicy::Emulate`.
+    pub fn set_alignment_policy(&mut self, policy: AlignmentPolicy) {
+        self.alignment_policy = policy;
+    }
 
-            // U-Type
-            Instruction::Auipc(args) => self.inst_auipc(&args),
-            Instruction::Lui(args) => self.inst_lui(&args),
+    /// Serve `ebreak` between `slli x0,x0,0x1f` and `srai x0,x0,7` with the semihosting
+    /// handler, as RISC-V semihosting specifies. Other `ebreak`s, or all of them without
+    /// a handler, still stop.
+    pub fn set_semihosting(&mut self, enabled: bool) {
+        self.semihosting = enabled;
+    }
 
-            // J-Type
-            Instruction::Jal(args) => self.inst_jal(&args)?,
+    /// Install the handler serving semihosting calls enabled with `set_semihosting`.
+    /// Unlike `ecall`s, the operation number is in a0 and its parameter in a1.
+    pub fn set_semihosting_handler(&mut self, f: SyscallHandler<M>) {
+        self.semihosting_handler = Some(f);
+    }
 
-            _ => panic!("unimplemented"),
-        }
+    /// Install the handler serving `ecall` under `EcallPolicy::Semihost`.
+    /// Without one, `ecall` stops execution as under `EcallPolicy::Stop`.
+    pub fn set_syscall_handler(&mut self, f: SyscallHandler<M>) {
+        self.syscall_handler = Some(f);
+    }
 
-        // If no jump occured, increment pc.
-        if !self.has_jumped {
-            self.pc += 4;
-        }
-        self.has_jumped = false;
+    /// Stop with `StopReason::StackOverflow` after an instruction leaving sp outside
+    /// `low..=high`, or storing through sp outside `low..high`.
+    /// This catches runaway recursion in the guest.
+    pub fn set_stack_bounds(&mut self, low: Xlen, high: Xlen) {
+        self.stack_bounds = Some((low, high));
+    }
 
-        Ok(())
+    /// Current program counter.
+    pub fn pc(&self) -> Xlen {
+        self.pc
     }
-}
 
-impl Processor {
-    const fn sign_extend(val: u16) -> u32 {
-        if val & 0x800 != 0 {
-            (val as u32) | 0xfffff000
-        } else {
-            val as u32
-        }
+    /// Values of all general purpose registers, x0 being 0.
+    pub fn registers(&self) -> [Xlen; 32] {
+        self.regs.values()
     }
 
-    // Sign extend given integer with 20bit.
-    const fn sign_extend_20bit(value: u32) -> i32 {
-        if value & 0xfff80000 != 0 {
-            (value | 0xfff00000) as i32
-        } else {
-            value as i32
+    /// Set all general purpose registers at once. The value for x0 is ignored.
+    pub fn set_registers(&mut self, values: &[Xlen; 32]) {
+        for (idx, val) in values.iter().enumerate() {
+            self.regs.write(idx, *val);
         }
     }
 
-    fn inst_add(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv.wrapping_add(rv);
-        self.write_reg(args.rd, v);
+    /// Set program counter, failing if it is not aligned to a 4byte boundary.
+    pub fn try_set_pc(&mut self, pc: Xlen) -> Result<(), Exception> {
+        if !pc.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned);
+        }
+        self.pc = pc;
+        self.has_jumped = true;
+        Ok(())
     }
 
-    fn inst_sub(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv.wrapping_sub(rv);
-        self.write_reg(args.rd, v);
+    /// Set program counter to start instruction execution.
+    /// Called by a custom executor or a hook, this jumps to `pc`, so the pc is not
+    /// incremented afterwards even if `pc` is the address of the instruction itself.
+    pub fn set_pc(&mut self, pc: Xlen) {
+        if !pc.is_multiple_of(4) {
+            // If this rule is broken, instruction execution will never be done properly.
+            // And this is not during instruction execution, so returning `Exception` is
+            // inappropriate.
+            panic!("Instruction address must be aligned to a 4byte boundary");
+        }
+        self.pc = pc;
+        self.has_jumped = true;
     }
 
-    fn inst_sll(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv << rv;
-        self.write_reg(args.rd, v);
+    /// Load a program, which is an array of `u32` integer, in the `address`.
+    pub fn load(&mut self, address: Xlen, program: Vec<u32>) {
+        if !address.is_multiple_of(4) {
+            panic!("Instruction address must be aligned to a 4byte boundary");
+        }
+        for (index, instruction) in program.iter().enumerate() {
+            self.mem
+                .write_inst(Addr(address.wrapping_add(index as u32 * 4)), *instruction);
+        }
+        #[cfg(feature = "hash")]
+        self.loaded
+            .push(address as usize..address as usize + program.len() * 4);
+        self.flush_fetch_cache();
     }
 
-    fn inst_slt(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = self.read_reg(args.rs2) as i32;
-        let v = (lv < rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Load instruction words as `objdump -d` displays them, e.g. `0x00100093` for
+    /// `addi ra,zero,1`. They are stored little-endian like `load` does.
+    pub fn load_words_be_display(&mut self, address: Xlen, words: &[u32]) {
+        self.load(address, words.to_vec());
     }
 
-    fn inst_sltu(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = (lv < rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Load words holding bytes in memory order, as a hex dump like `xxd` displays
+    /// them, e.g. `0x93001000` for `addi ra,zero,1`.
+    pub fn load_words_le_bytes(&mut self, address: Xlen, words: &[u32]) {
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_be_bytes()).collect();
+        self.load_binary(address, &bytes);
     }
 
-    fn inst_xor(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv ^ rv;
-        self.write_reg(args.rd, v);
+    /// Load raw bytes, such as the output of `objcopy -O binary`, in the `address`.
+    /// The bytes are written verbatim, so instructions must be little-endian.
+    pub fn load_binary(&mut self, address: Xlen, bytes: &[u8]) {
+        for (index, byte) in bytes.iter().enumerate() {
+            self.mem
+                .write_byte(Addr(address.wrapping_add(index as u32)), *byte);
+        }
+        #[cfg(feature = "hash")]
+        self.loaded
+            .push(address as usize..address as usize + bytes.len());
+        self.flush_fetch_cache();
     }
 
-    fn inst_srl(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv >> rv;
-        self.write_reg(args.rd, v);
+    /// SHA-256 of the current contents of the memory ranges written by `load` and
+    /// `load_binary`, concatenated in the order they were loaded.
+    /// Comparing it with a known value guards against running a wrong image.
+    #[cfg(feature = "hash")]
+    pub fn loaded_image_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for range in &self.loaded {
+            for addr in range.clone() {
+                hasher.update([self.mem.read_byte(Addr(addr as u32))]);
+            }
+        }
+        hasher.finalize().into()
     }
 
-    fn inst_sra(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = self.read_reg(args.rs2);
-        let v = (lv >> rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Panic on an instruction word which neither the built-in nor the custom decoder
+    /// understands, instead of raising `IllegalInstruction`.
+    /// The backtrace is handy during development; it is off by default for library use.
+    pub fn set_panic_on_unimplemented(&mut self, panic_on_unimplemented: bool) {
+        self.panic_on_unimplemented = panic_on_unimplemented;
     }
 
-    fn inst_or(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv | rv;
-        self.write_reg(args.rd, v);
+    /// Treat `wfi` as a no-op instead of stopping with `StopReason::WaitingForInterrupt`,
+    /// so that idle loops in test programs keep running.
+    pub fn set_wfi_as_nop(&mut self, wfi_as_nop: bool) {
+        self.wfi_as_nop = wfi_as_nop;
     }
 
-    fn inst_and(&mut self, args: &RType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        let v = lv & rv;
-        self.write_reg(args.rd, v);
+    /// Skip an instruction word nothing can decode and continue from the next word,
+    /// counting it in `ExecStats::skipped`. This is not what hardware does, but helps to
+    /// explore a blob mixing code and data.
+    pub fn set_skip_illegal(&mut self, skip_illegal: bool) {
+        self.skip_illegal = skip_illegal;
     }
 
-    fn inst_jalr(&mut self, args: &IType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let new_pc = (lv + rv) & 0xffff_fffe;
-        if new_pc % 4 != 0 {
-            return Err(Exception::InstructionAddressMisaligned);
-        }
-        self.write_reg(args.rd, self.pc + 4);
-        self.set_pc(new_pc);
-        self.has_jumped = true;
-        Ok(())
+    /// Treat an all-zero word as the end of the program, so that `step` and `execute`
+    /// stop with `StopReason::Halted` rather than `IllegalInstruction` when running off
+    /// the end into zero-filled memory.
+    pub fn set_stop_on_zero(&mut self, stop_on_zero: bool) {
+        self.stop_on_zero = stop_on_zero;
     }
 
-    fn inst_addi(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = Self::sign_extend(args.imm) as i32;
-        let v = (lv + rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Wait for `ms` milliseconds after each instruction in `step` and `execute`,
+    /// so that execution can be observed. Zero disables waiting.
+    pub fn set_interval(&mut self, ms: u64) {
+        self.interval = ms;
     }
 
-    fn inst_slli(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = args.imm & 0x1f;
-        let v = lv << rv;
-        self.write_reg(args.rd, v);
+    /// Install a clock used to wait for the interval and to read the `time` CSR,
+    /// which restarts from the new clock's origin.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
     }
 
-    fn inst_slti(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = Self::sign_extend(args.imm) as i32;
-        let v = (lv < rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Cache fetched and decoded instructions by pc, so that executing the same address
+    /// again skips both. Stores by the processor and `load` keep the cache coherent, but
+    /// writing to `mem` directly or through another handle of a `SharedMemory` requires
+    /// calling `flush_fetch_cache`.
+    pub fn set_fetch_cache(&mut self, enabled: bool) {
+        self.fetch_cache = if enabled { Some(BTreeMap::new()) } else { None };
     }
 
-    fn inst_sltiu(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let v = (lv < rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Choose the optional extensions to execute. Instructions of disabled ones raise
+    /// `IllegalInstruction`.
+    pub fn set_extensions(&mut self, extensions: Extensions) {
+        self.extensions = extensions;
+        self.flush_fetch_cache();
     }
 
-    fn inst_xori(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let v = lv ^ rv;
-        self.write_reg(args.rd, v);
+    /// Discard all cached instructions.
+    pub fn flush_fetch_cache(&mut self) {
+        if let Some(cache) = self.fetch_cache.as_mut() {
+            cache.clear();
+        }
     }
 
-    fn inst_srli(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = args.imm & 0x1f;
-        let v = (lv >> rv) as u32;
-        self.write_reg(args.rd, v);
+    // Discard cached instructions overlapping `size` bytes from `addr`.
+    fn invalidate_fetch_cache(&mut self, addr: Addr, size: usize) {
+        if let Some(cache) = self.fetch_cache.as_mut() {
+            if size == 0 {
+                return;
+            }
+            let addr = addr.index();
+            let first = (addr & !0b11) as Xlen;
+            let last = ((addr + size - 1) & !0b11) as Xlen;
+            let stale: Vec<Xlen> = cache.range(first..=last).map(|(&pc, _)| pc).collect();
+            for pc in stale {
+                cache.remove(&pc);
+            }
+        }
     }
 
-    fn inst_srai(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = args.imm & 0x1f;
-        let v = (lv >> rv) as u32;
-        self.write_reg(args.rd, v);
+    /// Map `device` to the address range starting from `base`.
+    /// Accesses to the range are forwarded to the device instead of memory.
+    pub fn map_device(&mut self, base: Xlen, device: Box<dyn MmioDevice>) {
+        self.devices.push((base as usize, device));
     }
 
-    fn inst_ori(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let v = lv | rv;
-        self.write_reg(args.rd, v);
+    /// Call `f` for a load from `addr` and use its return value as the loaded value.
+    /// This takes precedence over devices and memory.
+    pub fn map_read_callback(&mut self, addr: u32, f: ReadCallback) {
+        self.read_callbacks.push((addr as usize, f));
     }
 
-    fn inst_andi(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let v = lv & rv;
-        self.write_reg(args.rd, v);
+    /// Call `f` with the stored value for a store to `addr` instead of writing it.
+    /// This takes precedence over devices and memory.
+    pub fn map_write_callback(&mut self, addr: u32, f: WriteCallback) {
+        self.write_callbacks.push((addr as usize, f));
     }
 
-    fn inst_lb(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let addr = (lv + rv) as usize;
-        let v = (self.mem.read_byte(addr) as i8) as u32;
-        self.write_reg(args.rd, v);
+    /// Restrict accesses to `size` bytes from `base` to `permissions`.
+    /// A store to a non-writable region raises `StoreAccessFault`, a load from a
+    /// non-readable region `LoadAccessFault` and a fetch from a non-executable region
+    /// `InstructionAccessFault`. Addresses outside any region allow every access.
+    pub fn set_permissions(&mut self, base: Xlen, size: usize, permissions: Permissions) {
+        let base = base as usize;
+        self.regions
+            .push((base..base.saturating_add(size), permissions));
     }
 
-    fn inst_lh(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let addr = (lv + rv) as usize;
-        let v = (self.mem.read_halfword(addr) as i16) as u32;
-        self.write_reg(args.rd, v);
+    /// Make each load or store to `size` bytes from `base` take `cycles` cycles in
+    /// addition to the cycle of the instruction, accumulated into `mcycle`. This models
+    /// memories of different speeds, such as SRAM, DRAM and MMIO.
+    pub fn set_latency(&mut self, base: Xlen, size: usize, cycles: u64) {
+        let base = base as usize;
+        self.latencies
+            .push((base..base.saturating_add(size), cycles));
     }
 
-    fn inst_lw(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let addr = (lv + rv) as usize;
-        let v = self.mem.read_word(addr);
-        self.write_reg(args.rd, v);
+    /// Point the stack pointer (x2) to `top`, rounded down to a 16byte boundary
+    /// as the calling convention requires.
+    pub fn setup_stack(&mut self, top: u32) {
+        self.write_reg(SP, top & !0xf);
     }
 
-    fn inst_lbu(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let addr = (lv + rv) as usize;
-        let v = self.mem.read_byte(addr) as u32;
-        self.write_reg(args.rd, v);
-    }
+    /// Set up the stack like Linux does for a new process and point the stack pointer to
+    /// it. The stack holds `argc`, `argv` and `envp` arrays terminated with NULL, followed
+    /// by the strings they point to, which are placed right below `top`.
+    /// Raises `StoreAccessFault` without writing anything if the stack does not fit
+    /// between address 0 and `top`, or `top` lies beyond the memory.
+    pub fn setup_stack_with_args(
+        &mut self,
+        top: u32,
+        args: &[&str],
+        envs: &[&str],
+    ) -> Result<(), Exception> {
+        let strings: Vec<&str> = args.iter().chain(envs).copied().collect();
+        let mut addr = top;
+        let mut ptrs = Vec::with_capacity(strings.len());
+        for s in &strings {
+            let len = u32::try_from(s.len() + 1).map_err(|_| Exception::StoreAccessFault)?;
+            addr = addr.checked_sub(len).ok_or(Exception::StoreAccessFault)?;
+            ptrs.push(addr);
+        }
+        let (arg_ptrs, env_ptrs) = ptrs.split_at(args.len());
+
+        // argc, argv, NULL, envp, NULL
+        let words: Vec<u32> = Some(args.len() as u32)
+            .into_iter()
+            .chain(arg_ptrs.iter().copied())
+            .chain(Some(0))
+            .chain(env_ptrs.iter().copied())
+            .chain(Some(0))
+            .collect();
+        let words_len = u32::try_from(words.len() * 4).map_err(|_| Exception::StoreAccessFault)?;
+        let sp = addr
+            .checked_sub(words_len)
+            .ok_or(Exception::StoreAccessFault)?
+            & !0xf;
+        if !self.mem.is_writable() || !self.in_memory(Addr(sp), (top - sp) as usize) {
+            return Err(Exception::StoreAccessFault);
+        }
 
-    fn inst_lhu(&mut self, args: &IType) {
-        let lv = self.read_reg(args.rs1);
-        let rv = Self::sign_extend(args.imm);
-        let addr = (lv + rv) as usize;
-        let v = self.mem.read_halfword(addr) as u32;
-        self.write_reg(args.rd, v);
+        for (s, ptr) in strings.iter().zip(&ptrs) {
+            for (i, byte) in s.bytes().chain(Some(0)).enumerate() {
+                self.mem.write_byte(Addr(ptr.wrapping_add(i as u32)), byte);
+            }
+        }
+        for (i, word) in words.iter().enumerate() {
+            self.mem
+                .write_word(Addr(sp.wrapping_add(i as u32 * 4)), *word);
+        }
+        self.write_reg(SP, sp);
+        Ok(())
     }
 
-    fn inst_sb(&mut self, args: &SType) {
-        let base = self.read_reg(args.rs1);
-        let offset = Self::sign_extend(args.imm);
-        let addr = (base + offset) as usize;
-        // Write least significant byte in rs2.
-        let data = self.read_reg(args.rs2) & 0xff;
-        self.mem.write_byte(addr, data as u8);
+    /// Execute the program stored in the memory for at most `max_steps` instructions.
+    pub fn execute_with_limit(&mut self, max_steps: usize) -> StopReason {
+        self.run_slice(max_steps as u64).0
     }
 
-    fn inst_sh(&mut self, args: &SType) {
-        let base = self.read_reg(args.rs1);
-        let offset = Self::sign_extend(args.imm);
-        let addr = (base + offset) as usize;
-        // Write least significant 2 byte in rs2.
-        let data = self.read_reg(args.rs2) & 0xffff;
-        self.mem.write_halfword(addr, data as u16);
+    /// Execute at most `budget` instructions and return the reason why it stopped, with
+    /// the number of instructions retired. The reason is `LimitReached` if the budget runs
+    /// out, in which case calling this again continues from the next instruction.
+    pub fn run_slice(&mut self, budget: u64) -> (StopReason, u64) {
+        let mut retired = 0;
+        while retired < budget {
+            match self.step() {
+                None => retired += 1,
+                // Exceptions, breakpoints and halts stop before the instruction retires.
+                Some(reason) => return (reason, retired + self.retired as u64),
+            }
+        }
+        (StopReason::LimitReached, retired)
     }
 
-    fn inst_sw(&mut self, args: &SType) {
-        let base = self.read_reg(args.rs1);
-        let offset = Self::sign_extend(args.imm);
-        let addr = (base + offset) as usize;
-        // Write least significant 4 byte in rs2.
-        let data = self.read_reg(args.rs2);
-        self.mem.write_word(addr, data);
+    /// Execute the program stored in the memory.
+    pub fn execute(&mut self) -> StopReason {
+        loop {
+            if let Some(reason) = self.step() {
+                return reason;
+            }
+        }
     }
 
-    // Inner procejure which is common to branch instructions.
-    // `offset` is branch instructions' immediate.
-    fn branch_inner(&mut self, condition: bool, offset: u16) -> Result<(), Exception> {
-        if condition {
-            if offset % 4 != 0 {
-                // This exception is generated only if the branch condition is true.
-                // cf. RISC-V Unprivileged ISA V20191213
+    /// Address of the instruction executed after the current one, computed from the
+    /// current registers without executing it.
+    /// Returns the exception the current instruction would raise on its control flow.
+    pub fn next_pc(&self) -> Result<Xlen, Exception> {
+        if !self.permissions(Addr(self.pc), 4).execute {
+            return Err(Exception::InstructionAccessFault);
+        }
+        let branch = |condition: bool, offset: u16| {
+            if !condition {
+                Ok(self.pc.wrapping_add(4))
+            } else if !offset.is_multiple_of(4) {
                 Err(Exception::InstructionAddressMisaligned)
             } else {
-                let offset = Self::sign_extend(offset);
-                self.pc += offset;
-                self.has_jumped = true;
-                Ok(())
+                Ok(self.pc.wrapping_add(Self::sign_extend_13bit(offset)))
             }
-        } else {
-            Ok(())
+        };
+        let target = |new_pc: Xlen| {
+            if new_pc.is_multiple_of(4) {
+                Ok(new_pc)
+            } else {
+                Err(Exception::InstructionAddressMisaligned)
+            }
+        };
+        let reg = |idx: usize| self.read_reg(idx);
+
+        let inst = match self.decode(self.mem.try_read_inst(Addr(self.pc))?)? {
+            Decoded::Builtin(inst) => inst,
+            // The custom executor is not run, so assume it falls through.
+            Decoded::Custom(_) => return Ok(self.pc.wrapping_add(4)),
+        };
+        match inst {
+            Instruction::Beq(args) => branch(reg(args.rs1) == reg(args.rs2), args.imm),
+            Instruction::Bne(args) => branch(reg(args.rs1) != reg(args.rs2), args.imm),
+            Instruction::Blt(args) => {
+                branch(Self::signed_lt(reg(args.rs1), reg(args.rs2)), args.imm)
+            }
+            Instruction::Bge(args) => {
+                branch(!Self::signed_lt(reg(args.rs1), reg(args.rs2)), args.imm)
+            }
+            Instruction::Bltu(args) => branch(reg(args.rs1) < reg(args.rs2), args.imm),
+            Instruction::Bgeu(args) => branch(reg(args.rs1) >= reg(args.rs2), args.imm),
+            Instruction::Jal(args) => target(self.pc.wrapping_add(args.imm)),
+            Instruction::Jalr(args) => {
+                target(reg(args.rs1).wrapping_add(Self::sign_extend(args.imm)) & !1)
+            }
+            Instruction::Mret => Ok(self.csr.read(MEPC) & !0b11),
+            Instruction::Ecall => Err(Exception::EnvironmentCallFromMMode),
+            Instruction::Ebreak => Err(Exception::Breakpoint),
+            _ => Ok(self.pc.wrapping_add(4)),
         }
     }
 
-    fn inst_beq(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        self.branch_inner(lv == rv, args.imm)
+    /// Execute one instruction like `tick` and report what it changed.
+    pub fn step_delta(&mut self) -> Result<StepDelta, Exception> {
+        self.record_retire = true;
+        let result = self.tick();
+        self.record_retire = false;
+        result?;
+
+        let record = self.retiring.take().unwrap_or_default();
+        Ok(StepDelta {
+            inst: record.inst_word,
+            reg_write: record.reg_writes.last().copied(),
+            mem_write: record
+                .mem_writes
+                .last()
+                .map(|&(addr, size, value)| (addr as u32, size as u8, value)),
+            csr_write: record.csr_writes.last().copied(),
+            pc_before: record.pc,
+            pc_after: record.new_pc,
+        })
     }
 
-    fn inst_bne(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        self.branch_inner(lv != rv, args.imm)
+    /// Execute one instruction, or a whole call if it is a call instruction, and return
+    /// the reason to stop execution if any.
+    /// Calls are `jal`/`jalr` writing `ra`, and returns are `jalr x0,0(ra)`.
+    pub fn step_over(&mut self) -> Option<StopReason> {
+        self.step_until_depth(|depth| depth <= 0)
     }
 
-    fn inst_blt(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = self.read_reg(args.rs2) as i32;
-        self.branch_inner(lv < rv, args.imm)
+    /// Execute instructions until the current function returns, and return the reason
+    /// to stop execution if any.
+    pub fn step_out(&mut self) -> Option<StopReason> {
+        self.step_until_depth(|depth| depth < 0)
     }
 
-    fn inst_bge(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1) as i32;
-        let rv = self.read_reg(args.rs2) as i32;
-        self.branch_inner(lv >= rv, args.imm)
+    // Step until the call depth relative to the current one satisfies `done`.
+    fn step_until_depth(&mut self, done: impl Fn(i32) -> bool) -> Option<StopReason> {
+        let mut depth = 0;
+        loop {
+            depth += self.call_depth_change();
+            if let Some(reason) = self.step() {
+                return Some(reason);
+            }
+            if done(depth) {
+                return None;
+            }
+        }
+    }
+
+    // How executing the current instruction changes the call depth.
+    fn call_depth_change(&self) -> i32 {
+        let word = self.mem.try_read_inst(Addr(self.pc));
+        match word.and_then(|word| self.decode(word)) {
+            Ok(Decoded::Builtin(Instruction::Jal(JType { rd: RA, .. }))) => 1,
+            Ok(Decoded::Builtin(Instruction::Jalr(IType { rd: RA, .. }))) => 1,
+            Ok(Decoded::Builtin(Instruction::Jalr(IType {
+                rd: 0,
+                rs1: RA,
+                imm: 0,
+            }))) => -1,
+            _ => 0,
+        }
+    }
+
+    /// Execute one instruction and return the reason to stop execution if any.
+    pub fn step(&mut self) -> Option<StopReason> {
+        self.retired = false;
+        if self.stop_on_zero && self.mem.try_read_inst(Addr(self.pc)) == Ok(0) {
+            return Some(StopReason::Halted);
+        }
+        if let Some(kind) = self.pending_interrupt() {
+            self.take_interrupt(kind);
+        }
+        match self.tick() {
+            Err(Exception::Breakpoint) => {
+                let handler = if self.is_semihosting_call() {
+                    self.semihosting_handler.take()
+                } else {
+                    None
+                };
+                match handler {
+                    Some(handler) => {
+                        if let Some(reason) = self.run_syscall_handler(handler, true) {
+                            return Some(reason);
+                        }
+                    }
+                    // No debug module exists, so return control to the host.
+                    None => return Some(StopReason::Breakpoint(self.pc)),
+                }
+            }
+            Err(Exception::EnvironmentCallFromMMode) => {
+                if let Some(reason) = self.handle_ecall() {
+                    return Some(reason);
+                }
+            }
+            // We have nothing to do with exception, stop the loop for now.
+            Err(exception) => return Some(StopReason::Exception(exception)),
+            Ok(()) => (),
+        }
+        if core::mem::take(&mut self.waiting) {
+            return Some(StopReason::WaitingForInterrupt);
+        }
+        if self.stack_overflowed() {
+            return Some(StopReason::StackOverflow);
+        }
+        if self.interval != 0 {
+            self.clock.sleep(self.interval);
+        }
+        self.take_stop_request()
+    }
+
+    // Whether the last instruction broke `stack_bounds`.
+    fn stack_overflowed(&mut self) -> bool {
+        let violated = core::mem::take(&mut self.stack_violated);
+        match self.stack_bounds {
+            Some((low, high)) => {
+                let sp = self.read_reg(SP);
+                violated || sp < low || sp > high
+            }
+            None => false,
+        }
+    }
+
+    // Record a store through sp to `addr` outside `stack_bounds`.
+    fn check_stack_store(&mut self, rs1: usize, addr: Addr) {
+        if let Some((low, high)) = self.stack_bounds {
+            if rs1 == SP && !(low..high).contains(&addr.0) {
+                self.stack_violated = true;
+            }
+        }
+    }
+
+    // Deal with `ecall` at the pc according to `ecall_policy`.
+    fn handle_ecall(&mut self) -> Option<StopReason> {
+        match self.ecall_policy {
+            EcallPolicy::Stop => Some(StopReason::Ecall),
+            EcallPolicy::Trap => {
+                self.trap(Exception::EnvironmentCallFromMMode, 0);
+                None
+            }
+            EcallPolicy::Semihost => match self.syscall_handler.take() {
+                Some(handler) => self.run_syscall_handler(handler, false),
+                None => Some(StopReason::Ecall),
+            },
+        }
+    }
+
+    // Serve the call at the pc with `handler`, which has been taken out of the processor
+    // so that it can borrow the processor mutably. It is put back as the semihosting
+    // handler if `semihosting` is true, or as the syscall handler otherwise.
+    fn run_syscall_handler(
+        &mut self,
+        mut handler: SyscallHandler<M>,
+        semihosting: bool,
+    ) -> Option<StopReason> {
+        let reason = handler(self);
+        if semihosting {
+            self.semihosting_handler = Some(handler);
+        } else {
+            self.syscall_handler = Some(handler);
+        }
+        self.retire();
+        reason
+    }
+
+    // Whether the `ebreak` at the pc is surrounded by the semihosting sequence.
+    fn is_semihosting_call(&self) -> bool {
+        self.semihosting
+            && self.mem.try_read_inst(Addr(self.pc).offset(-4)) == Ok(SEMIHOST_ENTRY)
+            && self.mem.try_read_inst(Addr(self.pc).offset(4)) == Ok(SEMIHOST_EXIT)
+    }
+
+    // Collect a stop request issued by devices.
+    fn take_stop_request(&mut self) -> Option<StopReason> {
+        self.devices
+            .iter_mut()
+            .find_map(|(_, device)| device.take_stop_request())
+    }
+
+    // Find the device mapped at `addr` and return it with the offset from its base.
+    fn find_device(&mut self, addr: Addr) -> Option<(&mut Box<dyn MmioDevice>, usize)> {
+        let addr = addr.index();
+        self.devices
+            .iter_mut()
+            .find(|(base, device)| *base <= addr && addr < base.saturating_add(device.size()))
+            .map(|(base, device)| (device, addr - *base))
+    }
+
+    // Whether `size` bytes from `addr` lie partly inside and partly outside a device.
+    fn straddles_device(&self, addr: Addr, size: usize) -> bool {
+        let (addr, end) = (addr.index(), addr.index().saturating_add(size));
+        self.devices.iter().any(|(base, device)| {
+            let device_end = base.saturating_add(device.size());
+            let overlaps = addr < device_end && *base < end;
+            overlaps && !(*base <= addr && end <= device_end)
+        })
+    }
+
+    // Whether any of `size` bytes from `addr` is mapped to a device or a callback.
+    fn overlaps_mmio(&self, addr: Addr, size: usize) -> bool {
+        let range = addr.index()..addr.index().saturating_add(size);
+        let overlaps_device = self.devices.iter().any(|(base, device)| {
+            *base < range.end && range.start < base.saturating_add(device.size())
+        });
+        let read_callbacks = self.read_callbacks.iter().map(|(a, _)| a);
+        let write_callbacks = self.write_callbacks.iter().map(|(a, _)| a);
+        overlaps_device
+            || read_callbacks
+                .chain(write_callbacks)
+                .any(|a| range.contains(a))
+    }
+
+    // Permissions allowed to every byte of `size` bytes from `addr`, which may lie in
+    // different regions.
+    fn permissions(&self, addr: Addr, size: usize) -> Permissions {
+        let end = addr.index().saturating_add(size);
+        let mut allowed = Permissions::RWX;
+        let mut byte = addr.index();
+        while byte < end {
+            let permissions = self
+                .regions
+                .iter()
+                .rev()
+                .find(|(range, _)| range.contains(&byte))
+                .map_or(Permissions::RWX, |(_, permissions)| *permissions);
+            allowed = Permissions::new(
+                allowed.read && permissions.read,
+                allowed.write && permissions.write,
+                allowed.execute && permissions.execute,
+            );
+            // Permissions only change at the boundaries of regions.
+            byte = self
+                .regions
+                .iter()
+                .flat_map(|(range, _)| [range.start, range.end])
+                .filter(|&boundary| boundary > byte)
+                .min()
+                .map_or(end, |boundary| boundary.min(end));
+        }
+        allowed
+    }
+
+    // Charge the latency of the region containing `addr` to `mcycle`.
+    fn charge_latency(&mut self, addr: Addr) {
+        if let Some((_, cycles)) = self
+            .latencies
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&addr.index()))
+        {
+            self.cycle = self.cycle.wrapping_add(*cycles);
+        }
+    }
+
+    // Whether an access of `size` bytes at `addr` must trap under `alignment_policy`.
+    fn is_misaligned(&self, addr: Addr, size: usize) -> bool {
+        self.alignment_policy == AlignmentPolicy::Trap && !addr.0.is_multiple_of(size as u32)
+    }
+
+    // Whether `size` bytes from `addr` lie within the memory.
+    fn in_memory(&self, addr: Addr, size: usize) -> bool {
+        addr.index()
+            .checked_add(size)
+            .is_some_and(|end| end <= self.mem.len())
+    }
+
+    // Read `size` bytes located at `addr` from a device or memory.
+    fn read_data(&mut self, addr: Addr, size: usize) -> Result<u32, Exception> {
+        if self.is_misaligned(addr, size) {
+            return Err(Exception::LoadAddressMisaligned);
+        }
+        if !self.permissions(addr, size).read || self.straddles_device(addr, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        // Latency is charged only once the access is known to succeed.
+        if let Some((_, f)) = self
+            .read_callbacks
+            .iter_mut()
+            .find(|(a, _)| *a == addr.index())
+        {
+            let value = f();
+            self.charge_latency(addr);
+            return Ok(value);
+        }
+        if let Some((device, offset)) = self.find_device(addr) {
+            let value = device.read(offset, size);
+            self.charge_latency(addr);
+            return Ok(value);
+        }
+        // The access is checked even if rd is x0 and the value is discarded.
+        if !self.in_memory(addr, size) {
+            return Err(Exception::LoadAccessFault);
+        }
+        self.charge_latency(addr);
+        Ok(match size {
+            1 => self.mem.read_byte(addr) as u32,
+            2 => self.mem.read_halfword(addr) as u32,
+            _ => self.mem.read_word(addr),
+        })
+    }
+
+    // Write lower `size` bytes of `data` at `addr` to a device or memory.
+    fn write_data(&mut self, addr: Addr, size: usize, data: u32) -> Result<(), Exception> {
+        if self.is_misaligned(addr, size) {
+            return Err(Exception::StoreAddressMisaligned);
+        }
+        if !self.permissions(addr, size).write || self.straddles_device(addr, size) {
+            return Err(Exception::StoreAccessFault);
+        }
+        if let Some(record) = self.retiring.as_mut() {
+            record.mem_writes.push((addr.index(), size, data));
+        }
+        self.invalidate_fetch_cache(addr, size);
+        if let Some((_, f)) = self
+            .write_callbacks
+            .iter_mut()
+            .find(|(a, _)| *a == addr.index())
+        {
+            f(data);
+            self.charge_latency(addr);
+            return Ok(());
+        }
+        if let Some((device, offset)) = self.find_device(addr) {
+            device.write(offset, size, data);
+            self.charge_latency(addr);
+            return Ok(());
+        }
+        if !self.mem.is_writable() || !self.in_memory(addr, size) {
+            return Err(Exception::StoreAccessFault);
+        }
+        self.charge_latency(addr);
+        match size {
+            1 => self.mem.write_byte(addr, data as u8),
+            2 => self.mem.write_halfword(addr, data as u16),
+            _ => self.mem.write_word(addr, data),
+        }
+        Ok(())
+    }
+
+    /// Execute at most `max_steps` instructions, stopping at the first exception.
+    /// Unlike trap handling, this does not vector anywhere, so the returned frame
+    /// describes the exact state at the fault.
+    /// Returns `None` if no exception occured within `max_steps`.
+    pub fn run_to_trap(&mut self, max_steps: usize) -> Option<TrapFrame> {
+        for _ in 0..max_steps {
+            if let Err(cause) = self.tick() {
+                let instruction = self.mem.try_read_inst(Addr(self.pc)).ok().and_then(|word| {
+                    match self.decode(word) {
+                        Ok(Decoded::Builtin(inst)) => Some(inst),
+                        _ => None,
+                    }
+                });
+                let access = match &instruction {
+                    Some(
+                        Instruction::Lb(IType { rs1, imm, .. })
+                        | Instruction::Lh(IType { rs1, imm, .. })
+                        | Instruction::Lw(IType { rs1, imm, .. })
+                        | Instruction::Lbu(IType { rs1, imm, .. })
+                        | Instruction::Lhu(IType { rs1, imm, .. })
+                        | Instruction::Sb(SType { rs1, imm, .. })
+                        | Instruction::Sh(SType { rs1, imm, .. })
+                        | Instruction::Sw(SType { rs1, imm, .. }),
+                    ) => Some(MemoryAccess {
+                        base: *rs1,
+                        base_value: self.read_reg(*rs1),
+                        offset: Self::sign_extend(*imm) as SXlen,
+                        addr: self.effective_address(*rs1, *imm).0,
+                    }),
+                    _ => None,
+                };
+                let mtval = match cause {
+                    Exception::IllegalInstruction => {
+                        self.mem.try_read_inst(Addr(self.pc)).unwrap_or(0)
+                    }
+                    Exception::InstructionAccessFault | Exception::Breakpoint => self.pc,
+                    Exception::LoadAddressMisaligned
+                    | Exception::LoadAccessFault
+                    | Exception::StoreAddressMisaligned
+                    | Exception::StoreAccessFault => {
+                        access.as_ref().map_or(0, |access| access.addr)
+                    }
+                    _ => 0,
+                };
+                return Some(TrapFrame {
+                    cause,
+                    pc: self.pc,
+                    mtval,
+                    instruction,
+                    access,
+                });
+            }
+        }
+        None
+    }
+
+    /// Hart ID of this processor, held in `mhartid`.
+    pub fn hart_id(&self) -> u32 {
+        self.csr.read(MHARTID)
+    }
+
+    /// Set hart ID of this processor.
+    pub fn set_hart_id(&mut self, id: u32) {
+        // `mhartid` is read-only for the guest.
+        self.csr.force_write(MHARTID, id);
+    }
+
+    /// Enter the trap handler pointed by `mtvec` for `cause`.
+    /// `mepc` holds the current pc, `MIE` in `mstatus` is pushed to `MPIE` and cleared,
+    /// and `MPP` records machine mode, the only mode implemented.
+    pub fn trap(&mut self, cause: Exception, mtval: u32) {
+        self.enter_trap(cause.code(), mtval);
+        // Exceptions always use the base address even in vectored mode.
+        self.pc = self.csr.read(MTVEC) & !0b11;
+    }
+
+    /// The highest priority interrupt which is pending in `mip`, enabled in `mie` and
+    /// not masked by `MIE` in `mstatus`.
+    /// Only machine mode is implemented, so `mideleg` plays no part.
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        if self.csr.read(MSTATUS) & MSTATUS_MIE == 0 {
+            return None;
+        }
+        let pending = self.csr.read(MIP) & self.csr.read(MIE);
+        Interrupt::PRIORITY
+            .iter()
+            .copied()
+            .find(|kind| pending & kind.mask() != 0)
+    }
+
+    // Enter the trap handler for the interrupt `kind` before executing the current pc.
+    fn take_interrupt(&mut self, kind: Interrupt) {
+        self.enter_trap(1 << 31 | kind as u32, 0);
+        let mtvec = self.csr.read(MTVEC);
+        let base = mtvec & !0b11;
+        // In vectored mode, each interrupt has its own entry.
+        self.pc = if mtvec & 0b11 == 1 {
+            base + 4 * kind as u32
+        } else {
+            base
+        };
+    }
+
+    // Update the CSRs on entering a trap.
+    fn enter_trap(&mut self, mcause: u32, mtval: u32) {
+        self.csr.force_write(MEPC, self.pc);
+        self.csr.force_write(MCAUSE, mcause);
+        self.csr.force_write(MTVAL, mtval);
+
+        let mstatus = self.csr.read(MSTATUS);
+        let mpie = if mstatus & MSTATUS_MIE != 0 {
+            MSTATUS_MPIE
+        } else {
+            0
+        };
+        let mstatus = (mstatus & !(MSTATUS_MIE | MSTATUS_MPIE)) | mpie | MSTATUS_MPP;
+        self.csr.force_write(MSTATUS, mstatus);
+    }
+
+    /// Mark `kind` as pending in `mip`.
+    pub fn set_pending_interrupt(&mut self, kind: Interrupt) {
+        self.csr.force_write(MIP, self.csr.read(MIP) | kind.mask());
+    }
+
+    /// Clear the pending bit of `kind` in `mip`.
+    pub fn clear_pending_interrupt(&mut self, kind: Interrupt) {
+        self.csr.force_write(MIP, self.csr.read(MIP) & !kind.mask());
+    }
+
+    /// Enable `kind` in `mie`.
+    pub fn enable_interrupt(&mut self, kind: Interrupt) {
+        self.csr.force_write(MIE, self.csr.read(MIE) | kind.mask());
+    }
+
+    /// Disable `kind` in `mie`.
+    pub fn disable_interrupt(&mut self, kind: Interrupt) {
+        self.csr.force_write(MIE, self.csr.read(MIE) & !kind.mask());
+    }
+
+    /// Read the CSR at `addr`.
+    pub fn read_csr(&self, addr: u16) -> u32 {
+        self.csr_value(addr)
+    }
+
+    /// Make CSR instructions accessing CSRs unimplemented by this emulator raise
+    /// `IllegalInstruction` instead of treating them as ordinary registers.
+    pub fn set_strict_csr(&mut self, strict: bool) {
+        self.csr.set_strict(strict);
+    }
+
+    /// Write `val` to the CSR at `addr`.
+    pub fn write_csr(&mut self, addr: u16, val: u32) -> Result<(), Exception> {
+        if let Some((counter, high)) = self.counter_mut(addr) {
+            *counter = Self::replace_half(*counter, val, high);
+            return Ok(());
+        }
+        self.csr.write(addr, val)
+    }
+
+    /// Full 64bit value of `mcycle`.
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Full 64bit value of `minstret`.
+    pub fn instructions_retired(&self) -> u64 {
+        self.instret
+    }
+
+    /// Reset `mcycle` and `minstret` to zero, e.g. before a region to measure.
+    pub fn reset_counters(&mut self) {
+        self.cycle = 0;
+        self.instret = 0;
+    }
+
+    // Counter backing a writable counter CSR, and whether the CSR is its upper half.
+    fn counter_mut(&mut self, addr: u16) -> Option<(&mut u64, bool)> {
+        match addr {
+            MCYCLE => Some((&mut self.cycle, false)),
+            MCYCLEH => Some((&mut self.cycle, true)),
+            MINSTRET => Some((&mut self.instret, false)),
+            MINSTRETH => Some((&mut self.instret, true)),
+            _ => None,
+        }
+    }
+
+    // Replace the upper or lower half of `counter` with `val`.
+    fn replace_half(counter: u64, val: u32, high: bool) -> u64 {
+        if high {
+            (counter & 0xffff_ffff) | (val as u64) << 32
+        } else {
+            (counter & !0xffff_ffff) | val as u64
+        }
+    }
+
+    /// Take a snapshot of the architectural state except for memory.
+    /// The CSRs include the current values of `mcycle`, `minstret` and their shadows,
+    /// but not `time`, which follows the clock rather than execution.
+    pub fn state(&self) -> ProcessorState {
+        let mut csrs = self.csr.values().to_vec();
+        for addr in [
+            MCYCLE, MCYCLEH, MINSTRET, MINSTRETH, CYCLE, CYCLEH, INSTRET, INSTRETH,
+        ] {
+            csrs[addr as usize] = self.csr_value(addr);
+        }
+        ProcessorState {
+            pc: self.pc,
+            regs: self.regs.values(),
+            csrs,
+        }
+    }
+
+    /// Copy `len` bytes of memory from `src` to `dst` on the host, e.g. to move
+    /// position-independent code. The ranges may overlap. Both are checked before
+    /// anything is copied: `LoadAccessFault` is raised if `src` is not readable, lies
+    /// beyond the memory or overlaps a device or a callback, and `StoreAccessFault` if
+    /// `dst` is likewise unfit for writing. No device or callback sees the copy, and no
+    /// latency is charged to `mcycle`.
+    pub fn copy_region(&mut self, src: Xlen, dst: Xlen, len: usize) -> Result<(), Exception> {
+        let (src, dst) = (Addr(src), Addr(dst));
+        if !self.permissions(src, len).read
+            || !self.in_memory(src, len)
+            || self.overlaps_mmio(src, len)
+        {
+            return Err(Exception::LoadAccessFault);
+        }
+        if !self.permissions(dst, len).write
+            || !self.mem.is_writable()
+            || !self.in_memory(dst, len)
+            || self.overlaps_mmio(dst, len)
+        {
+            return Err(Exception::StoreAccessFault);
+        }
+        let bytes: Vec<u8> = (0..len)
+            .map(|i| self.mem.read_byte(Addr(src.0.wrapping_add(i as u32))))
+            .collect();
+        for (i, byte) in bytes.iter().enumerate() {
+            self.mem
+                .write_byte(Addr(dst.0.wrapping_add(i as u32)), *byte);
+        }
+        self.invalidate_fetch_cache(dst, len);
+        Ok(())
+    }
+
+    /// Dump `len` bytes of memory from `addr` in `xxd` format.
+    pub fn dump_memory(&self, addr: u32, len: usize) -> String {
+        hex_dump(self.mem.as_ref(), Addr(addr), len)
+    }
+
+    /// Keep the pc and the word of the last `n` instructions fetched, including one which
+    /// raised an exception, for `recent_trace`. Zero disables it.
+    pub fn enable_backtrace(&mut self, n: usize) {
+        self.backtrace = if n == 0 {
+            None
+        } else {
+            Some(VecDeque::with_capacity(n))
+        };
+        self.backtrace_len = n;
+    }
+
+    /// Pairs of pc and instruction word recorded by `enable_backtrace`, oldest first.
+    pub fn recent_trace(&self) -> Vec<(u32, u32)> {
+        self.backtrace
+            .as_ref()
+            .map_or_else(Vec::new, |trace| trace.iter().copied().collect())
+    }
+
+    /// Name `addr` with `name` for `symbol_for`. A later symbol at the same address
+    /// replaces the earlier one.
+    pub fn add_symbol(&mut self, name: &str, addr: Xlen) {
+        self.symbols.insert(addr, String::from(name));
+    }
+
+    /// Find the nearest symbol at or before `addr`, returning its name and the offset of
+    /// `addr` from it, so that `addr` can be shown as `<name+offset>`.
+    pub fn symbol_for(&self, addr: Xlen) -> Option<(String, u32)> {
+        self.symbols
+            .range(..=addr)
+            .next_back()
+            .map(|(base, name)| (name.clone(), addr - base))
+    }
+
+    /// Read the register value at index `idx`.
+    fn read_reg(&self, idx: usize) -> Xlen {
+        self.regs.read(idx)
+    }
+
+    /// Write value to the register at index `idx`.
+    fn write_reg(&mut self, idx: usize, val: Xlen) {
+        if self.regs.write(idx, val) {
+            if let Some(record) = self.retiring.as_mut() {
+                record.reg_writes.push((idx, val));
+            }
+        }
+    }
+
+    /// Counters accumulated by `tick` so far.
+    pub fn stats(&self) -> &ExecStats {
+        &self.stats
+    }
+
+    /// How many times each undecodable instruction word was hit, telling which
+    /// instructions a partial implementation needs next. Pair with `set_skip_illegal`
+    /// to collect them in one run.
+    pub fn illegal_histogram(&self) -> &BTreeMap<u32, u64> {
+        &self.stats.illegal
+    }
+
+    /// Per-mnemonic execution counts in CSV, for importing into a spreadsheet.
+    pub fn stats_csv(&self) -> String {
+        self.stats.to_csv()
+    }
+
+    /// Read an instruction from current program counter and execute it.
+    pub fn tick(&mut self) -> Result<(), Exception> {
+        let result = self.execute_next();
+        if result.is_err() {
+            self.stats.traps += 1;
+        }
+        result
+    }
+
+    // Body of `tick`.
+    fn execute_next(&mut self) -> Result<(), Exception> {
+        // `set_pc` by the host before this instruction is not a jump of it.
+        self.has_jumped = false;
+        if !self.permissions(Addr(self.pc), 4).execute {
+            return Err(Exception::InstructionAccessFault);
+        }
+
+        let cached = self
+            .fetch_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&self.pc))
+            .cloned();
+        let (raw_inst, decoded) = match cached {
+            Some((raw_inst, inst)) => (raw_inst, Ok(Decoded::Builtin(inst))),
+            None => {
+                let raw_inst = self.mem.try_read_inst(Addr(self.pc))?;
+                let decoded = self.decode(raw_inst);
+                if let (Some(cache), Ok(Decoded::Builtin(inst))) =
+                    (self.fetch_cache.as_mut(), &decoded)
+                {
+                    cache.insert(self.pc, (raw_inst, inst.clone()));
+                }
+                (raw_inst, decoded)
+            }
+        };
+        if let Some(trace) = self.backtrace.as_mut() {
+            if trace.len() == self.backtrace_len {
+                trace.pop_front();
+            }
+            trace.push_back((self.pc, raw_inst));
+        }
+        self.retiring = if self.wants_retire_record() {
+            Some(RetireRecord {
+                pc: self.pc,
+                inst_word: raw_inst,
+                ..Default::default()
+            })
+        } else {
+            None
+        };
+        let decoded = match decoded {
+            Ok(Decoded::Builtin(inst)) => inst,
+            Ok(Decoded::Custom(custom)) => {
+                self.inst_custom(&custom)?;
+                self.stats.record_custom();
+                self.retire();
+                return Ok(());
+            }
+            Err(Exception::IllegalInstruction) => {
+                if !self.handle_illegal_instruction(raw_inst) {
+                    *self.stats.illegal.entry(raw_inst).or_insert(0) += 1;
+                    if self.skip_illegal {
+                        // The word is not executed, so it does not retire.
+                        self.retiring = None;
+                        self.pc = self.pc.wrapping_add(4);
+                        self.stats.skipped += 1;
+                        return Ok(());
+                    }
+                    if self.panic_on_unimplemented {
+                        panic!(
+                            "Unimplemented instruction {:#010x} at {:#x}",
+                            raw_inst, self.pc
+                        );
+                    }
+                    return Err(Exception::IllegalInstruction);
+                }
+                self.stats.record_custom();
+                self.retire();
+                return Ok(());
+            }
+            Err(exception) => return Err(exception),
+        };
+        if let Some(hook) = self.useless_write_hook.as_mut() {
+            if Self::is_useless_write(&decoded) {
+                hook(self.pc, &decoded);
+            }
+        }
+        match &decoded {
+            // R-Type
+            Instruction::Add(args) => self.inst_add(args),
+            Instruction::Sub(args) => self.inst_sub(args),
+            Instruction::Sll(args) => self.inst_sll(args),
+            Instruction::Slt(args) => self.inst_slt(args),
+            Instruction::Sltu(args) => self.inst_sltu(args),
+            Instruction::Xor(args) => self.inst_xor(args),
+            Instruction::Srl(args) => self.inst_srl(args),
+            Instruction::Sra(args) => self.inst_sra(args),
+            Instruction::Or(args) => self.inst_or(args),
+            Instruction::And(args) => self.inst_and(args),
+
+            // I-Type
+            Instruction::Jalr(args) => self.inst_jalr(args)?,
+            Instruction::Addi(args) => self.inst_addi(args),
+            Instruction::Slli(args) => self.inst_slli(args),
+            Instruction::Slti(args) => self.inst_slti(args),
+            Instruction::Sltiu(args) => self.inst_sltiu(args),
+            Instruction::Xori(args) => self.inst_xori(args),
+            Instruction::Srli(args) => self.inst_srli(args),
+            Instruction::Srai(args) => self.inst_srai(args),
+            Instruction::Ori(args) => self.inst_ori(args),
+            Instruction::Andi(args) => self.inst_andi(args),
+            Instruction::Lb(args) => self.inst_lb(args)?,
+            Instruction::Lh(args) => self.inst_lh(args)?,
+            Instruction::Lw(args) => self.inst_lw(args)?,
+            Instruction::Lbu(args) => self.inst_lbu(args)?,
+            Instruction::Lhu(args) => self.inst_lhu(args)?,
+            Instruction::Csrrw(args) => self.inst_csrrw(args)?,
+            Instruction::Csrrs(args) => self.inst_csrrs(args)?,
+            Instruction::Csrrc(args) => self.inst_csrrc(args)?,
+            Instruction::Csrrwi(args) => self.inst_csrrwi(args)?,
+            Instruction::Csrrsi(args) => self.inst_csrrsi(args)?,
+            Instruction::Csrrci(args) => self.inst_csrrci(args)?,
+
+            // S-Type
+            Instruction::Sb(args) => self.inst_sb(args)?,
+            Instruction::Sh(args) => self.inst_sh(args)?,
+            Instruction::Sw(args) => self.inst_sw(args)?,
+
+            // B-Type
+            Instruction::Beq(args) => self.inst_beq(args)?,
+            Instruction::Bne(args) => self.inst_bne(args)?,
+            Instruction::Blt(args) => self.inst_blt(args)?,
+            Instruction::Bge(args) => self.inst_bge(args)?,
+            Instruction::Bltu(args) => self.inst_bltu(args)?,
+            Instruction::Bgeu(args) => self.inst_bgeu(args)?,
+
+            // U-Type
+            Instruction::Auipc(args) => self.inst_auipc(args),
+            Instruction::Lui(args) => self.inst_lui(args),
+
+            // J-Type
+            Instruction::Jal(args) => self.inst_jal(args)?,
+
+            // Only machine mode is implemented.
+            Instruction::Ecall => return Err(Exception::EnvironmentCallFromMMode),
+            Instruction::Ebreak => return Err(Exception::Breakpoint),
+            Instruction::Mret => self.inst_mret(),
+            // There is no other hart to yield to.
+            Instruction::Pause => (),
+            Instruction::Wfi => self.inst_wfi(),
+        }
+
+        self.stats.record(&decoded, self.has_jumped);
+        self.retire();
+        Ok(())
+    }
+
+    // Whether effects of the instruction being executed need to be recorded.
+    fn wants_retire_record(&self) -> bool {
+        #[cfg(feature = "std")]
+        if self.trace_log.is_some() {
+            return true;
+        }
+        self.retire_sink.is_some() || self.record_retire
+    }
+
+    // Disassembly of a retired instruction word for the trace log. A word nothing
+    // decodes must have been emulated by the illegal instruction hook.
+    #[cfg(feature = "std")]
+    fn disassemble(&self, word: u32) -> String {
+        match self.decode(word) {
+            Ok(Decoded::Builtin(inst)) => inst.to_string(),
+            Ok(Decoded::Custom(inst)) => format!("custom {}", inst.id),
+            Err(_) => String::from("emulated"),
+        }
+    }
+
+    // Write a line like `00000004: 00102823  sw x1,16(x0)  => mem[0x10]=0x2a`.
+    #[cfg(feature = "std")]
+    fn write_trace_line(
+        log: &mut Box<dyn Write>,
+        record: &RetireRecord,
+        disassembly: &str,
+    ) -> std::io::Result<()> {
+        write!(
+            log,
+            "{:08x}: {:08x}  {}",
+            record.pc, record.inst_word, disassembly
+        )?;
+        if let Some(&(idx, value)) = record.reg_writes.last() {
+            write!(log, "  => x{}={:#x}", idx, value)?;
+        } else if let Some(&(addr, _, value)) = record.mem_writes.last() {
+            write!(log, "  => mem[{:#x}]={:#x}", addr, value)?;
+        } else if let Some(&(addr, value)) = record.csr_writes.last() {
+            write!(log, "  => csr[{:#x}]={:#x}", addr, value)?;
+        }
+        writeln!(log)
+    }
+
+    /// Finish the current instruction.
+    /// Increment pc if no jump occured, and report the effects to the retire sink.
+    fn retire(&mut self) {
+        if !self.has_jumped {
+            self.pc = self.pc.wrapping_add(4);
+        }
+        self.has_jumped = false;
+        self.retired = true;
+        self.cycle = self.cycle.wrapping_add(1);
+        self.instret = self.instret.wrapping_add(1);
+
+        if let Some(mut record) = self.retiring.take() {
+            record.new_pc = self.pc;
+            if let Some(sink) = self.retire_sink.as_mut() {
+                sink(&record);
+            }
+            #[cfg(feature = "std")]
+            if self.trace_log.is_some() {
+                let disassembly = self.disassemble(record.inst_word);
+                if let Some(log) = self.trace_log.as_mut() {
+                    // The log is only for diagnostics, so a failed write does not stop
+                    // execution.
+                    let _ = Self::write_trace_line(log, &record, &disassembly);
+                }
+            }
+            if self.record_retire {
+                self.retiring = Some(record);
+            }
+        }
+    }
+}
+
+impl Processor {
+    /// Run `program` loaded at `entry` on a fresh processor with memory just large enough
+    /// to hold it, for at most `RUN_PROGRAM_LIMIT` instructions.
+    /// Returns the processor to inspect the result, and the reason why it stopped.
+    pub fn run_program(program: Vec<u32>, entry: u32) -> (Self, StopReason) {
+        let size = entry as usize + program.len() * 4;
+        let mut processor = Self::new(Box::new(VectorMemory::new(size)));
+        processor.load(entry, program);
+        processor.set_pc(entry);
+        let reason = processor.execute_with_limit(RUN_PROGRAM_LIMIT);
+        (processor, reason)
+    }
+}
+
+impl<M: Memory + ?Sized> Processor<M> {
+    // Sign extend 12bit immediate to XLEN.
+    const fn sign_extend(val: u16) -> Xlen {
+        // Move the sign bit to MSB and shift it back arithmetically.
+        const SHIFT: u32 = SXlen::BITS - 12;
+        (((val as SXlen) << SHIFT) >> SHIFT) as Xlen
+    }
+
+    // Compare two register values as signed integers.
+    const fn signed_lt(lv: Xlen, rv: Xlen) -> bool {
+        (lv as SXlen) < (rv as SXlen)
+    }
+
+    // Sign extend 13bit branch offset to XLEN.
+    const fn sign_extend_13bit(val: u16) -> Xlen {
+        const SHIFT: u32 = SXlen::BITS - 13;
+        (((val as SXlen) << SHIFT) >> SHIFT) as Xlen
+    }
+
+    // Sign extend given integer with 20bit.
+    // Compute the address `x[rs1] + sext(imm)` accessed by loads and stores.
+    fn effective_address(&self, rs1: usize, imm: u16) -> Addr {
+        Addr(self.read_reg(rs1)).offset(Self::sign_extend(imm) as SXlen)
+    }
+
+    // Decode `word` with the enabled extensions, letting the custom decoder interpret
+    // words the built-in decoder rejects as illegal.
+    fn decode(&self, word: u32) -> Result<Decoded, Exception> {
+        match decode_with_extensions(word, self.extensions) {
+            Err(Exception::IllegalInstruction) => self
+                .custom_decoder
+                .as_ref()
+                .and_then(|f| f(word))
+                .map(Decoded::Custom)
+                .ok_or(Exception::IllegalInstruction),
+            decoded => decoded.map(Decoded::Builtin),
+        }
+    }
+
+    fn inst_custom(&mut self, args: &CustomInstruction) -> Result<(), Exception> {
+        // Take the executor out so that it can borrow the processor mutably.
+        let mut executor = self
+            .custom_executor
+            .take()
+            .ok_or(Exception::IllegalInstruction)?;
+        let result = executor(self, args);
+        self.custom_executor = Some(executor);
+        result
+    }
+
+    // Whether `inst` is a computational instruction whose result is discarded to x0,
+    // except for the canonical `nop`.
+    fn is_useless_write(inst: &Instruction) -> bool {
+        match inst {
+            Instruction::Addi(IType {
+                rd: 0,
+                rs1: 0,
+                imm: 0,
+            }) => false,
+            Instruction::Add(args)
+            | Instruction::Sub(args)
+            | Instruction::Sll(args)
+            | Instruction::Slt(args)
+            | Instruction::Sltu(args)
+            | Instruction::Xor(args)
+            | Instruction::Srl(args)
+            | Instruction::Sra(args)
+            | Instruction::Or(args)
+            | Instruction::And(args) => args.rd == 0,
+            Instruction::Addi(args)
+            | Instruction::Slli(args)
+            | Instruction::Slti(args)
+            | Instruction::Sltiu(args)
+            | Instruction::Xori(args)
+            | Instruction::Srli(args)
+            | Instruction::Srai(args)
+            | Instruction::Ori(args)
+            | Instruction::Andi(args) => args.rd == 0,
+            Instruction::Lui(args) | Instruction::Auipc(args) => args.rd == 0,
+            _ => false,
+        }
+    }
+
+    // Let the hook handle an instruction word nothing could decode.
+    fn handle_illegal_instruction(&mut self, raw_inst: u32) -> bool {
+        match self.illegal_instruction_hook.take() {
+            Some(mut hook) => {
+                let handled = hook(self, raw_inst);
+                self.illegal_instruction_hook = Some(hook);
+                handled
+            }
+            None => false,
+        }
+    }
+
+    // Wait for an interrupt by returning control to the host, unless one is pending
+    // regardless of `MIE` in `mstatus`, which would wake the hart.
+    fn inst_wfi(&mut self) {
+        if !self.wfi_as_nop && self.csr.read(MIP) & self.csr.read(MIE) == 0 {
+            self.waiting = true;
+        }
+    }
+
+    // Return from a trap: pop `MPIE` to `MIE` and resume at `mepc`.
+    // `MPP` is set to machine mode, the least privileged mode implemented.
+    fn inst_mret(&mut self) {
+        let mstatus = self.csr.read(MSTATUS);
+        let mie = if mstatus & MSTATUS_MPIE != 0 {
+            MSTATUS_MIE
+        } else {
+            0
+        };
+        let mstatus = (mstatus & !MSTATUS_MIE) | mie | MSTATUS_MPIE | MSTATUS_MPP;
+        self.csr.force_write(MSTATUS, mstatus);
+
+        self.pc = self.csr.read(MEPC) & !0b11;
+        self.has_jumped = true;
+    }
+
+    fn inst_add(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv.wrapping_add(rv);
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_sub(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv.wrapping_sub(rv);
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_sll(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv << rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_slt(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = Self::signed_lt(lv, rv) as u32;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_sltu(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = (lv < rv) as u32;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_xor(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv ^ rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_srl(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv >> rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_sra(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1) as SXlen;
+        let rv = self.read_reg(args.rs2);
+        let v = (lv >> rv) as Xlen;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_or(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv | rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_and(&mut self, args: &RType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        let v = lv & rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_jalr(&mut self, args: &IType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let new_pc = lv.wrapping_add(rv) & !1;
+        if !new_pc.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned);
+        }
+        self.write_reg(args.rd, self.pc.wrapping_add(4));
+        self.set_pc(new_pc);
+        Ok(())
+    }
+
+    fn inst_addi(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = lv.wrapping_add(rv);
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_slli(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = args.imm & 0x1f;
+        let v = lv << rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_slti(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = Self::signed_lt(lv, rv) as u32;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_sltiu(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = (lv < rv) as u32;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_xori(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = lv ^ rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_srli(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = args.imm & 0x1f;
+        let v = lv >> rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_srai(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1) as SXlen;
+        let rv = args.imm & 0x1f;
+        let v = (lv >> rv) as Xlen;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_ori(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = lv | rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_andi(&mut self, args: &IType) {
+        let lv = self.read_reg(args.rs1);
+        let rv = Self::sign_extend(args.imm);
+        let v = lv & rv;
+        self.write_reg(args.rd, v);
+    }
+
+    fn inst_lb(&mut self, args: &IType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        let v = (self.read_data(addr, 1)? as i8) as Xlen;
+        self.write_reg(args.rd, v);
+        Ok(())
+    }
+
+    fn inst_lh(&mut self, args: &IType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        let v = (self.read_data(addr, 2)? as i16) as Xlen;
+        self.write_reg(args.rd, v);
+        Ok(())
+    }
+
+    fn inst_lw(&mut self, args: &IType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        let v = self.read_data(addr, 4)?;
+        self.write_reg(args.rd, v);
+        Ok(())
+    }
+
+    fn inst_lbu(&mut self, args: &IType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        let v = self.read_data(addr, 1)?;
+        self.write_reg(args.rd, v);
+        Ok(())
+    }
+
+    fn inst_lhu(&mut self, args: &IType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        let v = self.read_data(addr, 2)?;
+        self.write_reg(args.rd, v);
+        Ok(())
+    }
+
+    // Read a CSR on behalf of CSR instructions.
+    fn csr_read(&self, addr: u16) -> Result<u32, Exception> {
+        if let (SEED, Some(entropy)) = (addr, self.entropy.as_ref()) {
+            return Ok(Self::next_seed(entropy));
+        }
+        self.csr.check_access(addr)?;
+        Ok(self.csr_value(addr))
+    }
+
+    // Next value of `seed` CSR: 16bit of entropy with OPST set to ES16.
+    fn next_seed(entropy: &Cell<u64>) -> u32 {
+        let mut x = entropy.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        entropy.set(x);
+        const ES16: u32 = 0b10 << 30;
+        ES16 | (x >> 48) as u32
+    }
+
+    // Value of a CSR, including ones whose values are maintained outside of `Csr`.
+    fn csr_value(&self, addr: u16) -> u32 {
+        match addr {
+            MCYCLE | CYCLE => self.cycle as u32,
+            MCYCLEH | CYCLEH => (self.cycle >> 32) as u32,
+            MINSTRET | INSTRET => self.instret as u32,
+            MINSTRETH | INSTRETH => (self.instret >> 32) as u32,
+            TIME => self.time() as u32,
+            TIMEH => (self.time() >> 32) as u32,
+            _ => self.csr.read(addr),
+        }
+    }
+
+    // Value of `time` CSR: nanoseconds elapsed on the installed clock.
+    fn time(&self) -> u64 {
+        self.clock.elapsed_ns()
+    }
+
+    // Write a CSR on behalf of CSR instructions.
+    fn csr_write(&mut self, addr: u16, val: u32) -> Result<(), Exception> {
+        if addr == SEED && self.entropy.is_some() {
+            return Ok(());
+        }
+        if let Some((counter, high)) = self.counter_mut(addr) {
+            // The writing instruction itself is not counted, so compensate the increment
+            // on its retirement.
+            *counter = Self::replace_half(*counter, val, high).wrapping_sub(1);
+            if let Some(record) = self.retiring.as_mut() {
+                record.csr_writes.push((addr, val));
+            }
+            return Ok(());
+        }
+        self.csr.write(addr, val)?;
+        if let Some(record) = self.retiring.as_mut() {
+            record.csr_writes.push((addr, val));
+        }
+        Ok(())
+    }
+
+    // For CSR instructions, `imm` holds the CSR address.
+    // Immediate variants use the `rs1` field as a 5bit unsigned immediate (zimm).
+    fn inst_csrrw(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        self.csr_write(args.imm, self.read_reg(args.rs1))?;
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    fn inst_csrrs(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        // If rs1 is x0, the CSR is not written at all, so no exception for read-only CSRs.
+        if args.rs1 != 0 {
+            self.csr_write(args.imm, old | self.read_reg(args.rs1))?;
+        }
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    fn inst_csrrc(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        if args.rs1 != 0 {
+            self.csr_write(args.imm, old & !self.read_reg(args.rs1))?;
+        }
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    fn inst_csrrwi(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        self.csr_write(args.imm, Self::zimm(args))?;
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    fn inst_csrrsi(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        if args.rs1 != 0 {
+            self.csr_write(args.imm, old | Self::zimm(args))?;
+        }
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    fn inst_csrrci(&mut self, args: &IType) -> Result<(), Exception> {
+        let old = self.csr_read(args.imm)?;
+        if args.rs1 != 0 {
+            self.csr_write(args.imm, old & !Self::zimm(args))?;
+        }
+        self.write_reg(args.rd, old);
+        Ok(())
+    }
+
+    // 5bit unsigned immediate of CSR immediate instructions, held in the `rs1` field.
+    fn zimm(args: &IType) -> Xlen {
+        args.rs1 as Xlen
+    }
+
+    fn inst_sb(&mut self, args: &SType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        self.check_stack_store(args.rs1, addr);
+        // Write least significant byte in rs2.
+        let data = self.read_reg(args.rs2) & 0xff;
+        self.write_data(addr, 1, data)
+    }
+
+    fn inst_sh(&mut self, args: &SType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        self.check_stack_store(args.rs1, addr);
+        // Write least significant 2 byte in rs2.
+        let data = self.read_reg(args.rs2) & 0xffff;
+        self.write_data(addr, 2, data)
+    }
+
+    fn inst_sw(&mut self, args: &SType) -> Result<(), Exception> {
+        let addr = self.effective_address(args.rs1, args.imm);
+        self.check_stack_store(args.rs1, addr);
+        // Write least significant 4 byte in rs2.
+        let data = self.read_reg(args.rs2);
+        self.write_data(addr, 4, data)
+    }
+
+    // Inner procejure which is common to branch instructions.
+    // `offset` is branch instructions' immediate.
+    fn branch_inner(&mut self, condition: bool, offset: u16) -> Result<(), Exception> {
+        if condition {
+            if !offset.is_multiple_of(4) {
+                // This exception is generated only if the branch condition is true.
+                // cf. RISC-V Unprivileged ISA V20191213
+                Err(Exception::InstructionAddressMisaligned)
+            } else {
+                let offset = Self::sign_extend_13bit(offset);
+                // A backward branch adds a large unsigned offset, which wraps around.
+                self.pc = self.pc.wrapping_add(offset);
+                self.has_jumped = true;
+                Ok(())
+            }
+        } else {
+            Ok(())
+        }
+    }
+
+    fn inst_beq(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(lv == rv, args.imm)
+    }
+
+    fn inst_bne(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(lv != rv, args.imm)
+    }
+
+    fn inst_blt(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(Self::signed_lt(lv, rv), args.imm)
+    }
+
+    fn inst_bge(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(!Self::signed_lt(lv, rv), args.imm)
+    }
+
+    fn inst_bltu(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(lv < rv, args.imm)
+    }
+
+    fn inst_bgeu(&mut self, args: &BType) -> Result<(), Exception> {
+        let lv = self.read_reg(args.rs1);
+        let rv = self.read_reg(args.rs2);
+        self.branch_inner(lv >= rv, args.imm)
+    }
+
+    fn inst_auipc(&mut self, args: &UType) {
+        let offset = args.imm << 12;
+        self.write_reg(args.rd, self.pc.wrapping_add(offset));
+    }
+
+    fn inst_lui(&mut self, args: &UType) {
+        let imm = args.imm << 12;
+        self.write_reg(args.rd, imm);
+    }
+
+    fn inst_jal(&mut self, args: &JType) -> Result<(), Exception> {
+        self.write_reg(args.rd, self.pc.wrapping_add(4));
+        let new_pc = self.pc.wrapping_add(args.imm);
+        if !new_pc.is_multiple_of(4) {
+            return Err(Exception::InstructionAddressMisaligned);
+        }
+        self.set_pc(new_pc);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unusual_byte_groupings)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::csr::MSTATUS;
+    use crate::device::{ExitDevice, Framebuffer};
+    use crate::memory::{Addr, EmptyMemory, Permissions, SliceMemory, VectorMemory};
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    #[test]
+    fn calc_rv32i_r_add() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x7);
+        proc.inst_add(&args);
+        assert_eq!(proc.read_reg(3), 0xa);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_add(&args);
+        assert_eq!(proc.read_reg(3), 0x80007ffe);
+    }
+
+    #[test]
+    fn calc_rv32i_r_sub() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x7);
+        proc.inst_sub(&args);
+        assert_eq!(proc.read_reg(3), 0xfffffffc);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_sub(&args);
+        assert_eq!(proc.read_reg(3), 0x7fff8000);
+    }
+
+    #[test]
+    fn calc_rv32i_r_sll() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x7);
+        proc.inst_sll(&args);
+        assert_eq!(proc.read_reg(3), 0x180);
+
+        proc.write_reg(1, 0xffff1234);
+        proc.write_reg(2, 16);
+        proc.inst_sll(&args);
+        assert_eq!(proc.read_reg(3), 0x12340000);
+    }
+
+    #[test]
+    fn calc_rv32i_r_slt() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x3);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x7);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x1);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+
+        proc.write_reg(1, 0xffffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x1);
+
+        // The most negative value is less than the most positive one.
+        proc.write_reg(1, 0x80000000);
+        proc.write_reg(2, 0x7fffffff);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x1);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x80000000);
+        proc.inst_slt(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+    }
+
+    #[test]
+    fn calc_rv32i_r_sltu() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x3);
+        proc.inst_sltu(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+
+        proc.write_reg(1, 0x3);
+        proc.write_reg(2, 0x7);
+        proc.inst_sltu(&args);
+        assert_eq!(proc.read_reg(3), 0x1);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_sltu(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+
+        proc.write_reg(1, 0xffffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_sltu(&args);
+        assert_eq!(proc.read_reg(3), 0x0);
+    }
+
+    #[test]
+    fn calc_rv32i_r_xor() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x1234);
+        proc.write_reg(2, 0x5678);
+        proc.inst_xor(&args);
+        assert_eq!(proc.read_reg(3), 0x444c);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_xor(&args);
+        assert_eq!(proc.read_reg(3), 0x7fff8000);
+    }
+
+    #[test]
+    fn calc_rv32i_r_srl() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x1234);
+        proc.write_reg(2, 0x4);
+        proc.inst_srl(&args);
+        assert_eq!(proc.read_reg(3), 0x123);
+
+        proc.write_reg(1, 0x80000000);
+        proc.write_reg(2, 0x4);
+        proc.inst_srl(&args);
+        assert_eq!(proc.read_reg(3), 0x08000000);
+    }
+
+    #[test]
+    fn calc_rv32i_r_sra() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x1234);
+        proc.write_reg(2, 0x4);
+        proc.inst_sra(&args);
+        assert_eq!(proc.read_reg(3), 0x123);
+
+        proc.write_reg(1, 0x80000000);
+        proc.write_reg(2, 0x4);
+        proc.inst_sra(&args);
+        assert_eq!(proc.read_reg(3), 0xf8000000);
+    }
+
+    #[test]
+    fn calc_rv32i_r_and() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x1234);
+        proc.write_reg(2, 0x5678);
+        proc.inst_and(&args);
+        assert_eq!(proc.read_reg(3), 0x1230);
+
+        proc.write_reg(1, 0x7fffffff);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_and(&args);
+        assert_eq!(proc.read_reg(3), 0x00007fff);
+    }
+
+    #[test]
+    fn calc_rv32i_r_or() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: RType = RType {
+            rs1: 1,
+            rs2: 2,
+            rd: 3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x1234);
+        proc.write_reg(2, 0x5678);
+        proc.inst_or(&args);
+        assert_eq!(proc.read_reg(3), 0x567c);
+
+        proc.write_reg(1, 0x7fff8000);
+        proc.write_reg(2, 0x00007fff);
+        proc.inst_or(&args);
+        assert_eq!(proc.read_reg(3), 0x7fffffff);
+    }
+
+    #[test]
+    fn calc_rv32i_i_jalr() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x111,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.set_pc(0x1234);
+
+        proc.write_reg(1, 0x567);
+        proc.inst_jalr(&args)?;
+        assert_eq!(proc.read_reg(2), 0x1238);
+        assert_eq!(proc.pc(), 0x678);
+
+        proc.set_pc(0x1234);
+        proc.write_reg(1, 0x543);
+        proc.inst_jalr(&args)?;
+        assert_eq!(proc.read_reg(2), 0x1238);
+        assert_eq!(proc.pc(), 0x654);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_jalr_invalid_address() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x110,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.set_pc(0x1234);
+        proc.write_reg(1, 0x567);
+        // x1 == 0x677, which is not aligned to a 4byte boundary.
+        assert_eq!(
+            proc.inst_jalr(&args),
+            Err(Exception::InstructionAddressMisaligned)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_addi() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x567);
+        proc.inst_addi(&args);
+        assert_eq!(proc.read_reg(2), 0x68a);
+    }
+
+    #[test]
+    fn calc_rv32i_i_slli() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_slli(&args);
+        assert_eq!(proc.read_reg(2), 0x2b3c0);
+    }
+
+    #[test]
+    fn calc_rv32i_i_slti() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x567);
+        proc.inst_slti(&args);
+        assert_eq!(proc.read_reg(2), 0x0);
+
+        proc.write_reg(1, 0x0);
+        proc.inst_slti(&args);
+        assert_eq!(proc.read_reg(2), 0x1);
+
+        proc.write_reg(1, 0xffffffff);
+        proc.inst_slti(&args);
+        assert_eq!(proc.read_reg(2), 0x1);
+
+        proc.write_reg(1, 0x80000000);
+        proc.inst_slti(&args);
+        assert_eq!(proc.read_reg(2), 0x1);
+
+        // imm == -1
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0xfff,
+        };
+        proc.write_reg(1, 0x7fffffff);
+        proc.inst_slti(&args);
+        assert_eq!(proc.read_reg(2), 0x0);
+    }
+
+    #[test]
+    fn calc_rv32i_i_sltiu() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_sltiu(&args);
+        assert_eq!(proc.read_reg(2), 0x0);
+
+        proc.write_reg(1, 0x0);
+        proc.inst_sltiu(&args);
+        assert_eq!(proc.read_reg(2), 0x1);
+
+        proc.write_reg(1, 0xffffffff);
+        proc.inst_sltiu(&args);
+        assert_eq!(proc.read_reg(2), 0x0);
+    }
+
+    #[test]
+    fn calc_rv32i_i_xori() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_xori(&args);
+        assert_eq!(proc.read_reg(2), 0x575b);
+    }
+
+    #[test]
+    fn calc_rv32i_i_srli() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_srli(&args);
+        assert_eq!(proc.read_reg(2), 0xacf);
+
+        proc.write_reg(1, 0x80000000);
+        proc.inst_srli(&args);
+        assert_eq!(proc.read_reg(2), 0x10000000);
+    }
+
+    #[test]
+    fn calc_rv32i_i_srai() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x3,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_srai(&args);
+        assert_eq!(proc.read_reg(2), 0xacf);
+
+        proc.write_reg(1, 0x80000000);
+        proc.inst_srai(&args);
+        assert_eq!(proc.read_reg(2), 0xf0000000);
+    }
+
+    #[test]
+    fn calc_rv32i_i_ori() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_ori(&args);
+        assert_eq!(proc.read_reg(2), 0x577b);
+    }
+
+    #[test]
+    fn calc_rv32i_i_andi() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x123,
+        };
+
+        let mut proc = Processor::new(memory);
+
+        proc.write_reg(1, 0x5678);
+        proc.inst_andi(&args);
+        assert_eq!(proc.read_reg(2), 0x020);
+    }
+
+    #[test]
+    fn calc_rv32i_i_load_sign() -> Result<(), Exception> {
+        // Little-endian words 0x807f807f and 0x7f807f80.
+        let memory = vec![0x7f, 0x80, 0x7f, 0x80, 0x80, 0x7f, 0x80, 0x7f];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
+        let mut proc = Processor::new(memory);
+        let load = |imm| IType { rs1: 0, rd: 2, imm };
+
+        // The most significant byte of the first word.
+        proc.inst_lb(&load(3))?;
+        assert_eq!(proc.read_reg(2), 0xffffff80);
+        proc.inst_lbu(&load(3))?;
+        assert_eq!(proc.read_reg(2), 0x80);
+        proc.inst_lb(&load(2))?;
+        assert_eq!(proc.read_reg(2), 0x7f);
+        proc.inst_lbu(&load(2))?;
+        assert_eq!(proc.read_reg(2), 0x7f);
+
+        // The upper halfword of each word.
+        proc.inst_lh(&load(2))?;
+        assert_eq!(proc.read_reg(2), 0xffff807f);
+        proc.inst_lhu(&load(2))?;
+        assert_eq!(proc.read_reg(2), 0x807f);
+        proc.inst_lh(&load(6))?;
+        assert_eq!(proc.read_reg(2), 0x7f80);
+        proc.inst_lhu(&load(6))?;
+        assert_eq!(proc.read_reg(2), 0x7f80);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_load() -> Result<(), Exception> {
+        let memory = vec![0x0, 0x0, 0x0, 0x0, 0x80, 0x80, 0x08, 0x08];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x0,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 4);
+
+        proc.inst_lb(&args)?;
+        assert_eq!(proc.read_reg(2), 0xffffff80);
+
+        proc.inst_lh(&args)?;
+        assert_eq!(proc.read_reg(2), 0xffff8080);
+
+        proc.inst_lw(&args)?;
+        assert_eq!(proc.read_reg(2), 0x08088080);
+
+        proc.inst_lbu(&args)?;
+        assert_eq!(proc.read_reg(2), 0x80);
+
+        proc.inst_lhu(&args)?;
+        assert_eq!(proc.read_reg(2), 0x8080);
+
+        let args: IType = IType {
+            rs1: 1,
+            rd: 2,
+            imm: 0x4,
+        };
+
+        proc.write_reg(1, 0);
+
+        proc.inst_lb(&args)?;
+        assert_eq!(proc.read_reg(2), 0xffffff80);
+
+        proc.inst_lh(&args)?;
+        assert_eq!(proc.read_reg(2), 0xffff8080);
+
+        proc.inst_lw(&args)?;
+        assert_eq!(proc.read_reg(2), 0x08088080);
+
+        proc.inst_lbu(&args)?;
+        assert_eq!(proc.read_reg(2), 0x80);
+
+        proc.inst_lhu(&args)?;
+        assert_eq!(proc.read_reg(2), 0x8080);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_csr() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let mut proc = Processor::new(memory);
+        // mscratch
+        let args = IType {
+            rd: 2,
+            rs1: 1,
+            imm: 0x340,
+        };
+
+        proc.write_reg(1, 0b1100);
+        proc.inst_csrrw(&args)?;
+        assert_eq!(proc.read_reg(2), 0);
+        assert_eq!(proc.read_csr(0x340), 0b1100);
+
+        proc.write_reg(1, 0b0011);
+        proc.inst_csrrs(&args)?;
+        assert_eq!(proc.read_reg(2), 0b1100);
+        assert_eq!(proc.read_csr(0x340), 0b1111);
+
+        proc.write_reg(1, 0b0110);
+        proc.inst_csrrc(&args)?;
+        assert_eq!(proc.read_reg(2), 0b1111);
+        assert_eq!(proc.read_csr(0x340), 0b1001);
+
+        // Immediate variants take `rs1` field as an immediate.
+        let args = IType {
+            rd: 2,
+            rs1: 0b10000,
+            imm: 0x340,
+        };
+        proc.inst_csrrsi(&args)?;
+        assert_eq!(proc.read_reg(2), 0b1001);
+        assert_eq!(proc.read_csr(0x340), 0b11001);
+
+        proc.inst_csrrci(&args)?;
+        assert_eq!(proc.read_reg(2), 0b11001);
+        assert_eq!(proc.read_csr(0x340), 0b01001);
+
+        proc.inst_csrrwi(&args)?;
+        assert_eq!(proc.read_reg(2), 0b01001);
+        assert_eq!(proc.read_csr(0x340), 0b10000);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_sb() -> Result<(), Exception> {
+        let memory = vec![0; 8];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
+        let args = SType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x2,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x2);
+        proc.write_reg(2, 0x180);
+        proc.inst_sb(&args)?;
+        assert_eq!(proc.mem.read_byte(Addr(4)), 0x80);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_sh() -> Result<(), Exception> {
+        let memory = vec![0; 8];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
+        let args = SType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x2,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x2);
+        proc.write_reg(2, 0x18080);
+        proc.inst_sh(&args)?;
+        assert_eq!(proc.mem.read_halfword(Addr(4)), 0x8080);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_i_sw() -> Result<(), Exception> {
+        let memory = vec![0; 8];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
+        let args = SType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x2,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x2);
+        proc.write_reg(2, 0x80808080);
+        proc.inst_sw(&args)?;
+        assert_eq!(proc.mem.read_word(Addr(4)), 0x80808080);
+        Ok(())
     }
 
-    fn inst_bltu(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        self.branch_inner(lv < rv, args.imm)
+    #[test]
+    fn calc_rv32i_b_beq() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x80,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 42);
+        proc.write_reg(2, 42);
+        proc.inst_beq(&args)?;
+        assert_eq!(proc.pc(), 0x80);
+        Ok(())
     }
 
-    fn inst_bgeu(&mut self, args: &BType) -> Result<(), Exception> {
-        let lv = self.read_reg(args.rs1);
-        let rv = self.read_reg(args.rs2);
-        self.branch_inner(lv >= rv, args.imm)
+    #[test]
+    fn calc_rv32i_b_offset() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let mut proc = Processor::new(memory);
+
+        // Offset whose bit 11 is set, but positive as 13bit integer.
+        let args = BType {
+            rs1: 0,
+            rs2: 0,
+            imm: 0x800,
+        };
+        proc.inst_beq(&args)?;
+        assert_eq!(proc.pc(), 0x800);
+
+        // -4096, the smallest offset.
+        let args = BType {
+            rs1: 0,
+            rs2: 0,
+            imm: 0x1000,
+        };
+        proc.inst_beq(&args)?;
+        assert_eq!(proc.pc(), 0xfffff800);
+
+        // -8
+        let args = BType {
+            rs1: 0,
+            rs2: 0,
+            imm: 0x1ff8,
+        };
+        proc.inst_beq(&args)?;
+        assert_eq!(proc.pc(), 0xfffff7f8);
+        Ok(())
     }
 
-    fn inst_auipc(&mut self, args: &UType) {
-        let offset = args.imm << 12;
-        let new_pc = self.pc + offset;
-        self.set_pc(new_pc);
-        self.write_reg(args.rd, new_pc);
+    // Test for invalid address in branch instruction is enough for this case because a processing the
+    // exception is abstracted in `Processor::branch_inner()`.
+    #[test]
+    fn calc_rv32i_b_beq_invalid_address() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x81,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 42);
+        proc.write_reg(2, 42);
+        assert_eq!(
+            proc.inst_beq(&args),
+            Err(Exception::InstructionAddressMisaligned)
+        );
+        Ok(())
     }
 
-    fn inst_lui(&mut self, args: &UType) {
-        let imm = args.imm << 12;
-        self.write_reg(args.rd, imm);
+    #[test]
+    fn calc_rv32i_b_bne() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x80,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 42);
+        proc.write_reg(2, 0);
+        proc.inst_bne(&args)?;
+        assert_eq!(proc.pc(), 0x80);
+        Ok(())
     }
 
-    fn inst_jal(&mut self, args: &JType) -> Result<(), Exception> {
-        self.write_reg(args.rd, self.pc + 4);
-        let offset = Self::sign_extend_20bit(args.imm);
-        let new_pc = (self.pc as i32).wrapping_add(offset) as u32;
-        if new_pc % 4 != 0 {
-            return Err(Exception::InstructionAddressMisaligned);
-        }
-        self.set_pc(new_pc);
-        self.has_jumped = true;
+    #[test]
+    fn calc_rv32i_b_blt() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x80,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0xffffff80);
+        proc.write_reg(2, 0);
+        // Compare register values as signed value.
+        proc.inst_blt(&args)?;
+        assert_eq!(proc.pc(), 0x80);
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::memory::{EmptyMemory, VectorMemory};
+    #[test]
+    fn calc_rv32i_b_bgt() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x80,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0);
+        proc.write_reg(2, 0xffffff80);
+        // Compare register values as signed value.
+        proc.inst_bge(&args)?;
+        assert_eq!(proc.pc(), 0x80);
+
+        proc.write_reg(1, 0xffffff80);
+        proc.write_reg(2, 0xffffff80);
+        // Compare register values as signed value.
+        proc.inst_bge(&args)?;
+        assert_eq!(proc.pc(), 0x100);
+        Ok(())
+    }
 
     #[test]
-    fn calc_rv32i_r_add() {
+    fn calc_rv32i_b_bltu() -> Result<(), Exception> {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
+        let args = BType {
             rs1: 1,
             rs2: 2,
-            rd: 3,
+            imm: 0x80,
         };
 
         let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0);
+        proc.write_reg(2, 0xffffff80);
+        // Compare register values as unsigned value.
+        proc.inst_bltu(&args)?;
+        assert_eq!(proc.pc(), 0x80);
+        Ok(())
+    }
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x7);
-        proc.inst_add(&args);
-        assert_eq!(proc.read_reg(3), 0xa);
+    #[test]
+    fn calc_rv32i_b_bgtu() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = BType {
+            rs1: 1,
+            rs2: 2,
+            imm: 0x80,
+        };
 
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_add(&args);
-        assert_eq!(proc.read_reg(3), 0x80007ffe);
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0xffffff80);
+        proc.write_reg(2, 0);
+        // Compare register values as unsigned value.
+        proc.inst_bgeu(&args)?;
+        assert_eq!(proc.pc(), 0x80);
+
+        proc.write_reg(1, 0xffffff80);
+        proc.write_reg(2, 0xffffff80);
+        // Compare register values as signed value.
+        proc.inst_bgeu(&args)?;
+        assert_eq!(proc.pc(), 0x100);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_u_lui() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = UType {
+            rd: 1,
+            imm: 0xfffff,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x0);
+        proc.inst_lui(&args);
+        assert_eq!(proc.read_reg(args.rd), 0xfffff000);
+    }
+
+    #[test]
+    fn calc_rv32i_u_auipc() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = UType {
+            rd: 1,
+            imm: 0xfffff,
+        };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x0);
+        // If pc is 0, cannot detect not adding `imm` to current pc.
+        proc.set_pc(0x4);
+        proc.inst_auipc(&args);
+        assert_eq!(proc.read_reg(args.rd), 0xfffff004);
+        assert_eq!(proc.pc(), 0x4);
+    }
+
+    #[test]
+    fn calc_rv32i_j_jal() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = JType { rd: 1, imm: 0x80 };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x0);
+        proc.set_pc(0x4);
+        proc.inst_jal(&args)?;
+        assert_eq!(proc.read_reg(args.rd), 0x8);
+        assert_eq!(proc.pc(), 0x84);
+
+        let args = JType {
+            rd: 1,
+            imm: 0xfffffffc, // -4
+        };
+        proc.inst_jal(&args)?;
+        assert_eq!(proc.read_reg(args.rd), 0x88);
+        assert_eq!(proc.pc(), 0x80);
+        Ok(())
+    }
+
+    #[test]
+    fn calc_rv32i_j_jal_invalid_address() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let args = JType { rd: 1, imm: 0x82 };
+
+        let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x0);
+        assert_eq!(
+            proc.inst_jal(&args),
+            Err(Exception::InstructionAddressMisaligned)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn useless_write_hook() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(12));
+        let mut proc = Processor::new(memory);
+        let reported = Rc::new(RefCell::new(Vec::new()));
+        let reported_clone = Rc::clone(&reported);
+        proc.set_useless_write_hook(Box::new(move |pc, _| reported_clone.borrow_mut().push(pc)));
+        proc.load(
+            0,
+            vec![
+                0x00000013, // nop
+                0x00208033, // add x0,x1,x2
+                0x00001037, // lui x0,1
+            ],
+        );
+        proc.execute();
+        assert_eq!(*reported.borrow(), vec![4, 8]);
+    }
+
+    #[test]
+    fn illegal_instruction_hook() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0b0000000_00000_00000_000_00101_0101011, // custom-1 writing x5
+                0x02a00093,                              // addi x1,x0,42
+                0b0000000_00000_00000_000_00000_1011011, // custom-2 not handled
+            ],
+        );
+        proc.set_illegal_instruction_hook(Box::new(|proc, raw| {
+            if raw & 0x7f != 0b0101011 {
+                return false;
+            }
+            proc.write_reg(((raw >> 7) & 0x1f) as usize, 7);
+            true
+        }));
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+        assert_eq!(proc.regs[5], 7);
+        assert_eq!(proc.regs[1], 42);
+        assert_eq!(proc.pc(), 8);
+    }
+
+    #[test]
+    fn custom_instruction() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut proc = Processor::new(memory);
+        // Increment rd, encoded with custom-0 opcode which is reserved for extensions.
+        proc.load(0, vec![0b0000000_00000_00000_000_00101_0001011]);
+        assert_eq!(proc.tick(), Err(Exception::IllegalInstruction));
+
+        proc.set_custom_decoder(Box::new(|raw| {
+            if raw & 0x7f == 0b0001011 {
+                Some(CustomInstruction { id: 0, raw })
+            } else {
+                None
+            }
+        }));
+        proc.set_custom_executor(Box::new(|proc, inst| {
+            let rd = ((inst.raw >> 7) & 0x1f) as usize;
+            proc.write_reg(rd, proc.read_reg(rd) + 1);
+            Ok(())
+        }));
+        proc.write_reg(5, 41);
+        proc.tick()?;
+        assert_eq!(proc.read_reg(5), 42);
+        assert_eq!(proc.pc(), 4);
+
+        // The custom decoder rejects this word.
+        assert_eq!(proc.tick(), Err(Exception::IllegalInstruction));
+        Ok(())
     }
 
     #[test]
-    fn calc_rv32i_r_sub() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
-
+    fn custom_instruction_self_loop() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
         let mut proc = Processor::new(memory);
+        // Decrement x5 and loop on itself until it reaches zero.
+        proc.load(0, vec![0b0000000_00000_00000_000_00101_0001011]);
+        proc.set_custom_decoder(Box::new(|raw| {
+            (raw & 0x7f == 0b0001011).then_some(CustomInstruction { id: 0, raw })
+        }));
+        proc.set_custom_executor(Box::new(|proc, _| {
+            let v = proc.read_reg(5) - 1;
+            proc.write_reg(5, v);
+            if v != 0 {
+                proc.set_pc(proc.pc());
+            }
+            Ok(())
+        }));
+        proc.write_reg(5, 3);
+        assert_eq!(proc.run_slice(2), (StopReason::LimitReached, 2));
+        assert_eq!(proc.pc(), 0);
+        assert_eq!(
+            proc.run_slice(2),
+            (StopReason::Exception(Exception::IllegalInstruction), 1)
+        );
+        assert_eq!(proc.read_reg(5), 0);
+        assert_eq!(proc.pc(), 4);
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x7);
-        proc.inst_sub(&args);
-        assert_eq!(proc.read_reg(3), 0xfffffffc);
-
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_sub(&args);
-        assert_eq!(proc.read_reg(3), 0x7fff8000);
+        // ecall served by a handler jumping back to it.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut proc = Processor::new(memory);
+        proc.load(0, vec![0x00000073]);
+        proc.set_ecall_policy(EcallPolicy::Semihost);
+        proc.set_syscall_handler(Box::new(|proc| {
+            proc.set_pc(proc.pc());
+            None
+        }));
+        assert_eq!(proc.run_slice(3), (StopReason::LimitReached, 3));
+        assert_eq!(proc.pc(), 0);
+        assert_eq!(proc.instructions_retired(), 3);
     }
 
     #[test]
-    fn calc_rv32i_r_sll() {
+    fn unaligned_pc() {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
-
         let mut proc = Processor::new(memory);
+        proc.set_pc(0x8);
+        assert_eq!(
+            proc.try_set_pc(0x6),
+            Err(Exception::InstructionAddressMisaligned)
+        );
+        assert_eq!(proc.pc(), 0x8);
+        assert_eq!(proc.try_set_pc(0xc), Ok(()));
+        assert_eq!(proc.pc(), 0xc);
+    }
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x7);
-        proc.inst_sll(&args);
-        assert_eq!(proc.read_reg(3), 0x180);
+    #[test]
+    fn unimplemented_traps() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(4));
+        let mut proc = Processor::new(memory);
+        // add with an unknown funct7
+        proc.load(0, vec![0x02310133]);
+        assert_eq!(proc.tick(), Err(Exception::IllegalInstruction));
+    }
 
-        proc.write_reg(1, 0xffff1234);
-        proc.write_reg(2, 16);
-        proc.inst_sll(&args);
-        assert_eq!(proc.read_reg(3), 0x12340000);
+    #[test]
+    #[should_panic]
+    fn unimplemented_panics() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(4));
+        let mut proc = Processor::new(memory);
+        proc.set_panic_on_unimplemented(true);
+        // add with an unknown funct7
+        proc.load(0, vec![0x02310133]);
+        let _ = proc.tick();
     }
 
     #[test]
-    fn calc_rv32i_r_slt() {
+    #[should_panic]
+    fn set_unaligned_pc() {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
-
         let mut proc = Processor::new(memory);
+        proc.set_pc(0x2);
+    }
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x3);
-        proc.inst_slt(&args);
-        assert_eq!(proc.read_reg(3), 0x0);
+    #[test]
+    fn run_to_trap() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        // addi a5,a5,1
+        // addi a5,a5,2
+        // (illegal)
+        proc.load(0, vec![0x00178793, 0x00278793, 0xffffffff]);
+        assert_eq!(proc.run_to_trap(2), None);
+
+        proc.set_pc(0);
+        let frame = proc.run_to_trap(10);
+        assert_eq!(
+            frame,
+            Some(TrapFrame {
+                cause: Exception::IllegalInstruction,
+                pc: 8,
+                mtval: 0xffffffff,
+                instruction: None,
+                access: None,
+            })
+        );
+        assert_eq!(proc.pc(), 8);
+    }
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x7);
-        proc.inst_slt(&args);
-        assert_eq!(proc.read_reg(3), 0x1);
+    #[test]
+    fn alignment_policy() {
+        let program = vec![
+            0x00200093, // addi x1,x0,2
+            0x0000a103, // lw x2,0(x1)
+        ];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, program.clone());
+        assert_eq!(proc.execute_with_limit(2), StopReason::LimitReached);
+        // Upper half of the first instruction and lower half of the second one.
+        assert_eq!(proc.regs[2], 0xa1030020);
 
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_slt(&args);
-        assert_eq!(proc.read_reg(3), 0x0);
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.set_alignment_policy(AlignmentPolicy::Trap);
+        proc.load(0, program);
+        assert_eq!(
+            proc.execute_with_limit(2),
+            StopReason::Exception(Exception::LoadAddressMisaligned)
+        );
+        assert_eq!(proc.pc(), 4);
+        assert_eq!(proc.regs[2], 0);
 
-        proc.write_reg(1, 0xffffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_slt(&args);
-        assert_eq!(proc.read_reg(3), 0x1);
+        proc.set_pc(0);
+        proc.load(4, vec![0x0010a023]); // sw x1,0(x1)
+        assert_eq!(
+            proc.execute_with_limit(2),
+            StopReason::Exception(Exception::StoreAddressMisaligned)
+        );
     }
 
     #[test]
-    fn calc_rv32i_r_sltu() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
-
+    fn out_of_bounds_access() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x0000a003, // lw x0,0(x1)
+                0x0010a023, // sw x1,0(x1)
+            ],
+        );
+        // The result would be discarded, but the access still faults.
+        proc.write_reg(1, 6);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::LoadAccessFault)
+        );
+        assert_eq!(proc.pc(), 0);
+        assert_eq!(proc.read_reg(0), 0);
 
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x3);
-        proc.inst_sltu(&args);
-        assert_eq!(proc.read_reg(3), 0x0);
-
-        proc.write_reg(1, 0x3);
-        proc.write_reg(2, 0x7);
-        proc.inst_sltu(&args);
-        assert_eq!(proc.read_reg(3), 0x1);
+        proc.write_reg(1, 0x1000);
+        assert_eq!(
+            proc.run_to_trap(1).map(|frame| frame.cause),
+            Some(Exception::LoadAccessFault)
+        );
+        proc.set_pc(4);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
+    }
 
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_sltu(&args);
-        assert_eq!(proc.read_reg(3), 0x0);
+    #[test]
+    fn run_to_trap_load_operands() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x800));
+        let mut proc = Processor::new(memory);
+        proc.set_permissions(0x400, 0x400, Permissions::new(false, false, false));
+        proc.load(
+            0,
+            vec![
+                0x7ff00293, // addi x5,x0,2047
+                0xffc2a303, // lw x6,-4(x5)
+            ],
+        );
+        let frame = proc.run_to_trap(10).unwrap();
+        assert_eq!(frame.cause, Exception::LoadAccessFault);
+        assert_eq!(frame.pc, 4);
+        assert_eq!(frame.mtval, 2043);
+        assert_eq!(
+            frame.instruction,
+            Some(Instruction::Lw(IType {
+                rd: 6,
+                rs1: 5,
+                imm: 0xffc
+            }))
+        );
+        assert_eq!(
+            frame.access,
+            Some(MemoryAccess {
+                base: 5,
+                base_value: 2047,
+                offset: -4,
+                addr: 2043,
+            })
+        );
+        assert_eq!(proc.regs[6], 0);
+    }
 
-        proc.write_reg(1, 0xffffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_sltu(&args);
-        assert_eq!(proc.read_reg(3), 0x0);
+    #[test]
+    fn slice_memory() {
+        static PROGRAM: [u8; 12] = [
+            0x93, 0x00, 0x10, 0x00, // addi x1,x0,1
+            0x13, 0x01, 0x20, 0x00, // addi x2,x0,2
+            0x23, 0x20, 0x10, 0x00, // sw x1,0(x0)
+        ];
+        let mut proc = Processor::new(Box::new(SliceMemory(&PROGRAM)));
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(proc.read_reg(1), 1);
+        assert_eq!(proc.read_reg(2), 2);
+        assert_eq!(PROGRAM[0], 0x93);
     }
 
     #[test]
-    fn calc_rv32i_r_xor() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
+    fn load_words() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut display = Processor::new(memory);
+        // addi x1,x0,1; addi x2,x0,2
+        display.load_words_be_display(0, &[0x00100093, 0x00200113]);
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut raw = Processor::new(memory);
+        raw.load_words_le_bytes(0, &[0x93001000, 0x13012000]);
+
+        assert_eq!(display.mem.read_byte(Addr(0)), 0x93);
+        assert_eq!(raw.mem.read_byte(Addr(0)), 0x93);
+        for proc in [&mut display, &mut raw] {
+            proc.execute();
+            assert_eq!(proc.read_reg(1), 1);
+            assert_eq!(proc.read_reg(2), 2);
+        }
+    }
 
+    #[test]
+    fn reset_counters() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00a00093, // addi x1,x0,10
+                0x00310113, // addi x2,x2,3
+                0xfff08093, // addi x1,x1,-1
+                0xfe009ce3, // bne x1,x0,-8
+            ],
+        );
+        proc.step();
+        assert_eq!(proc.instructions_retired(), 1);
+        proc.reset_counters();
+        assert_eq!(proc.cycles(), 0);
+        assert_eq!(proc.instructions_retired(), 0);
+
+        // The loop body runs 10 times.
+        proc.execute();
+        assert_eq!(proc.instructions_retired(), 30);
+        assert_eq!(proc.cycles(), 30);
+    }
 
-        proc.write_reg(1, 0x1234);
-        proc.write_reg(2, 0x5678);
-        proc.inst_xor(&args);
-        assert_eq!(proc.read_reg(3), 0x444c);
-
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_xor(&args);
-        assert_eq!(proc.read_reg(3), 0x7fff8000);
+    #[test]
+    fn negative_offset() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0xfe20ae23, // sw x2,-4(x1)
+                0xffc0a183, // lw x3,-4(x1)
+            ],
+        );
+        proc.write_reg(1, 0x100);
+        proc.write_reg(2, 0xcafe);
+        proc.tick().unwrap();
+        proc.tick().unwrap();
+        assert_eq!(proc.mem.read_word(Addr(0xfc)), 0xcafe);
+        assert_eq!(proc.read_reg(3), 0xcafe);
     }
 
     #[test]
-    fn calc_rv32i_r_srl() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
+    fn xlen_helpers() {
+        assert_eq!(Processor::<dyn Memory>::sign_extend(0x7ff), 0x7ff);
+        assert_eq!(Processor::<dyn Memory>::sign_extend(0x800), 0xfffff800);
+        assert_eq!(Processor::<dyn Memory>::sign_extend(0xfff), 0xffffffff);
 
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
         let mut proc = Processor::new(memory);
+        proc.write_reg(1, 0x100);
+        assert_eq!(proc.effective_address(1, 0x004), Addr(0x104));
+        // -4
+        assert_eq!(proc.effective_address(1, 0xffc), Addr(0xfc));
+        // Address calculation wraps around like hardware does.
+        proc.write_reg(1, 0xffffffff);
+        assert_eq!(proc.effective_address(1, 0x001), Addr(0x0));
+    }
 
-        proc.write_reg(1, 0x1234);
-        proc.write_reg(2, 0x4);
-        proc.inst_srl(&args);
-        assert_eq!(proc.read_reg(3), 0x123);
+    #[test]
+    fn exit_device() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
+        let mut proc = Processor::new(memory);
+        proc.map_device(0x100, Box::new(ExitDevice::new()));
+        proc.load(
+            0,
+            vec![
+                0x10000093, // addi x1,x0,0x100
+                0x55500113, // addi x2,x0,0x555
+                0x00500193, // addi x3,x0,5
+                0x00c19193, // slli x3,x3,12
+                0x00316133, // or x2,x2,x3
+                0x0020a023, // sw x2,0(x1)
+            ],
+        );
+        assert_eq!(proc.execute(), StopReason::Exit(0));
+        assert_eq!(proc.pc(), 24);
+    }
 
-        proc.write_reg(1, 0x80000000);
-        proc.write_reg(2, 0x4);
-        proc.inst_srl(&args);
-        assert_eq!(proc.read_reg(3), 0x08000000);
+    #[test]
+    fn framebuffer() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        let fb = Framebuffer::new(4, 2);
+        proc.map_device(0x100, Box::new(fb.clone()));
+        proc.load(
+            0,
+            vec![
+                0x10000093, // addi x1,x0,0x100
+                0xfff00113, // addi x2,x0,-1
+                0x0020a023, // sw x2,0(x1)
+                0x0020ae23, // sw x2,28(x1)
+            ],
+        );
+        proc.execute();
+        let mut expected = [0; 8];
+        expected[0] = 0xffffffff;
+        expected[7] = 0xffffffff;
+        assert_eq!(&*fb.pixels(), &expected);
     }
 
     #[test]
-    fn calc_rv32i_r_sra() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
+    fn mmio_sub_word() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        let fb = Framebuffer::new(4, 2);
+        proc.map_device(0x100, Box::new(fb.clone()));
+        proc.load(
+            0,
+            vec![
+                0x10000093, // addi x1,x0,0x100
+                0x0ab00113, // addi x2,x0,0xab
+                0x00208123, // sb x2,2(x1)
+                0x12300113, // addi x2,x0,0x123
+                0x00209323, // sh x2,6(x1)
+                0x0020c183, // lbu x3,2(x1)
+                0x00609203, // lh x4,6(x1)
+                0x00100073, // ebreak
+            ],
+        );
+        assert_eq!(proc.execute(), StopReason::Breakpoint(28));
+        assert_eq!(fb.pixels()[0], 0x00ab0000);
+        assert_eq!(fb.pixels()[1], 0x01230000);
+        assert_eq!(proc.read_reg(3), 0xab);
+        assert_eq!(proc.read_reg(4), 0x123);
+    }
 
+    #[test]
+    fn mmio_straddling_access() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
         let mut proc = Processor::new(memory);
+        proc.map_device(0x100, Box::new(Framebuffer::new(4, 2)));
+        proc.load(
+            0,
+            vec![
+                0x01e0a283, // lw x5,30(x1)
+                0xffe0a283, // lw x5,-2(x1)
+                0x00209fa3, // sh x2,31(x1)
+            ],
+        );
+        proc.write_reg(1, 0x100);
+        for pc in [0, 4] {
+            proc.set_pc(pc);
+            assert_eq!(
+                proc.execute(),
+                StopReason::Exception(Exception::LoadAccessFault)
+            );
+        }
+        proc.set_pc(8);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
+        assert_eq!(proc.read_reg(5), 0);
+    }
 
-        proc.write_reg(1, 0x1234);
-        proc.write_reg(2, 0x4);
-        proc.inst_sra(&args);
-        assert_eq!(proc.read_reg(3), 0x123);
+    #[test]
+    fn mmio_callbacks() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        let last = Rc::new(RefCell::new(None));
+        let last_clone = Rc::clone(&last);
+        proc.map_write_callback(0x100, Box::new(move |v| *last_clone.borrow_mut() = Some(v)));
+        proc.map_read_callback(0x104, Box::new(|| 7));
+        proc.load(
+            0,
+            vec![
+                0x02a00093, // addi x1,x0,42
+                0x10102023, // sw x1,256(x0)
+                0x10402103, // lw x2,260(x0)
+            ],
+        );
+        proc.execute();
+        assert_eq!(*last.borrow(), Some(42));
+        assert_eq!(proc.regs[2], 7);
+    }
 
-        proc.write_reg(1, 0x80000000);
-        proc.write_reg(2, 0x4);
-        proc.inst_sra(&args);
-        assert_eq!(proc.read_reg(3), 0xf8000000);
+    #[test]
+    fn next_pc() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00208663, // beq x1,x2,12
+                0x02a00093, // addi x1,x0,42
+            ],
+        );
+        assert_eq!(proc.next_pc(), Ok(12));
+        assert_eq!(proc.pc(), 0);
+
+        proc.regs[1] = 1;
+        assert_eq!(proc.next_pc(), Ok(4));
+        proc.set_pc(4);
+        assert_eq!(proc.next_pc(), Ok(8));
+        assert_eq!(proc.regs[1], 1);
+
+        // A word only the custom decoder understands.
+        proc.load(8, vec![0b0000000_00000_00000_000_00000_0001011]);
+        proc.set_pc(8);
+        assert_eq!(proc.next_pc(), Err(Exception::IllegalInstruction));
+        proc.set_custom_decoder(Box::new(|raw| Some(CustomInstruction { id: 0, raw })));
+        assert_eq!(proc.next_pc(), Ok(12));
     }
 
     #[test]
-    fn calc_rv32i_r_and() {
+    fn symbols() {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
-
         let mut proc = Processor::new(memory);
+        proc.add_symbol("main", 0x100);
+        proc.add_symbol("helper", 0x140);
 
-        proc.write_reg(1, 0x1234);
-        proc.write_reg(2, 0x5678);
-        proc.inst_and(&args);
-        assert_eq!(proc.read_reg(3), 0x1230);
-
-        proc.write_reg(1, 0x7fffffff);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_and(&args);
-        assert_eq!(proc.read_reg(3), 0x00007fff);
+        assert_eq!(proc.symbol_for(0xfc), None);
+        assert_eq!(proc.symbol_for(0x100), Some((String::from("main"), 0)));
+        assert_eq!(proc.symbol_for(0x110), Some((String::from("main"), 0x10)));
+        assert_eq!(proc.symbol_for(0x148), Some((String::from("helper"), 8)));
     }
 
     #[test]
-    fn calc_rv32i_r_or() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: RType = RType {
-            rs1: 1,
-            rs2: 2,
-            rd: 3,
-        };
+    fn step_over_and_out() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(24));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00100513, // addi x10,x0,1
+                0x010000e7, // jalr x1,16(x0)
+                0x00200593, // addi x11,x0,2
+                0x00300613, // addi x12,x0,3
+                0x00550513, // addi x10,x10,5
+                0x00008067, // jalr x0,0(x1)
+            ],
+        );
+
+        assert_eq!(proc.step_over(), None);
+        assert_eq!(proc.pc(), 4);
+        assert_eq!(proc.step_over(), None);
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(proc.regs[10], 6);
+
+        proc.set_pc(4);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 16);
+        assert_eq!(proc.step_out(), None);
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(proc.regs[10], 11);
+    }
 
+    #[test]
+    fn backtrace() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
+        proc.enable_backtrace(3);
+        proc.load(
+            0,
+            vec![
+                0x00a00093, // addi x1,x0,10
+                0x00310113, // addi x2,x2,3
+                0xfff08093, // addi x1,x1,-1
+                0xfe009ce3, // bne x1,x0,-8
+            ],
+        );
+        proc.execute_with_limit(6);
+        assert_eq!(
+            proc.recent_trace(),
+            vec![(12, 0xfe009ce3), (4, 0x00310113), (8, 0xfff08093)]
+        );
+    }
 
-        proc.write_reg(1, 0x1234);
-        proc.write_reg(2, 0x5678);
-        proc.inst_or(&args);
-        assert_eq!(proc.read_reg(3), 0x567c);
+    #[test]
+    #[cfg(feature = "std")]
+    fn trace_log() {
+        // Hands the written bytes to the test while the processor owns the writer.
+        struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
 
-        proc.write_reg(1, 0x7fff8000);
-        proc.write_reg(2, 0x00007fff);
-        proc.inst_or(&args);
-        assert_eq!(proc.read_reg(3), 0x7fffffff);
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x02a00093,                              // addi x1,x0,42
+                0x00102e23,                              // sw x1,28(x0)
+                0x00000013,                              // addi x0,x0,0
+                0b0000000_00000_00000_000_00000_0001011, // custom-0
+                0b0000000_00000_00000_000_00000_0101011, // custom-1 emulated by the hook
+            ],
+        );
+        proc.set_custom_decoder(Box::new(|raw| {
+            (raw & 0x7f == 0b0001011).then_some(CustomInstruction { id: 3, raw })
+        }));
+        proc.set_custom_executor(Box::new(|_, _| Ok(())));
+        proc.set_illegal_instruction_hook(Box::new(|_, raw| raw & 0x7f == 0b0101011));
+        let log = Rc::new(RefCell::new(Vec::new()));
+        proc.enable_trace_log(Box::new(SharedBuf(Rc::clone(&log))));
+        for _ in 0..5 {
+            proc.step();
+        }
+        assert_eq!(
+            String::from_utf8(log.borrow().clone()).unwrap(),
+            "00000000: 02a00093  li ra,42  => x1=0x2a\n\
+             00000004: 00102e23  sw ra,28(zero)  => mem[0x1c]=0x2a\n\
+             00000008: 00000013  nop\n\
+             0000000c: 0000000b  custom 3\n\
+             00000010: 0000002b  emulated\n"
+        );
     }
 
     #[test]
-    fn calc_rv32i_i_jalr() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x111,
-        };
-
+    fn step_delta() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
         let mut proc = Processor::new(memory);
-        proc.set_pc(0x1234);
+        proc.load(
+            0,
+            vec![
+                0x02a00093, // addi x1,x0,42
+                0x00102823, // sw x1,16(x0)
+            ],
+        );
 
-        proc.write_reg(1, 0x567);
-        proc.inst_jalr(&args)?;
-        assert_eq!(proc.read_reg(2), 0x1238);
-        assert_eq!(proc.pc, 0x678);
+        let delta = proc.step_delta().unwrap();
+        assert_eq!(delta.reg_write, Some((1, 42)));
+        assert_eq!(delta.mem_write, None);
 
-        proc.pc = 0x1234;
-        proc.write_reg(1, 0x543);
-        proc.inst_jalr(&args)?;
-        assert_eq!(proc.read_reg(2), 0x1238);
-        assert_eq!(proc.pc, 0x654);
-        Ok(())
+        let delta = proc.step_delta().unwrap();
+        assert_eq!(
+            delta,
+            StepDelta {
+                inst: 0x00102823,
+                reg_write: None,
+                mem_write: Some((16, 4, 42)),
+                csr_write: None,
+                pc_before: 4,
+                pc_after: 8,
+            }
+        );
+        assert_eq!(proc.step_delta(), Err(Exception::IllegalInstruction));
     }
 
     #[test]
-    fn calc_rv32i_i_jalr_invalid_address() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x110,
-        };
-
+    fn retire_record() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
         let mut proc = Processor::new(memory);
+        let records = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&records);
+        proc.set_retire_sink(Box::new(move |record| {
+            sink.borrow_mut().push(record.clone())
+        }));
+        proc.load(
+            0,
+            vec![
+                0x01000093, // addi x1,x0,16
+                0x3400a173, // csrrs x2,mscratch,x1
+                0x0010a223, // sw x1,4(x1)
+            ],
+        );
+        for _ in 0..3 {
+            proc.step();
+        }
 
-        proc.pc = 0x1234;
-        proc.write_reg(1, 0x567);
-        // x1 == 0x677, which is not aligned to a 4byte boundary.
+        let records = records.borrow();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].reg_writes, vec![(1, 16)]);
+        // Writing the old CSR value to x2 is recorded too.
+        assert_eq!(records[1].reg_writes, vec![(2, 0)]);
+        assert_eq!(records[1].csr_writes, vec![(0x340, 16)]);
         assert_eq!(
-            proc.inst_jalr(&args),
-            Err(Exception::InstructionAddressMisaligned)
+            records[2],
+            RetireRecord {
+                pc: 8,
+                inst_word: 0x0010a223,
+                reg_writes: vec![],
+                mem_writes: vec![(20, 4, 16)],
+                csr_writes: vec![],
+                new_pc: 12,
+            }
         );
-        Ok(())
     }
 
     #[test]
-    fn calc_rv32i_i_addi() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x123,
-        };
-
+    fn ebreak() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0x00100073, // ebreak
+                0x00200113, // addi x2,x0,2
+            ],
+        );
+        assert_eq!(proc.execute(), StopReason::Breakpoint(4));
+        assert_eq!(proc.pc(), 4);
+        assert_eq!(proc.read_reg(1), 1);
+        assert_eq!(proc.read_reg(2), 0);
+    }
 
-        proc.write_reg(1, 0x567);
-        proc.inst_addi(&args);
-        assert_eq!(proc.read_reg(2), 0x68a);
+    #[test]
+    fn setup_stack() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.setup_stack(0x104);
+        assert_eq!(proc.read_reg(SP), 0x100);
+
+        proc.load(
+            0,
+            vec![
+                0x02a00093, // addi x1,x0,42
+                0xff010113, // addi sp,sp,-16
+                0x00112623, // sw x1,12(sp)
+                0x00c12183, // lw x3,12(sp)
+                0x01010113, // addi sp,sp,16
+            ],
+        );
+        proc.execute();
+        assert_eq!(proc.read_reg(3), 42);
+        assert_eq!(proc.mem.read_word(Addr(0xfc)), 42);
+        assert_eq!(proc.read_reg(SP), 0x100);
     }
 
     #[test]
-    fn calc_rv32i_i_slli() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x3,
+    fn setup_stack_with_args() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.setup_stack_with_args(0x100, &["prog", "-v"], &["HOME=/"])
+            .unwrap();
+
+        // Strings are placed at 0xfb("prog"), 0xf8("-v") and 0xf1("HOME=/").
+        let sp = proc.read_reg(SP);
+        assert_eq!(sp, 0xd0);
+        let words: Vec<u32> = (0..6)
+            .map(|i| proc.mem.read_word(Addr(sp + i * 4)))
+            .collect();
+        assert_eq!(words, vec![2, 0xfb, 0xf8, 0, 0xf1, 0]);
+        let read_str = |addr: u32| -> String {
+            (addr..)
+                .map(|a| proc.mem.read_byte(Addr(a)))
+                .take_while(|&b| b != 0)
+                .map(|b| b as char)
+                .collect()
         };
+        assert_eq!(read_str(0xfb), "prog");
+        assert_eq!(read_str(0xf8), "-v");
+        assert_eq!(read_str(0xf1), "HOME=/");
 
-        let mut proc = Processor::new(memory);
+        // The stack would extend below address 0 or beyond the memory.
+        let sp = proc.read_reg(SP);
+        assert_eq!(
+            proc.setup_stack_with_args(0x10, &["program"], &[]),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(
+            proc.setup_stack_with_args(0x200, &["prog"], &[]),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(
+            proc.setup_stack_with_args(u32::MAX, &[], &[]),
+            Err(Exception::StoreAccessFault)
+        );
+        assert_eq!(proc.read_reg(SP), sp);
+    }
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_slli(&args);
-        assert_eq!(proc.read_reg(2), 0x2b3c0);
+    #[test]
+    fn interval() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        let clock = MockClock::new();
+        proc.set_clock(Box::new(clock.clone()));
+        proc.set_interval(10);
+        proc.load(
+            0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0x00100093, // addi x1,x0,1
+                0x00100093, // addi x1,x0,1
+            ],
+        );
+        proc.execute();
+        // Only executed instructions wait.
+        assert_eq!(clock.slept_ms(), 30);
     }
 
     #[test]
-    fn calc_rv32i_i_slti() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x123,
-        };
+    fn state_diff() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        // addi t0,x0,42
+        proc.load(0, vec![0x02a00293]);
+        let before = proc.state();
+        proc.step();
+        let diff = before.diff(&proc.state());
+        assert_eq!(diff.pc, Some((0, 4)));
+        assert_eq!(diff.regs, vec![(5, 0, 42)]);
+        // mcycle, minstret, cycle and instret.
+        assert_eq!(
+            diff.csrs,
+            vec![
+                (MCYCLE, 0, 1),
+                (MINSTRET, 0, 1),
+                (CYCLE, 0, 1),
+                (INSTRET, 0, 1)
+            ]
+        );
+    }
 
+    #[test]
+    fn memory_protection() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(128));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0xfff00093, // addi x1,x0,-1
+                0x04102023, // sw x1,64(x0)
+                0x04000067, // jalr x0,64(x0)
+            ],
+        );
+        proc.set_permissions(0, 64, Permissions::RX);
+        proc.set_permissions(64, 64, Permissions::R);
 
-        proc.write_reg(1, 0x567);
-        proc.inst_slti(&args);
-        assert_eq!(proc.read_reg(2), 0x0);
+        // Store to read-only memory.
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
+        assert_eq!(proc.pc(), 4);
+        assert_eq!(proc.mem.read_word(Addr(64)), 0);
 
-        proc.write_reg(1, 0x0);
-        proc.inst_slti(&args);
-        assert_eq!(proc.read_reg(2), 0x1);
+        // Fetch from non-executable memory.
+        proc.set_permissions(64, 64, Permissions::RW);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::InstructionAccessFault)
+        );
+        assert_eq!(proc.pc(), 64);
+        assert_eq!(proc.mem.read_word(Addr(64)), 0xffffffff);
 
-        proc.write_reg(1, 0xffffffff);
-        proc.inst_slti(&args);
-        assert_eq!(proc.read_reg(2), 0x1);
+        // A region reaching past the end of the address space.
+        proc.set_permissions(0xffff_fff0, usize::MAX, Permissions::R);
+        proc.set_latency(0xffff_fff0, usize::MAX, 1);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::InstructionAccessFault)
+        );
     }
 
     #[test]
-    fn calc_rv32i_i_sltiu() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x123,
-        };
-
+    fn access_straddling_regions() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x80));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x03e02083, // lw x1,62(x0)
+                0x04102e23, // sw x1,92(x0)
+            ],
+        );
+        proc.set_permissions(64, 32, Permissions::new(false, false, false));
+        proc.set_permissions(32, 32, Permissions::RW);
+        // The load starts in a readable region and ends in a non-readable one.
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::LoadAccessFault)
+        );
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_sltiu(&args);
-        assert_eq!(proc.read_reg(2), 0x0);
-
-        proc.write_reg(1, 0x0);
-        proc.inst_sltiu(&args);
-        assert_eq!(proc.read_reg(2), 0x1);
+        // The store ends in a read-only region.
+        proc.set_permissions(64, 32, Permissions::R);
+        proc.set_permissions(64, 30, Permissions::RW);
+        proc.set_pc(4);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
 
-        proc.write_reg(1, 0xffffffff);
-        proc.inst_sltiu(&args);
-        assert_eq!(proc.read_reg(2), 0x0);
+        proc.set_permissions(64, 32, Permissions::RW);
+        proc.set_pc(4);
+        proc.write_reg(1, 0xdeadbeef);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+        assert_eq!(proc.mem.read_word(Addr(92)), 0xdeadbeef);
     }
 
     #[test]
-    fn calc_rv32i_i_xori() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x123,
-        };
+    fn static_memory() {
+        use crate::encode::*;
+        let program = vec![
+            addi(1, 0, 0x100),
+            addi(2, 0, 64),
+            addi(3, 0, 0),
+            sw(1, 3, 0),
+            addi(3, 3, 3),
+            addi(1, 1, 4),
+            addi(2, 2, -1),
+            bne(2, 0, -16),
+            addi(1, 0, 0x100),
+            addi(2, 0, 64),
+            addi(4, 0, 0),
+            lw(5, 1, 0),
+            add(4, 4, 5),
+            sb(1, 4, 0),
+            addi(1, 1, 4),
+            addi(2, 2, -1),
+            bne(2, 0, -20),
+            ebreak(),
+        ];
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x200));
+        let mut dynamic = Processor::new(memory);
+        dynamic.load(0, program.clone());
+        let dynamic_reason = dynamic.execute();
+
+        let mut fast: Processor<VectorMemory> = Processor::new(Box::new(VectorMemory::new(0x200)));
+        fast.load(0, program);
+        assert_eq!(fast.execute(), dynamic_reason);
+        assert_eq!(dynamic_reason, StopReason::Breakpoint(68));
+        assert_eq!(fast.state().diff(&dynamic.state()).regs, vec![]);
+        assert_eq!(fast.regs[4], (0..64).map(|i| i * 3).sum::<u32>());
+        assert_eq!(fast.mem.to_bytes(), dynamic.mem.snapshot_bytes());
+        assert_eq!(fast.cycles(), dynamic.cycles());
+    }
 
+    #[test]
+    fn fetch_cache() {
+        let program = vec![
+            0x00a00093, // addi x1,x0,10
+            0x00310113, // addi x2,x2,3
+            0xfff08093, // addi x1,x1,-1
+            0xfe009ce3, // bne x1,x0,-8
+        ];
+        let (expected, _) = Processor::run_program(program.clone(), 0);
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
-
-        proc.write_reg(1, 0x5678);
-        proc.inst_xori(&args);
-        assert_eq!(proc.read_reg(2), 0x575b);
+        proc.set_fetch_cache(true);
+        proc.load(0, program);
+        proc.execute();
+        assert_eq!(proc.fetch_cache.as_ref().unwrap().len(), 4);
+        assert_eq!(proc.state().diff(&expected.state()).regs, vec![]);
+        assert_eq!(proc.regs[2], 30);
     }
 
     #[test]
-    fn calc_rv32i_i_srli() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x3,
-        };
+    fn fetch_cache_self_modifying_code() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut proc = Processor::new(memory);
+        proc.set_fetch_cache(true);
+        proc.load(
+            0,
+            vec![
+                0x00100293, // addi x5,x0,1
+                0x00302023, // sw x3,0(x0)
+            ],
+        );
+        // addi x5,x0,2
+        proc.regs[3] = 0x00200293;
+
+        proc.tick().unwrap();
+        assert_eq!(proc.regs[5], 1);
+        // Overwrite the cached instruction.
+        proc.tick().unwrap();
+        proc.set_pc(0);
+        proc.tick().unwrap();
+        assert_eq!(proc.regs[5], 2);
+    }
 
+    #[test]
+    fn fetch_cache_patched_loop() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(36));
         let mut proc = Processor::new(memory);
+        proc.set_fetch_cache(true);
+        proc.load(
+            0,
+            vec![
+                0x00128293, // addi x5,x5,1
+                0xfff30313, // addi x6,x6,-1
+                0xfe031ce3, // bne x6,x0,-8
+                0x003001a3, // sb x3,3(x0)
+                0x00021863, // bne x4,x0,16
+                0x00100213, // addi x4,x0,1
+                0x00100313, // addi x6,x0,1
+                0x00000067, // jalr x0,0(x0)
+                0x00100073, // ebreak
+            ],
+        );
+        proc.regs[6] = 2;
+        // Turns the first instruction into addi x5,x5,17 by a store into its middle.
+        proc.regs[3] = 1;
+        assert_eq!(proc.execute(), StopReason::Breakpoint(32));
+        // Two iterations of the original instruction and one of the patched one.
+        assert_eq!(proc.regs[5], 19);
+        assert_eq!(proc.mem.read_word(Addr(0)), 0x01128293);
+    }
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_srli(&args);
-        assert_eq!(proc.read_reg(2), 0xacf);
+    #[test]
+    fn load_binary() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut proc = Processor::new(memory);
+        // addi x1,x0,5; addi x2,x1,3
+        proc.load_binary(0, &[0x93, 0x00, 0x50, 0x00, 0x13, 0x81, 0x30, 0x00]);
+        proc.execute();
+        assert_eq!(proc.regs[1], 5);
+        assert_eq!(proc.regs[2], 8);
+    }
 
-        proc.write_reg(1, 0x80000000);
-        proc.inst_srli(&args);
-        assert_eq!(proc.read_reg(2), 0x10000000);
+    #[test]
+    fn stats() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00a00093, // addi x1,x0,10
+                0x00310113, // addi x2,x2,3
+                0xfff08093, // addi x1,x1,-1
+                0xfe009ce3, // bne x1,x0,-8
+            ],
+        );
+        proc.execute();
+
+        let stats = proc.stats();
+        assert_eq!(stats.instructions, 31);
+        assert_eq!(stats.by_format, [0, 21, 0, 10, 0, 0]);
+        // The loop runs 10 times and falls through at the last iteration.
+        assert_eq!(stats.branches_taken, 9);
+        assert_eq!(stats.branches_not_taken, 1);
+        assert_eq!(stats.traps, 1);
+        assert!(proc.stats_csv().lines().any(|line| line == "addi,21"));
     }
 
     #[test]
-    fn calc_rv32i_i_srai() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x3,
-        };
+    fn run_slice() {
+        let program = vec![
+            0x00a00093, // addi x1,x0,10
+            0x00310113, // addi x2,x2,3
+            0xfff08093, // addi x1,x1,-1
+            0xfe009ce3, // bne x1,x0,-8
+        ];
+        let (expected, _) = Processor::run_program(program.clone(), 0);
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, program);
+        assert_eq!(proc.run_slice(5), (StopReason::LimitReached, 5));
+        assert_eq!(proc.run_slice(14), (StopReason::LimitReached, 14));
+        // 31 instructions in total.
+        assert_eq!(
+            proc.run_slice(100),
+            (StopReason::Exception(Exception::InstructionAccessFault), 12)
+        );
+        assert!(proc.state().diff(&expected.state()).is_empty());
 
+        // Neither a halt nor a breakpoint retires the instruction it stops at.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
+        proc.set_stop_on_zero(true);
+        assert_eq!(proc.run_slice(10), (StopReason::Halted, 0));
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_srai(&args);
-        assert_eq!(proc.read_reg(2), 0xacf);
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, vec![0x00100093, 0x00100073]); // addi x1,x0,1; ebreak
+        assert_eq!(proc.run_slice(10), (StopReason::Breakpoint(4), 1));
+        assert_eq!(proc.instructions_retired(), 1);
 
-        proc.write_reg(1, 0x80000000);
-        proc.inst_srai(&args);
-        assert_eq!(proc.read_reg(2), 0xf0000000);
+        // A served `ecall` retires even if the handler stops execution.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, vec![0x00000073]); // ecall
+        proc.set_ecall_policy(EcallPolicy::Semihost);
+        proc.set_syscall_handler(Box::new(|_| Some(StopReason::Exit(0))));
+        assert_eq!(proc.run_slice(10), (StopReason::Exit(0), 1));
+        assert_eq!(proc.instructions_retired(), 1);
     }
 
     #[test]
-    fn calc_rv32i_i_ori() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
-            rd: 2,
-            imm: 0x123,
-        };
+    fn execute_with_limit() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        // jalr x0,0(x0)
+        proc.load(0, vec![0x00000067]);
+        assert_eq!(proc.execute_with_limit(100), StopReason::LimitReached);
+        assert_eq!(proc.pc(), 0);
+    }
+
+    #[test]
+    fn stop_on_zero() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0x00200113, // addi x2,x0,2
+            ],
+        );
+        proc.set_stop_on_zero(true);
+        assert_eq!(proc.execute(), StopReason::Halted);
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(proc.read_reg(2), 2);
+
+        proc.set_stop_on_zero(false);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+    }
 
+    #[test]
+    fn csr_zimm() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x340fd0f3, // csrrwi x1,0x340,31
+                0x34006173, // csrrsi x2,0x340,0
+                0x340af1f3, // csrrci x3,0x340,21
+                0x34006273, // csrrsi x4,0x340,0
+            ],
+        );
+        // zimm must not be taken from the register the field would name.
+        proc.write_reg(31, 0xdeadbeef);
+        proc.write_reg(21, 0xdeadbeef);
+        assert_eq!(proc.execute_with_limit(4), StopReason::LimitReached);
+        assert_eq!(proc.read_reg(2), 31);
+        assert_eq!(proc.read_reg(3), 31);
+        assert_eq!(proc.read_reg(4), 0b01010);
+        assert_eq!(proc.read_csr(0x340), 0b01010);
+    }
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_ori(&args);
-        assert_eq!(proc.read_reg(2), 0x577b);
+    #[test]
+    fn read_id_csrs() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(12));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0xf14020f3, // csrrs x1,mhartid,x0
+                0x30102173, // csrrs x2,misa,x0
+                0xf1409073, // csrrw x0,mhartid,x1
+            ],
+        );
+        proc.set_hart_id(5);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+        assert_eq!(proc.read_reg(1), 5);
+        assert_eq!(proc.read_reg(2), crate::csr::MISA_VALUE);
+        assert_eq!(proc.hart_id(), 5);
     }
 
     #[test]
-    fn calc_rv32i_i_andi() {
+    fn calc_rv32i_i_csr_readonly() {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args: IType = IType {
-            rs1: 1,
+        let mut proc = Processor::new(memory);
+        // mhartid
+        let args = IType {
             rd: 2,
-            imm: 0x123,
+            rs1: 1,
+            imm: 0xf14,
         };
 
-        let mut proc = Processor::new(memory);
+        assert_eq!(proc.inst_csrrw(&args), Err(Exception::IllegalInstruction));
+        assert_eq!(proc.inst_csrrs(&args), Err(Exception::IllegalInstruction));
+        assert_eq!(proc.inst_csrrwi(&args), Err(Exception::IllegalInstruction));
 
-        proc.write_reg(1, 0x5678);
-        proc.inst_andi(&args);
-        assert_eq!(proc.read_reg(2), 0x020);
+        // csrrs/csrrc with x0 (and the immediate variants with 0) only read the CSR.
+        let args = IType {
+            rd: 2,
+            rs1: 0,
+            imm: 0xf14,
+        };
+        proc.write_reg(2, 42);
+        assert_eq!(proc.inst_csrrs(&args), Ok(()));
+        assert_eq!(proc.read_reg(2), 0);
+        assert_eq!(proc.inst_csrrc(&args), Ok(()));
+        assert_eq!(proc.inst_csrrsi(&args), Ok(()));
+        assert_eq!(proc.inst_csrrci(&args), Ok(()));
     }
 
     #[test]
-    fn calc_rv32i_i_load() {
-        let memory = vec![0x0, 0x0, 0x0, 0x0, 0x80, 0x80, 0x08, 0x08];
-        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
-        let args: IType = IType {
-            rs1: 1,
+    fn strict_csr() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let mut proc = Processor::new(memory);
+        let args = IType {
             rd: 2,
-            imm: 0x0,
+            rs1: 0,
+            imm: 0x7ff,
         };
+        assert_eq!(proc.inst_csrrs(&args), Ok(()));
 
-        let mut proc = Processor::new(memory);
-        proc.write_reg(1, 4);
-
-        proc.inst_lb(&args);
-        assert_eq!(proc.read_reg(2), 0xffffff80);
-
-        proc.inst_lh(&args);
-        assert_eq!(proc.read_reg(2), 0xffff8080);
-
-        proc.inst_lw(&args);
-        assert_eq!(proc.read_reg(2), 0x08088080);
-
-        proc.inst_lbu(&args);
-        assert_eq!(proc.read_reg(2), 0x80);
-
-        proc.inst_lhu(&args);
-        assert_eq!(proc.read_reg(2), 0x8080);
-
-        let args: IType = IType {
-            rs1: 1,
+        proc.set_strict_csr(true);
+        assert_eq!(proc.inst_csrrs(&args), Err(Exception::IllegalInstruction));
+        let args = IType {
             rd: 2,
-            imm: 0x4,
+            rs1: 0,
+            imm: MSTATUS,
         };
+        assert_eq!(proc.inst_csrrs(&args), Ok(()));
+    }
 
-        proc.write_reg(1, 0);
+    #[test]
+    fn interrupt_bits() {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
+        let mut proc = Processor::new(memory);
+
+        proc.set_pending_interrupt(Interrupt::MachineTimer);
+        assert_eq!(proc.read_csr(MIP), 1 << 7);
+        proc.set_pending_interrupt(Interrupt::MachineSoftware);
+        assert_eq!(proc.read_csr(MIP), 1 << 7 | 1 << 3);
+        proc.clear_pending_interrupt(Interrupt::MachineTimer);
+        assert_eq!(proc.read_csr(MIP), 1 << 3);
+
+        proc.enable_interrupt(Interrupt::MachineExternal);
+        proc.enable_interrupt(Interrupt::SupervisorTimer);
+        assert_eq!(proc.read_csr(MIE), 1 << 11 | 1 << 5);
+        proc.disable_interrupt(Interrupt::MachineExternal);
+        assert_eq!(proc.read_csr(MIE), 1 << 5);
+    }
+
+    #[test]
+    fn ecall_cause() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
+        let mut proc = Processor::new(memory);
+        // ecall
+        proc.load(8, vec![0x00000073]);
+        proc.set_pc(8);
+        let cause = proc.tick().unwrap_err();
+        assert_eq!(cause, Exception::EnvironmentCallFromMMode);
+        assert_eq!(proc.pc(), 8);
+
+        proc.trap(cause, 0);
+        assert_eq!(proc.read_csr(MCAUSE), 11);
+        assert_eq!(proc.read_csr(MEPC), 8);
+    }
 
-        proc.inst_lb(&args);
-        assert_eq!(proc.read_reg(2), 0xffffff80);
+    #[test]
+    fn entropy_seed() {
+        let read_seeds = |seed| {
+            let memory: Box<dyn Memory> = Box::new(VectorMemory::new(12));
+            let mut proc = Processor::new(memory).with_entropy_seed(seed);
+            proc.load(
+                0,
+                vec![
+                    0x015010f3, // csrrw x1,seed,x0
+                    0x01501173, // csrrw x2,seed,x0
+                    0x015011f3, // csrrw x3,seed,x0
+                ],
+            );
+            proc.execute();
+            [proc.regs[1], proc.regs[2], proc.regs[3]]
+        };
 
-        proc.inst_lh(&args);
-        assert_eq!(proc.read_reg(2), 0xffff8080);
+        let seeds = read_seeds(42);
+        assert_eq!(seeds, read_seeds(42));
+        assert_ne!(seeds, read_seeds(43));
+        assert_ne!(seeds[0], seeds[1]);
+        for seed in &seeds {
+            assert_eq!(seed >> 16, 0b10 << 14);
+        }
+    }
 
-        proc.inst_lw(&args);
-        assert_eq!(proc.read_reg(2), 0x08088080);
+    #[test]
+    fn counter_csrs() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(20));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x06400093, // addi x1,x0,100
+                0xb0209073, // csrrw x0,minstret,x1
+                0x00000013, // nop
+                0x00000013, // nop
+                0xc0202173, // csrrs x2,instret,x0
+            ],
+        );
+        proc.execute();
+        assert_eq!(proc.regs[2], 102);
+        assert_eq!(proc.read_csr(MINSTRET), 103);
+        assert_eq!(proc.read_csr(MCYCLE), 5);
+
+        proc.write_csr(MINSTRETH, 1).unwrap();
+        assert_eq!(proc.read_csr(MINSTRETH), 1);
+        assert_eq!(proc.read_csr(INSTRET), 103);
+        assert_eq!(
+            proc.write_csr(INSTRET, 0),
+            Err(Exception::IllegalInstruction)
+        );
+    }
 
-        proc.inst_lbu(&args);
-        assert_eq!(proc.read_reg(2), 0x80);
+    #[test]
+    #[cfg(feature = "hash")]
+    fn loaded_image_hash() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load_binary(8, b"abc");
+        assert_eq!(
+            proc.loaded_image_hash(),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
 
-        proc.inst_lhu(&args);
-        assert_eq!(proc.read_reg(2), 0x8080);
+        // nop
+        proc.load(0, vec![0x00000013]);
+        let mut image = b"abc".to_vec();
+        image.extend_from_slice(&[0x13, 0, 0, 0]);
+        assert_eq!(
+            proc.loaded_image_hash(),
+            <[u8; 32]>::from(Sha256::digest(&image))
+        );
     }
 
     #[test]
-    fn calc_rv32i_i_sb() {
-        let memory = vec![0; 8];
-        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
-        let args = SType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x2,
-        };
+    fn wfi() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
+        let mut proc = Processor::new(memory);
+        proc.load(0, vec![0x10500073, 0x10500073]); // wfi
+        assert_eq!(proc.step(), Some(StopReason::WaitingForInterrupt));
+        assert_eq!(proc.pc(), 4);
 
+        // A pending interrupt wakes the hart even if it is globally disabled.
+        proc.set_pending_interrupt(Interrupt::MachineTimer);
+        proc.enable_interrupt(Interrupt::MachineTimer);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 8);
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x2);
-        proc.write_reg(2, 0x180);
-        proc.inst_sb(&args);
-        assert_eq!(proc.mem.read_byte(4), 0x80);
+        proc.load(0, vec![0x10500073, 0x10500073]);
+        proc.set_wfi_as_nop(true);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 8);
     }
 
     #[test]
-    fn calc_rv32i_i_sh() {
-        let memory = vec![0; 8];
-        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
-        let args = SType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x2,
-        };
-
+    fn pause() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(8));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x2);
-        proc.write_reg(2, 0x18080);
-        proc.inst_sh(&args);
-        assert_eq!(proc.mem.read_halfword(4), 0x8080);
+        proc.load(0, vec![0x0100000f]);
+        assert_eq!(proc.tick(), Ok(()));
+        assert_eq!(proc.pc(), 4);
     }
 
     #[test]
-    fn calc_rv32i_i_sw() {
-        let memory = vec![0; 8];
-        let memory: Box<dyn Memory> = Box::new(VectorMemory::from(memory));
-        let args = SType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x2,
-        };
+    fn trap_and_mret() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
+        let mut proc = Processor::new(memory);
+        // mret
+        proc.load(16, vec![0x30200073]);
+        proc.write_csr(MTVEC, 16).unwrap();
+        proc.write_csr(MSTATUS, MSTATUS_MIE).unwrap();
+        proc.set_pc(8);
+
+        proc.trap(Exception::IllegalInstruction, 0xdead);
+        assert_eq!(proc.pc(), 16);
+        assert_eq!(proc.read_csr(MEPC), 8);
+        assert_eq!(proc.read_csr(MCAUSE), 2);
+        assert_eq!(proc.read_csr(MTVAL), 0xdead);
+        assert_eq!(proc.read_csr(MSTATUS), MSTATUS_MPIE | MSTATUS_MPP);
+
+        proc.tick().unwrap();
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(
+            proc.read_csr(MSTATUS),
+            MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP
+        );
+    }
 
+    #[test]
+    fn interrupt_priority() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(64));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x2);
-        proc.write_reg(2, 0x80808080);
-        proc.inst_sw(&args);
-        assert_eq!(proc.mem.read_word(4), 0x80808080);
+        proc.load(0, vec![0x00000013; 16]); // nop
+        proc.write_csr(MTVEC, 32).unwrap();
+        proc.set_pending_interrupt(Interrupt::MachineTimer);
+        proc.set_pending_interrupt(Interrupt::MachineSoftware);
+        proc.enable_interrupt(Interrupt::MachineTimer);
+        proc.enable_interrupt(Interrupt::MachineSoftware);
+        assert_eq!(proc.pending_interrupt(), None);
+
+        proc.write_csr(MSTATUS, MSTATUS_MIE).unwrap();
+        assert_eq!(proc.pending_interrupt(), Some(Interrupt::MachineSoftware));
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.read_csr(MCAUSE), 1 << 31 | 3);
+        assert_eq!(proc.read_csr(MEPC), 0);
+        // The first instruction of the handler was executed.
+        assert_eq!(proc.pc(), 36);
+        // Interrupts are disabled in the handler.
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 40);
+
+        // Return with mret and take the timer interrupt.
+        proc.clear_pending_interrupt(Interrupt::MachineSoftware);
+        proc.load(40, vec![0x30200073]);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 0);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.read_csr(MCAUSE), 1 << 31 | 7);
     }
 
     #[test]
-    fn calc_rv32i_b_beq() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
+    fn trap_handler_resumes() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(36));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0x00000073, // ecall
+                0x00200113, // addi x2,x0,2
+                0x00100073, // ebreak
+                // Trap handler skipping the faulting instruction.
+                0x341022f3, // csrrs x5,mepc,x0
+                0x00428293, // addi x5,x5,4
+                0x34129073, // csrrw x0,mepc,x5
+                0x00600313, // addi x6,x0,6
+                0x30200073, // mret
+            ],
+        );
+        proc.write_csr(MTVEC, 16).unwrap();
+        proc.set_ecall_policy(EcallPolicy::Trap);
+
+        let mut pcs = Vec::new();
+        let reason = loop {
+            if let Some(reason) = proc.step() {
+                break reason;
+            }
+            pcs.push(proc.pc());
         };
+        assert_eq!(reason, StopReason::Breakpoint(12));
+        assert_eq!(pcs, vec![4, 16, 20, 24, 28, 32, 8, 12]);
+        assert_eq!(proc.read_csr(MCAUSE), 11);
+        assert_eq!(proc.read_csr(MEPC), 8);
+        assert_eq!(proc.regs[1], 1);
+        assert_eq!(proc.regs[2], 2);
+        assert_eq!(proc.regs[6], 6);
+    }
 
-        let mut proc = Processor::new(memory);
-        proc.write_reg(1, 42);
-        proc.write_reg(2, 42);
-        proc.inst_beq(&args)?;
-        assert_eq!(proc.pc, 0x80);
-        Ok(())
+    #[test]
+    fn new_with_state() {
+        let mut regs = RegisterFile::new();
+        regs.write(1, 42);
+        let mut csr = Csr::default();
+        csr.write(MTVEC, 0x20).unwrap();
+        csr.force_write(MHARTID, 3);
+        csr.force_write(MCYCLEH, 1);
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x24));
+        let mut proc = Processor::new_with_state(memory, 8, regs.clone(), csr).unwrap();
+        assert_eq!(proc.pc(), 8);
+        assert_eq!(proc.read_reg(1), 42);
+        assert_eq!(proc.read_csr(MHARTID), 3);
+        assert_eq!(proc.cycles(), 1 << 32);
+        assert_eq!(proc.instructions_retired(), 0);
+
+        // ecall
+        proc.load(8, vec![0x00000073]);
+        proc.set_ecall_policy(EcallPolicy::Trap);
+        assert_eq!(proc.step(), None);
+        assert_eq!(proc.pc(), 0x20);
+        assert_eq!(proc.read_csr(MEPC), 8);
+
+        // A misaligned pc is rejected instead of panicking.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x24));
+        assert_eq!(
+            Processor::new_with_state(memory, 6, regs, Csr::default()).err(),
+            Some(Exception::InstructionAddressMisaligned)
+        );
     }
 
-    // Test for invalid address in branch instruction is enough for this case because a processing the
-    // exception is abstracted in `Processor::branch_inner()`.
     #[test]
-    fn calc_rv32i_b_beq_invalid_address() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x81,
-        };
+    fn skip_illegal() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(12));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0xffffffff, // data
+                0x00200113, // addi x2,x0,2
+            ],
+        );
+        proc.set_skip_illegal(true);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::InstructionAccessFault)
+        );
+        assert_eq!(proc.read_reg(1), 1);
+        assert_eq!(proc.read_reg(2), 2);
+        assert_eq!(proc.stats().skipped, 1);
+        assert_eq!(proc.stats().instructions, 2);
+    }
 
+    #[test]
+    fn illegal_histogram() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(20));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 42);
-        proc.write_reg(2, 42);
+        proc.load(
+            0,
+            vec![
+                0xffffffff, // (illegal)
+                0x00100093, // addi x1,x0,1
+                0x0000007f, // (illegal)
+                0xffffffff, // (illegal)
+                0x00100073, // ebreak
+            ],
+        );
+        proc.set_skip_illegal(true);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(16));
+        let expected = BTreeMap::from([(0xffffffff, 2), (0x7f, 1)]);
+        assert_eq!(proc.illegal_histogram(), &expected);
+
+        proc.set_skip_illegal(false);
+        proc.set_pc(8);
         assert_eq!(
-            proc.inst_beq(&args),
-            Err(Exception::InstructionAddressMisaligned)
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
         );
-        Ok(())
+        assert_eq!(proc.illegal_histogram()[&0x7f], 2);
     }
 
     #[test]
-    fn calc_rv32i_b_bne() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
-        };
+    fn stack_bounds() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0xff810113, // addi sp,sp,-8
+                0x00112223, // sw ra,4(sp)
+                0xff810113, // addi sp,sp,-8
+                0xff810113, // addi sp,sp,-8
+            ],
+        );
+        proc.write_reg(SP, 0x100);
+        proc.set_stack_bounds(0xf0, 0x100);
+        assert_eq!(proc.execute(), StopReason::StackOverflow);
+        assert_eq!(proc.pc(), 16);
+        assert_eq!(proc.read_reg(SP), 0xe8);
+
+        // A store below the bound through sp.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x100));
+        let mut proc = Processor::new(memory);
+        proc.load(0, vec![0xfe112e23]); // sw ra,-4(sp)
+        proc.write_reg(SP, 0xf0);
+        proc.set_stack_bounds(0xf0, 0x100);
+        assert_eq!(proc.step(), Some(StopReason::StackOverflow));
+    }
 
+    #[test]
+    fn ecall_return_address() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(32));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 42);
-        proc.write_reg(2, 0);
-        proc.inst_bne(&args)?;
-        assert_eq!(proc.pc, 0x80);
-        Ok(())
+        proc.load(8, vec![0x00000073]); // ecall
+        proc.set_pc(8);
+        proc.set_ecall_policy(EcallPolicy::Semihost);
+        proc.set_syscall_handler(Box::new(|_| None));
+        assert_eq!(proc.step(), None);
+        // The ecall is not executed again.
+        assert_eq!(proc.pc(), 12);
+        assert_eq!(proc.read_csr(MINSTRET), 1);
+
+        proc.write_csr(MTVEC, 16).unwrap();
+        proc.set_pc(8);
+        proc.set_ecall_policy(EcallPolicy::Trap);
+        assert_eq!(proc.step(), None);
+        // The handler is responsible for skipping the ecall.
+        assert_eq!(proc.pc(), 16);
+        assert_eq!(proc.read_csr(MEPC), 8);
     }
 
     #[test]
-    fn calc_rv32i_b_blt() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
-        };
+    fn ecall_policy() {
+        let program = vec![
+            0x00000073, // ecall
+            0x00100073, // ebreak
+        ];
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, program.clone());
+        assert_eq!(proc.execute(), StopReason::Ecall);
+        assert_eq!(proc.pc(), 0);
 
+        // The handler at 8 is `ebreak`.
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0xffffff80);
-        proc.write_reg(2, 0);
-        // Compare register values as signed value.
-        proc.inst_blt(&args)?;
-        assert_eq!(proc.pc, 0x80);
-        Ok(())
+        proc.load(0, program.clone());
+        proc.load(8, vec![0x00100073]);
+        proc.write_csr(MTVEC, 8).unwrap();
+        proc.set_ecall_policy(EcallPolicy::Trap);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(8));
+        assert_eq!(proc.read_csr(MCAUSE), 11);
+        assert_eq!(proc.read_csr(MEPC), 0);
+
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(0, program);
+        proc.set_ecall_policy(EcallPolicy::Semihost);
+        proc.set_syscall_handler(Box::new(|proc| {
+            proc.write_reg(10, 42);
+            None
+        }));
+        assert_eq!(proc.execute(), StopReason::Breakpoint(4));
+        assert_eq!(proc.read_reg(10), 42);
     }
 
     #[test]
-    fn calc_rv32i_b_bgt() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
-        };
-
+    fn copy_region() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x50));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0);
-        proc.write_reg(2, 0xffffff80);
-        // Compare register values as signed value.
-        proc.inst_bge(&args)?;
-        assert_eq!(proc.pc, 0x80);
+        proc.load(
+            0,
+            vec![
+                0x00500093, // addi x1,x0,5
+                0x00308113, // addi x2,x1,3
+                0x00100073, // ebreak
+            ],
+        );
+        assert_eq!(proc.copy_region(0, 0x40, 12), Ok(()));
+        proc.mem.fill(Addr(0), 12, 0);
+        proc.set_pc(0x40);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(0x48));
+        assert_eq!(proc.read_reg(2), 8);
 
-        proc.write_reg(1, 0xffffff80);
-        proc.write_reg(2, 0xffffff80);
-        // Compare register values as signed value.
-        proc.inst_bge(&args)?;
-        assert_eq!(proc.pc, 0x100);
-        Ok(())
+        assert_eq!(
+            proc.copy_region(0x48, 0, 12),
+            Err(Exception::LoadAccessFault)
+        );
+        assert_eq!(proc.mem.read_word(Addr(0)), 0);
+        assert_eq!(
+            proc.copy_region(0, 0x48, 12),
+            Err(Exception::StoreAccessFault)
+        );
+
+        // Neither a device nor the latency of the region sees the copy.
+        let fb = Framebuffer::new(2, 1);
+        proc.map_device(0x30, Box::new(fb.clone()));
+        proc.set_latency(0, 0x30, 10);
+        assert_eq!(
+            proc.copy_region(0x2c, 0, 8),
+            Err(Exception::LoadAccessFault)
+        );
+        assert_eq!(
+            proc.copy_region(0, 0x34, 4),
+            Err(Exception::StoreAccessFault)
+        );
+        let cycles = proc.cycles();
+        proc.mem.write_word(Addr(0x20), 0x12345678);
+        assert_eq!(proc.copy_region(0x20, 0x24, 4), Ok(()));
+        assert_eq!(proc.mem.read_word(Addr(0x24)), 0x12345678);
+        assert_eq!(proc.cycles(), cycles);
+        assert_eq!(&*fb.pixels(), &[0, 0]);
     }
 
     #[test]
-    fn calc_rv32i_b_bltu() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
-        };
-
+    fn disable_zicsr() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(4));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0);
-        proc.write_reg(2, 0xffffff80);
-        // Compare register values as unsigned value.
-        proc.inst_bltu(&args)?;
-        assert_eq!(proc.pc, 0x80);
-        Ok(())
+        proc.load(0, vec![0x340110f3]); // csrrw x1,mscratch,x2
+        proc.set_extensions(Extensions { zicsr: false });
+        assert_eq!(proc.next_pc(), Err(Exception::IllegalInstruction));
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+        proc.set_extensions(Extensions::default());
+        assert_eq!(proc.next_pc(), Ok(4));
+        assert_eq!(proc.step(), None);
     }
 
     #[test]
-    fn calc_rv32i_b_bgtu() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = BType {
-            rs1: 1,
-            rs2: 2,
-            imm: 0x80,
-        };
-
+    fn memory_latency() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x180));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0xffffff80);
-        proc.write_reg(2, 0);
-        // Compare register values as unsigned value.
-        proc.inst_bgeu(&args)?;
-        assert_eq!(proc.pc, 0x80);
+        proc.load(
+            0,
+            vec![
+                0x10002083, // lw x1,0x100(x0)
+                0x14002103, // lw x2,0x140(x0)
+                0x14102223, // sw x1,0x144(x0)
+                0x00100073, // ebreak
+            ],
+        );
+        // Fast SRAM followed by slow DRAM.
+        proc.set_latency(0x100, 0x40, 1);
+        proc.set_latency(0x140, 0x40, 10);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(12));
+        assert_eq!(proc.cycles(), 3 + 1 + 10 + 10);
+        assert_eq!(proc.instructions_retired(), 3);
+    }
 
-        proc.write_reg(1, 0xffffff80);
-        proc.write_reg(2, 0xffffff80);
-        // Compare register values as signed value.
-        proc.inst_bgeu(&args)?;
-        assert_eq!(proc.pc, 0x100);
-        Ok(())
+    #[test]
+    fn exit_codes() {
+        let cases = [
+            (StopReason::Halted, 0),
+            (StopReason::Exit(0), 0),
+            (StopReason::Exit(3), 3),
+            (StopReason::Exit(99), 99),
+            // Would collide with `IllegalInstruction`.
+            (StopReason::Exit(102), 1),
+            // Would be truncated to 0 or turn negative.
+            (StopReason::Exit(256), 1),
+            (StopReason::Exit(u32::MAX), 1),
+            (StopReason::Exception(Exception::IllegalInstruction), 102),
+            (StopReason::Exception(Exception::LoadAccessFault), 105),
+            (StopReason::Breakpoint(0x80), 103),
+            (StopReason::Ecall, 111),
+            (StopReason::StackOverflow, 120),
+            (StopReason::WaitingForInterrupt, 121),
+            (StopReason::LimitReached, 124),
+        ];
+        for (reason, code) in cases {
+            assert_eq!(reason.to_exit_code(), code, "{:?}", reason);
+        }
     }
 
     #[test]
-    fn calc_rv32i_u_lui() {
+    fn registers() {
         let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = UType {
-            rd: 1,
-            imm: 0xfffff,
-        };
+        let mut proc = Processor::new(memory);
+        let mut values = [0; 32];
+        for (idx, val) in values.iter_mut().enumerate() {
+            *val = 0x1000 + idx as u32;
+        }
+        proc.set_registers(&values);
+        values[0] = 0;
+        assert_eq!(proc.registers(), values);
+        assert_eq!(proc.read_reg(31), 0x101f);
+    }
 
+    #[test]
+    fn semihosting() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x50));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x0);
-        proc.inst_lui(&args);
-        assert_eq!(proc.read_reg(args.rd), 0xfffff000);
+        proc.load(
+            0,
+            vec![
+                0x00400513, // addi a0,x0,4
+                0x04000593, // addi a1,x0,0x40
+                0x01f01013, // slli x0,x0,0x1f
+                0x00100073, // ebreak
+                0x40705013, // srai x0,x0,7
+                0x00100073, // ebreak
+            ],
+        );
+        proc.load_binary(0x40, b"hello\0");
+        // SYS_WRITE0 writes the NUL-terminated string at a1.
+        let output = Rc::new(RefCell::new(String::new()));
+        let captured = output.clone();
+        proc.set_semihosting_handler(Box::new(move |proc| {
+            assert_eq!(proc.read_reg(10), 4);
+            let mut addr = Addr(proc.read_reg(11));
+            while proc.mem.read_byte(addr) != 0 {
+                captured.borrow_mut().push(proc.mem.read_byte(addr) as char);
+                addr = addr.offset(1);
+            }
+            None
+        }));
+        assert_eq!(proc.execute(), StopReason::Breakpoint(12));
+
+        proc.set_semihosting(true);
+        proc.set_pc(0);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(20));
+        assert_eq!(*output.borrow(), "hello");
+
+        // The syscall handler serves only `ecall`s.
+        proc.set_syscall_handler(Box::new(|_| panic!("not an ecall")));
+        proc.set_pc(0);
+        assert_eq!(proc.execute(), StopReason::Breakpoint(20));
+        assert_eq!(*output.borrow(), "hellohello");
     }
 
     #[test]
-    fn calc_rv32i_u_auipc() {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = UType {
-            rd: 1,
-            imm: 0xfffff,
-        };
+    fn read_time_csr() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        let clock = MockClock::new();
+        proc.set_clock(Box::new(clock.clone()));
+        proc.load(
+            0,
+            vec![
+                0xc01020f3, // csrrs x1,time,x0
+                0x00000013, // addi x0,x0,0
+                0x00000013, // addi x0,x0,0
+                0xc0102173, // csrrs x2,time,x0
+            ],
+        );
+        for _ in 0..4 {
+            proc.step();
+            clock.sleep(1);
+        }
+        assert_eq!(proc.read_reg(1), 0);
+        assert_eq!(proc.read_reg(2), 3_000_000);
+
+        // time is read-only.
+        assert_eq!(proc.write_csr(TIME, 0), Err(Exception::IllegalInstruction));
+    }
 
+    #[test]
+    fn countdown_loop() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x0);
-        // If pc is 0, cannot detect not adding `imm` to current pc.
-        proc.set_pc(0x4);
-        proc.inst_auipc(&args);
-        assert_eq!(proc.read_reg(args.rd), 0xfffff004);
-        assert_eq!(proc.pc, 0xfffff004);
+        proc.load(
+            0,
+            vec![
+                0x00500093, // addi x1,x0,5
+                0xfff08093, // addi x1,x1,-1
+                0xfe009ee3, // bne x1,x0,-4
+            ],
+        );
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::IllegalInstruction)
+        );
+        assert_eq!(proc.read_reg(1), 0);
+        assert_eq!(proc.pc(), 12);
     }
 
     #[test]
-    fn calc_rv32i_j_jal() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = JType { rd: 1, imm: 0x80 };
+    fn auipc_falls_through() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.load(
+            0,
+            vec![
+                0x00000097, // auipc x1,0x0
+                0x00000117, // auipc x2,0x0
+            ],
+        );
+        assert_eq!(proc.next_pc(), Ok(4));
+        proc.tick().unwrap();
+        assert_eq!(proc.read_reg(1), 0);
+        assert_eq!(proc.pc(), 4);
+        proc.tick().unwrap();
+        assert_eq!(proc.read_reg(2), 4);
+        assert_eq!(proc.pc(), 8);
+    }
 
+    #[test]
+    fn link_wraps_at_top_of_address_space() -> Result<(), Exception> {
+        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x0);
-        proc.set_pc(0x4);
-        proc.inst_jal(&args)?;
-        assert_eq!(proc.read_reg(args.rd), 0x8);
-        assert_eq!(proc.pc, 0x84);
+        proc.set_pc(0xfffffffc);
+        proc.inst_jal(&JType { rd: 1, imm: 8 })?;
+        assert_eq!(proc.read_reg(1), 0);
+        assert_eq!(proc.pc(), 4);
 
-        let args = JType {
+        proc.set_pc(0xfffffffc);
+        proc.inst_jalr(&IType {
             rd: 1,
-            imm: 0xfffffffc, // -4
-        };
-        proc.inst_jal(&args)?;
-        assert_eq!(proc.read_reg(args.rd), 0x88);
-        assert_eq!(proc.pc, 0x80);
+            rs1: 0,
+            imm: 0x10,
+        })?;
+        assert_eq!(proc.read_reg(1), 0);
+        assert_eq!(proc.pc(), 0x10);
+
+        proc.set_pc(0xfffffffc);
+        proc.inst_auipc(&UType { rd: 1, imm: 0x1 });
+        assert_eq!(proc.read_reg(1), 0xffc);
         Ok(())
     }
 
     #[test]
-    fn calc_rv32i_j_jal_invalid_address() -> Result<(), Exception> {
-        let memory: Box<dyn Memory> = Box::new(EmptyMemory);
-        let args = JType { rd: 1, imm: 0x82 };
+    fn run_to_trap_store_misaligned() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
+        let mut proc = Processor::new(memory);
+        proc.set_alignment_policy(AlignmentPolicy::Trap);
+        proc.load(
+            0,
+            vec![
+                0x00600093, // addi x1,x0,6
+                0x0010a023, // sw x1,0(x1)
+            ],
+        );
+        let frame = proc.run_to_trap(10).unwrap();
+        assert_eq!(frame.cause, Exception::StoreAddressMisaligned);
+        assert_eq!(frame.mtval, 6);
+    }
 
+    #[test]
+    fn memory_latency_faulting_access() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(16));
         let mut proc = Processor::new(memory);
-        proc.write_reg(1, 0x0);
+        proc.load(0, vec![0x10002083]); // lw x1,0x100(x0)
+                                        // The slow region extends past the end of the memory.
+        proc.set_latency(0, 0x200, 10);
         assert_eq!(
-            proc.inst_jal(&args),
-            Err(Exception::InstructionAddressMisaligned)
+            proc.execute(),
+            StopReason::Exception(Exception::LoadAccessFault)
         );
-        Ok(())
+        assert_eq!(proc.cycles(), 0);
+
+        static PROGRAM: [u8; 4] = [
+            0x23, 0x20, 0x00, 0x00, // sw x0,0(x0)
+        ];
+        let mut proc = Processor::new(Box::new(SliceMemory(&PROGRAM)));
+        proc.set_latency(0, 4, 10);
+        assert_eq!(
+            proc.execute(),
+            StopReason::Exception(Exception::StoreAccessFault)
+        );
+        assert_eq!(proc.cycles(), 0);
     }
 }
@@ -0,0 +1,80 @@
+use core::ops::{Index, IndexMut};
+
+/// General purpose registers x0-x31.
+/// x0 is hardwired to zero: writes to it are discarded and it always reads as zero.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RegisterFile {
+    regs: [u32; 32],
+}
+
+impl RegisterFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read the register at index `idx`.
+    pub fn read(&self, idx: usize) -> u32 {
+        if idx == 0 {
+            0
+        } else {
+            self.regs[idx]
+        }
+    }
+
+    /// Write `val` to the register at index `idx`.
+    /// Returns false if the write was discarded because `idx` is x0.
+    pub fn write(&mut self, idx: usize, val: u32) -> bool {
+        if idx == 0 {
+            return false;
+        }
+        self.regs[idx] = val;
+        true
+    }
+
+    /// Values of all registers.
+    pub fn values(&self) -> [u32; 32] {
+        let mut values = self.regs;
+        values[0] = 0;
+        values
+    }
+}
+
+impl Index<usize> for RegisterFile {
+    type Output = u32;
+
+    fn index(&self, idx: usize) -> &u32 {
+        if idx == 0 {
+            &0
+        } else {
+            &self.regs[idx]
+        }
+    }
+}
+
+/// Assigning to x0 through an index has no visible effect, as it keeps reading as zero.
+impl IndexMut<usize> for RegisterFile {
+    fn index_mut(&mut self, idx: usize) -> &mut u32 {
+        &mut self.regs[idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hardwired_zero() {
+        let mut regs = RegisterFile::new();
+        assert!(!regs.write(0, 42));
+        assert!(regs.write(1, 42));
+        assert_eq!(regs.read(0), 0);
+        assert_eq!(regs.read(1), 42);
+
+        regs[0] = 7;
+        regs[2] = 7;
+        assert_eq!(regs[0], 0);
+        assert_eq!(regs.read(0), 0);
+        assert_eq!(regs[2], 7);
+        assert_eq!(regs.values()[..3], [0, 42, 7]);
+    }
+}
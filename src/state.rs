@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Architectural state of a processor at some point.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcessorState {
+    pub pc: u32,
+    pub regs: [u32; 32],
+    /// Values of all CSRs indexed by their addresses.
+    pub csrs: Vec<u32>,
+}
+
+/// Differences between two `ProcessorState`s.
+/// Each entry holds the value in the old state and that in the new state.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct StateDiff {
+    pub pc: Option<(u32, u32)>,
+    /// Triples of register index, old value and new value.
+    pub regs: Vec<(usize, u32, u32)>,
+    /// Triples of CSR address, old value and new value.
+    pub csrs: Vec<(u16, u32, u32)>,
+}
+
+impl ProcessorState {
+    /// Compare `self` as an old state with `other`.
+    pub fn diff(&self, other: &ProcessorState) -> StateDiff {
+        let pc = if self.pc != other.pc {
+            Some((self.pc, other.pc))
+        } else {
+            None
+        };
+        let regs = (0..32)
+            .filter(|&i| self.regs[i] != other.regs[i])
+            .map(|i| (i, self.regs[i], other.regs[i]))
+            .collect();
+        let csrs = self
+            .csrs
+            .iter()
+            .zip(other.csrs.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(addr, (&old, &new))| (addr as u16, old, new))
+            .collect();
+        StateDiff { pc, regs, csrs }
+    }
+}
+
+impl StateDiff {
+    /// Whether the two states are identical.
+    pub fn is_empty(&self) -> bool {
+        self.pc.is_none() && self.regs.is_empty() && self.csrs.is_empty()
+    }
+}
+
+/// Print only the differences, one per line.
+impl fmt::Display for StateDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((old, new)) = self.pc {
+            writeln!(f, "pc: {:#010x} -> {:#010x}", old, new)?;
+        }
+        for (idx, old, new) in &self.regs {
+            writeln!(f, "x{}: {:#010x} -> {:#010x}", idx, old, new)?;
+        }
+        for (addr, old, new) in &self.csrs {
+            writeln!(f, "csr {:#05x}: {:#010x} -> {:#010x}", addr, old, new)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn diff() {
+        let old = ProcessorState {
+            pc: 0x4,
+            regs: [0; 32],
+            csrs: vec![0; 4096],
+        };
+        let mut new = old.clone();
+        assert!(old.diff(&new).is_empty());
+        assert_eq!(old.diff(&new).to_string(), "");
+
+        new.pc = 0x8;
+        new.regs[5] = 42;
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff,
+            StateDiff {
+                pc: Some((0x4, 0x8)),
+                regs: vec![(5, 0, 42)],
+                csrs: vec![],
+            }
+        );
+        assert_eq!(
+            diff.to_string(),
+            "pc: 0x00000004 -> 0x00000008\nx5: 0x00000000 -> 0x0000002a\n"
+        );
+
+        new.csrs[0x340] = 1;
+        assert_eq!(old.diff(&new).csrs, vec![(0x340, 0, 1)]);
+    }
+}
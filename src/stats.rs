@@ -0,0 +1,130 @@
+use crate::decode::{supported_instructions, Format, Instruction};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::fmt;
+use core::fmt::Write;
+
+/// Counters accumulated while executing instructions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExecStats {
+    /// Retired instructions including custom ones.
+    pub instructions: u64,
+    /// Retired instructions of the base ISA per format, in the order of R, I, S, B, U, J.
+    pub by_format: [u64; 6],
+    /// Retired instructions of the base ISA per mnemonic.
+    pub by_mnemonic: BTreeMap<&'static str, u64>,
+    /// Retired instructions interpreted by a custom decoder.
+    pub custom: u64,
+    pub branches_taken: u64,
+    pub branches_not_taken: u64,
+    pub loads: u64,
+    pub stores: u64,
+    /// Exceptions raised by instructions.
+    pub traps: u64,
+    /// Undecodable words skipped by `Processor::set_skip_illegal`.
+    pub skipped: u64,
+    /// Occurrences of each word nothing could decode, whether skipped or raised.
+    pub illegal: BTreeMap<u32, u64>,
+}
+
+impl ExecStats {
+    // Count a retired instruction. `jumped` tells whether it changed the control flow.
+    pub(crate) fn record(&mut self, inst: &Instruction, jumped: bool) {
+        self.instructions += 1;
+        self.by_format[inst.format() as usize] += 1;
+        *self.by_mnemonic.entry(inst.mnemonic()).or_insert(0) += 1;
+        match inst {
+            Instruction::Lb(_)
+            | Instruction::Lh(_)
+            | Instruction::Lw(_)
+            | Instruction::Lbu(_)
+            | Instruction::Lhu(_) => self.loads += 1,
+            Instruction::Sb(_) | Instruction::Sh(_) | Instruction::Sw(_) => self.stores += 1,
+            _ if inst.format() == Format::B => {
+                if jumped {
+                    self.branches_taken += 1;
+                } else {
+                    self.branches_not_taken += 1;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Count a retired instruction interpreted by a custom decoder.
+    pub(crate) fn record_custom(&mut self) {
+        self.instructions += 1;
+        self.custom += 1;
+    }
+
+    /// CSV with a row of execution count for every supported mnemonic, followed by a
+    /// row for custom instructions.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("mnemonic,count\n");
+        for info in supported_instructions() {
+            let count = self.by_mnemonic.get(info.mnemonic).unwrap_or(&0);
+            writeln!(csv, "{},{}", info.mnemonic, count).unwrap();
+        }
+        writeln!(csv, "custom,{}", self.custom).unwrap();
+        csv
+    }
+}
+
+impl fmt::Display for ExecStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.instructions)?;
+        for (name, count) in ["R", "I", "S", "B", "U", "J"].iter().zip(&self.by_format) {
+            writeln!(f, "  {}-type: {}", name, count)?;
+        }
+        writeln!(f, "  custom: {}", self.custom)?;
+        writeln!(
+            f,
+            "branches: {} taken, {} not taken",
+            self.branches_taken, self.branches_not_taken
+        )?;
+        writeln!(f, "loads: {}", self.loads)?;
+        writeln!(f, "stores: {}", self.stores)?;
+        writeln!(f, "traps: {}", self.traps)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::decode;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn to_csv() {
+        let mut stats = ExecStats::default();
+        // addi x1,x0,1; addi x1,x1,1; sw x1,0(x0)
+        for word in [0x00100093, 0x00108093, 0x00102023] {
+            stats.record(&decode(word).unwrap(), false);
+        }
+        let csv = stats.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "mnemonic,count");
+        assert!(lines.contains(&"addi,2"));
+        assert!(lines.contains(&"sw,1"));
+        assert!(lines.contains(&"add,0"));
+        assert_eq!(lines.last(), Some(&"custom,0"));
+        assert_eq!(lines.len(), supported_instructions().len() + 2);
+    }
+
+    #[test]
+    fn display() {
+        let mut stats = ExecStats::default();
+        // addi x1,x0,1; sw x1,0(x0); beq x0,x0,8
+        for word in [0x00100093, 0x00102023, 0x00000463] {
+            stats.record(&decode(word).unwrap(), true);
+        }
+        stats.traps = 1;
+        assert_eq!(
+            stats.to_string(),
+            "instructions: 3\n  R-type: 0\n  I-type: 1\n  S-type: 1\n  B-type: 1\n  \
+             U-type: 0\n  J-type: 0\n  custom: 0\nbranches: 1 taken, 0 not taken\n\
+             loads: 0\nstores: 1\ntraps: 1\n"
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::memory::{Memory, SharedMemory};
+use crate::processor::{Processor, StopReason};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+/// Multiple harts sharing one memory.
+/// Each hart gets its index as the hart ID.
+pub struct System {
+    harts: Vec<Processor>,
+}
+
+impl System {
+    pub fn new(memory: Box<dyn Memory>, num_harts: usize) -> Self {
+        let memory = SharedMemory::new(memory);
+        let harts = (0..num_harts)
+            .map(|id| {
+                let mut hart = Processor::new(Box::new(memory.clone()) as Box<dyn Memory>);
+                hart.set_hart_id(id as u32);
+                hart
+            })
+            .collect();
+        Self { harts }
+    }
+
+    pub fn hart(&self, id: usize) -> &Processor {
+        &self.harts[id]
+    }
+
+    pub fn hart_mut(&mut self, id: usize) -> &mut Processor {
+        &mut self.harts[id]
+    }
+
+    /// Execute harts in round-robin manner, one instruction each, until all of them stop.
+    /// Returns the reasons indexed by hart ID.
+    pub fn execute(&mut self) -> Vec<StopReason> {
+        let mut reasons: Vec<Option<StopReason>> = self.harts.iter().map(|_| None).collect();
+        while reasons.iter().any(Option::is_none) {
+            for (hart, reason) in self.harts.iter_mut().zip(reasons.iter_mut()) {
+                if reason.is_none() {
+                    *reason = hart.step();
+                }
+            }
+        }
+        reasons.into_iter().flatten().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exception::Exception;
+    use crate::memory::VectorMemory;
+    use alloc::vec;
+
+    #[test]
+    fn two_harts() {
+        let memory: Box<dyn Memory> = Box::new(VectorMemory::new(0x84));
+        let mut system = System::new(memory, 2);
+        assert_eq!(system.hart(0).hart_id(), 0);
+        assert_eq!(system.hart(1).hart_id(), 1);
+
+        // Hart 0 raises a flag.
+        system.hart_mut(0).load(
+            0x0,
+            vec![
+                0x00100093, // addi x1,x0,1
+                0x08102023, // sw x1,0x80(x0)
+            ],
+        );
+        // Hart 1 spins until it sees the flag.
+        system.hart_mut(1).load(
+            0x40,
+            vec![
+                0x08002083, // lw x1,0x80(x0)
+                0x00009463, // bne x1,x0,8
+                0x04000067, // jalr x0,0x40(x0)
+                0x02a00113, // addi x2,x0,42
+            ],
+        );
+        system.hart_mut(1).set_pc(0x40);
+
+        let reasons = system.execute();
+        // Both harts run into zero-filled memory.
+        assert_eq!(
+            reasons,
+            vec![
+                StopReason::Exception(Exception::IllegalInstruction),
+                StopReason::Exception(Exception::IllegalInstruction),
+            ]
+        );
+        assert_eq!(system.hart(1).regs[2], 42);
+        assert_eq!(system.hart(1).pc(), 0x50);
+    }
+}